@@ -1,7 +1,10 @@
 // use std::ffi::OsString;
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
 use clap::Subcommand;
+use serde::Deserialize;
+
+use crate::naming::{NameTemplate, DEFAULT_NAME_TEMPLATE};
 
 #[derive(Debug, clap::Parser)]
 #[clap(version)]
@@ -9,27 +12,491 @@ pub struct Args {
     // /// The directory to read UTF-8 encoded text files from.
     // #[clap(long, short = 'i', default_value = "input", help_heading = "INPUT")]
     // pub input_dir: PathBuf,
+    /// Don't print progress or status messages.
+    #[clap(long, short = 'q', global = true)]
+    pub quiet: bool,
+
+    /// How progress is printed.
+    #[clap(long, value_enum, default_value_t = ProgressFormat::Bar, global = true)]
+    pub progress: ProgressFormat,
+
     /// The various subcommands this program can run.
     #[clap(subcommand)]
     pub top_commands: TopSubcommands,
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// A progress bar for every LOD layer.
+    Bar,
+    /// One JSON object per line for every progress event.
+    Json,
+}
+
+/// The image format tiles are written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TileFormat {
+    /// Keeps 8 and 16-bit tiles, floating point tiles are written with 16 bits per channel.
+    #[default]
+    Png,
+    /// JPEG has no alpha channel, transparent pixels are written black. Tiles are written with
+    /// 8 bits per channel.
+    Jpeg,
+    /// Tiles are written with 8 bits per channel.
+    Bmp,
+    /// Keeps 8 and 16-bit tiles, floating point tiles are written with 16 bits per channel.
+    Tiff,
+    /// OpenEXR stores floating point channels, for HDR and other floating point images.
+    Exr,
+    /// A KTX2 texture, block compressed for GPUs with sRGB colors. Tiles are compressed from 8 bits
+    /// per channel.
+    Ktx2,
+    /// A DDS texture, block compressed for GPUs with sRGB colors. Tiles are compressed from 8 bits
+    /// per channel.
+    Dds,
+}
+
+impl TileFormat {
+    /// The file extension of tiles in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            TileFormat::Jpeg => "jpg",
+            TileFormat::Bmp => "bmp",
+            TileFormat::Tiff => "tiff",
+            TileFormat::Exr => "exr",
+            TileFormat::Ktx2 => "ktx2",
+            TileFormat::Dds => "dds",
+        }
+    }
+
+    /// Returns the format whose tiles have `extension`, ignoring case, or `None` if no format
+    /// writes it.
+    pub fn from_extension(extension: &str) -> Option<TileFormat> {
+        <TileFormat as clap::ValueEnum>::value_variants()
+            .iter()
+            .copied()
+            .find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+}
+
+/// How `merge` combines a tile with the tiles below it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlendMode {
+    /// Alpha-composite the tile over the tiles below it.
+    #[default]
+    Over,
+    /// Multiply the colors of the tile and the tiles below it, which darkens.
+    Multiply,
+    /// Invert, multiply and invert the colors of the tile and the tiles below it, which lightens.
+    Screen,
+    /// Replace pixels below the tile's fully opaque pixels, and keep the others.
+    ReplaceIfOpaque,
+}
+
+/// The coordinate reference system of a georeferenced image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+pub enum SourceCrs {
+    /// Longitude and latitude in degrees (WGS 84).
+    #[value(name = "epsg:4326")]
+    #[serde(rename = "epsg:4326")]
+    Epsg4326,
+    /// Web Mercator meters.
+    #[value(name = "epsg:3857")]
+    #[serde(rename = "epsg:3857")]
+    Epsg3857,
+}
+
+/// How pixels are sampled from a source image that is reprojected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resampling {
+    /// The nearest source pixel. Fast, and keeps hard edges.
+    Nearest,
+    /// A weighted average of the nearest source pixels.
+    #[default]
+    Bilinear,
+    /// A 3 lobed Lanczos filter. Sharp, but slower.
+    Lanczos,
+}
+
+/// How the colors of a floating point image are mapped to the 0 to 1 range of 8-bit tiles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapping {
+    /// Clip colors above 1.
+    Linear,
+    /// Compress bright colors smoothly with c / (1 + c).
+    Reinhard,
+    /// An approximation of the ACES filmic curve, with more contrast than Reinhard.
+    Aces,
+}
+
+/// An axis to mirror an image across.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flip {
+    /// Mirror the left and right sides.
+    Horizontal,
+    /// Mirror the top and bottom.
+    Vertical,
+}
+
+/// An 8-bit RGB color, given as `r,g,b`, as hex like `#ff8800`, or as `black` or `white`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Color, String> {
+        let invalid = || format!("`{}` is not a color", value);
+
+        match value {
+            "black" => return Ok(Color([0, 0, 0])),
+            "white" => return Ok(Color([255, 255, 255])),
+            _ => {}
+        }
+
+        if value.contains(',') {
+            let channels: Vec<u8> = value
+                .split(',')
+                .map(|channel| channel.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid())?;
+            return Ok(Color(channels.try_into().map_err(|_| invalid())?));
+        }
+
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let channel = |index: usize| {
+            u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).map_err(|_| invalid())
+        };
+        Ok(Color([channel(0)?, channel(1)?, channel(2)?]))
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Color, String> {
+        value.parse()
+    }
+}
+
+/// The GPU block compression of KTX2 and DDS tiles. Blocks are 4x4 pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockCompression {
+    /// 8 bytes per block, for RGB with 1-bit alpha.
+    Bc1,
+    /// 16 bytes per block, for RGB with smooth alpha.
+    Bc3,
+    /// 16 bytes per block, for RGBA with the highest quality.
+    #[default]
+    Bc7,
+}
+
+/// How heights are encoded into the RGB channels of terrain tiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TerrainEncoding {
+    /// Mapbox Terrain-RGB: height = base + (R * 65536 + G * 256 + B) * interval. Defaults to a
+    /// base of -10000 and an interval of 0.1.
+    #[default]
+    Mapbox,
+    /// Terrarium: height = base + (R * 256 + G + B / 256) * interval. Defaults to a base of
+    /// -32768 and an interval of 1.
+    Terrarium,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum TopSubcommands {
     /// Slices an image into image tiles.
     GenTiles(GenTilesArgs),
     /// Slices an image into image tiles and generates tile LOD layers.
     GenTileLayers(GenTilesArgs),
+    /// Reprojects a georeferenced image into Web Mercator (EPSG:3857) slippy map tiles that line
+    /// up with OpenStreetMap. Every zoom level is saved in a folder named after it.
+    GenWebTiles(GenWebTilesArgs),
+    /// Slices an elevation raster into terrain-RGB tiles and generates tile LOD layers from its
+    /// heights, and optionally hillshade tiles alongside them.
+    GenTerrain(GenTerrainArgs),
     /// Creates single image from directory of tiles.
     StitchImage(StitchImageArgs),
     /// Generates layers from directory of tiles. The existing tiles will be moved into a "./0/" folder.
     /// subsuquent layers will be stored in neighboring folders.
     TilesToLayers(TilesToLayersArgs),
+    /// Checks a directory of tiles or tile layers for corrupt, missing or stray tiles.
+    /// Problems are printed as JSON.
+    Validate(ValidateArgs),
+    /// Replaces a region of the source image of existing tile layers with a patch image. Only the
+    /// tiles the patch overlaps, and the LOD tiles above them, are regenerated.
+    Update(UpdateArgs),
+    /// Merges tile directories or tile layer directories tile by tile, stacking each input over the
+    /// ones before it. LOD layers that not every input has are regenerated.
+    Merge(MergeArgs),
+    /// Packs the tiles of one LOD layer, or of a region of it, densely into fixed size atlas
+    /// pages, skipping empty tiles. A JSON index maps the coordinates of every packed tile to its
+    /// page and UV rectangle.
+    PackAtlas(PackAtlasArgs),
+    /// Converts a pixel of a source image to the tile holding it on a LOD layer, or a tile to the
+    /// pixels it covers. Prints the tile with its parent, children and neighbors as JSON.
+    Coords(CoordsArgs),
+    /// Runs every job of a TOML job file, and prints a summary of all of them.
+    Run(RunArgs),
+}
+
+fn default_tile_dimensions() -> u32 {
+    256
+}
+
+fn default_scale() -> f64 {
+    1.0
 }
 
-#[derive(Debug, Clone, clap::Parser)]
+/// Also read from the jobs of a job file, where every field is a key named like the field, such
+/// as `tile_dimensions = 512`.
+#[derive(Debug, Clone, clap::Parser, Deserialize)]
 pub struct GenTilesArgs {
-    /// The image to generate tiles from.
+    /// The image to generate tiles from, or a JSON manifest of several images to composite into
+    /// one set of tiles.
+    #[clap(long, short = 'i', help_heading = "IO")]
+    pub input: PathBuf,
+
+    /// The directory to save generated tiles to.
+    #[clap(long, short = 'o', help_heading = "IO")]
+    pub output: PathBuf,
+
+    /// The width and height (in pixels) of output tiles.
+    #[clap(long, default_value_t = 256, help_heading = "IO")]
+    #[serde(default = "default_tile_dimensions")]
+    pub tile_dimensions: u32,
+
+    /// The width (in pixels) of output tiles, for tiles that aren't square. Defaults to the tile
+    /// dimensions.
+    #[clap(long, help_heading = "IO")]
+    pub tile_width: Option<u32>,
+
+    /// The height (in pixels) of output tiles, for tiles that aren't square. Defaults to the tile
+    /// dimensions.
+    #[clap(long, help_heading = "IO")]
+    pub tile_height: Option<u32>,
+
+    /// The x pixel to make tile pixel 0,0. Defaults to half the image's width, or to 0 for a
+    /// manifest. Setting an offset ignores the image's world file or GeoTIFF tags.
+    #[clap(long, help_heading = "IO")]
+    pub x_offset: Option<i32>,
+
+    /// The y pixel to make tile pixel 0,0. Defaults to half the image's height, or to 0 for a
+    /// manifest. Setting an offset ignores the image's world file or GeoTIFF tags.
+    #[clap(long, help_heading = "IO")]
+    pub y_offset: Option<i32>,
+
+    /// The image format to write tiles in.
+    #[clap(long, value_enum, default_value_t = TileFormat::Png, help_heading = "IO")]
+    #[serde(default)]
+    pub format: TileFormat,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE, help_heading = "IO")]
+    #[serde(default)]
+    pub name_template: NameTemplate,
+
+    /// Add a border of this many pixels to every tile, copied from the neighboring tiles, so that
+    /// tiles sampled as textures with bilinear filtering have no seams. Where there is no
+    /// neighboring tile, the tile's edge is extruded. Tiles are written this many pixels larger
+    /// on every side, and LOD layers are generated from the tiles without their borders.
+    #[clap(long, default_value_t = 0, help_heading = "IO")]
+    #[serde(default)]
+    pub gutter: u32,
+
+    /// The world units per tile pixel that a georeferenced image is scaled to. Defaults to the
+    /// image's own pixel size.
+    #[clap(long, help_heading = "GEOREFERENCE")]
+    pub resolution: Option<f64>,
+
+    /// The world x coordinate of the top left corner of tile pixel 0,0, for georeferenced
+    /// images.
+    #[clap(long, default_value_t = 0.0, help_heading = "GEOREFERENCE")]
+    #[serde(default)]
+    pub world_origin_x: f64,
+
+    /// The world y coordinate of the top left corner of tile pixel 0,0, for georeferenced
+    /// images.
+    #[clap(long, default_value_t = 0.0, help_heading = "GEOREFERENCE")]
+    #[serde(default)]
+    pub world_origin_y: f64,
+
+    /// Tone map a floating point image, such as an OpenEXR or Radiance HDR image, into 8-bit
+    /// sRGB tiles. Integer images are not tone mapped, and floating point images without a tone
+    /// mapping keep their floating point colors.
+    #[clap(long, value_enum, help_heading = "HDR")]
+    pub tone_map: Option<ToneMapping>,
+
+    /// The exposure, in stops, that colors are scaled by before they are tone mapped.
+    #[clap(
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        help_heading = "HDR"
+    )]
+    #[serde(default)]
+    pub exposure: f64,
+
+    /// Also slice the untouched colors of the image into OpenEXR tiles in this directory, laid
+    /// out like the output directory, so that viewers can control exposure themselves. Not used
+    /// for manifests.
+    #[clap(long, help_heading = "HDR")]
+    pub float_output: Option<PathBuf>,
+
+    /// The block compression of KTX2 and DDS tiles.
+    #[clap(long, value_enum, default_value_t = BlockCompression::Bc7, help_heading = "TEXTURE")]
+    #[serde(default)]
+    pub block_compression: BlockCompression,
+
+    /// Store a full mip chain in every KTX2 and DDS tile, down to 1x1 pixels.
+    #[clap(long, help_heading = "TEXTURE")]
+    #[serde(default)]
+    pub mipmaps: bool,
+
+    /// Don't rotate or flip the image as its EXIF orientation says it should be displayed.
+    #[clap(long, help_heading = "TRANSFORM")]
+    #[serde(default)]
+    pub ignore_orientation: bool,
+
+    /// Mirror the image, after it is oriented as its EXIF orientation says. Setting a transform
+    /// ignores the image's world file or GeoTIFF tags. Transforms are not used for manifests.
+    #[clap(long, value_enum, help_heading = "TRANSFORM")]
+    pub flip: Option<Flip>,
+
+    /// Rotate the image clockwise by this many degrees, after it is flipped. Rotations that aren't
+    /// a multiple of 90 degrees are resampled, and leave the corners of the bounding box of the
+    /// rotated image transparent, or black for images without alpha.
+    #[clap(
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        help_heading = "TRANSFORM"
+    )]
+    #[serde(default)]
+    pub rotate: f64,
+
+    /// Scale the image by this factor, after it is rotated, such as to reach a ground resolution.
+    #[clap(long, default_value_t = 1.0, help_heading = "TRANSFORM")]
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+
+    /// A grayscale or alpha mask image the size of the input image, to cut the image out with
+    /// before it is sliced. The alpha of every pixel is multiplied by the mask's alpha, or by its
+    /// brightness if the mask is opaque, so tiles the mask hides are not written. Not used
+    /// for manifests.
+    #[clap(long, help_heading = "NODATA")]
+    pub mask: Option<PathBuf>,
+
+    /// Make every pixel of this color transparent, such as the margins of a scan. Given as
+    /// `r,g,b`, as hex like `#ff8800`, or as `black` or `white`. Matching pixels inside the image
+    /// are made transparent too. Not used for manifests.
+    #[clap(long, help_heading = "NODATA")]
+    pub nodata: Option<Color>,
+
+    /// How far each channel of a pixel may be from the nodata color, from 0 to 255, for the
+    /// pixel to be made transparent.
+    #[clap(long, default_value_t = 0, help_heading = "NODATA")]
+    #[serde(default)]
+    pub nodata_tolerance: u8,
+
+    /// Crop the image to the bounding box of its pixels that aren't transparent black, after
+    /// nodata pixels are made transparent. Tiles keep the coordinates they would have without
+    /// cropping. Not used for manifests.
+    #[clap(long, help_heading = "NODATA")]
+    #[serde(default)]
+    pub auto_crop: bool,
+
+    /// Resume an interrupted run instead of clearing the output directory. Tiles and LOD layers
+    /// recorded in the output directory's journal are skipped.
+    #[clap(long, help_heading = "JOB")]
+    #[serde(default)]
+    pub resume: bool,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long, help_heading = "JOB")]
+    #[serde(default)]
+    pub fsync: bool,
+
+    /// Clear the output directory even if it is not empty and was not written by tileproc before.
+    /// Only tiles, LOD layers and tileproc's own files are deleted.
+    #[clap(long, help_heading = "JOB")]
+    #[serde(default)]
+    pub force: bool,
+}
+
+impl GenTilesArgs {
+    /// Returns true if the image is flipped, rotated or scaled, other than by its EXIF
+    /// orientation.
+    pub fn has_transforms(&self) -> bool {
+        self.flip.is_some() || self.rotate != 0.0 || self.scale != 1.0
+    }
+
+    /// The width and height of output tiles.
+    pub fn tile_size(&self) -> (u32, u32) {
+        (
+            self.tile_width.unwrap_or(self.tile_dimensions),
+            self.tile_height.unwrap_or(self.tile_dimensions),
+        )
+    }
+
+    /// Checks the arguments that can't be checked while they are parsed, before any directory is
+    /// cleared or tile is written.
+    pub fn validate(&self) -> Result<(), String> {
+        let (tile_width, tile_height) = self.tile_size();
+        validate_tile_size((tile_width, tile_height))?;
+        if self.gutter > tile_width.min(tile_height) {
+            return Err("the gutter can not be wider than a tile".to_string());
+        }
+
+        let transform_is_valid =
+            self.scale > 0.0 && self.scale.is_finite() && self.rotate.is_finite();
+        if !transform_is_valid {
+            return Err(
+                "the scale must be a positive number, and the rotation a number".to_string(),
+            );
+        }
+
+        if self.mask.as_ref().is_some_and(|mask| !mask.is_file()) {
+            return Err("the mask is not a file".to_string());
+        }
+
+        // clearing either directory would clear tiles of the other
+        if let Some(float_output) = &self.float_output {
+            if float_output.starts_with(&self.output) || self.output.starts_with(float_output) {
+                return Err(
+                    "the float output can not be the output, or be inside it or hold it"
+                        .to_string(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that tiles are at least one pixel wide and high.
+fn validate_tile_size((tile_width, tile_height): (u32, u32)) -> Result<(), String> {
+    if tile_width == 0 || tile_height == 0 {
+        return Err("the tile dimensions must be positive".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct GenWebTilesArgs {
+    /// The georeferenced image to generate tiles from. It needs a world file or GeoTIFF tags.
     #[clap(long, short = 'i', help_heading = "IO")]
     pub input: PathBuf,
 
@@ -41,13 +508,141 @@ pub struct GenTilesArgs {
     #[clap(long, default_value_t = 256, help_heading = "IO")]
     pub tile_dimensions: u32,
 
-    /// The x pixel to make tile pixel 0,0.
+    /// The image format to write tiles in.
+    #[clap(long, value_enum, default_value_t = TileFormat::Png, help_heading = "IO")]
+    pub format: TileFormat,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE, help_heading = "IO")]
+    pub name_template: NameTemplate,
+
+    /// The coordinate reference system of the input's world file or GeoTIFF tags. Read from the
+    /// GeoTIFF keys of GeoTIFFs that have them.
+    #[clap(long, value_enum, help_heading = "PROJECTION")]
+    pub source_crs: Option<SourceCrs>,
+
+    /// The most zoomed out level to generate.
+    #[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=30), help_heading = "PROJECTION")]
+    pub min_zoom: u32,
+
+    /// The most zoomed in level to generate. Defaults to the first level that has at least the
+    /// resolution of the input.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(0..=30), help_heading = "PROJECTION")]
+    pub max_zoom: Option<u32>,
+
+    /// How the input is sampled for the tiles of the most zoomed in level. Lower levels are
+    /// shrunk from the levels above them.
+    #[clap(long, value_enum, default_value_t = Resampling::Bilinear, help_heading = "PROJECTION")]
+    pub resampling: Resampling,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long, help_heading = "JOB")]
+    pub fsync: bool,
+
+    /// Clear the output directory even if it is not empty and was not written by tileproc before.
+    /// Only tiles, LOD layers and tileproc's own files are deleted.
+    #[clap(long, help_heading = "JOB")]
+    pub force: bool,
+}
+
+impl GenWebTilesArgs {
+    /// Checks the arguments that can't be checked while they are parsed, before any directory is
+    /// cleared or tile is written.
+    pub fn validate(&self) -> Result<(), String> {
+        validate_tile_size((self.tile_dimensions, self.tile_dimensions))
+    }
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct GenTerrainArgs {
+    /// The elevation raster to generate tiles from, such as a 16-bit grayscale PNG or TIFF, or an
+    /// OpenEXR image. Heights are read from the first channel, and transparent pixels have none.
+    #[clap(long, short = 'i', help_heading = "IO")]
+    pub input: PathBuf,
+
+    /// The directory to save the terrain tile layers to.
+    #[clap(long, short = 'o', help_heading = "IO")]
+    pub output: PathBuf,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE, help_heading = "IO")]
+    pub name_template: NameTemplate,
+
+    /// The width and height (in pixels) of output tiles.
+    #[clap(long, default_value_t = 256, help_heading = "IO")]
+    pub tile_dimensions: u32,
+
+    /// The x pixel to make tile pixel 0,0. Defaults to half the raster's width.
     #[clap(long, help_heading = "IO")]
     pub x_offset: Option<i32>,
 
-    /// The y pixel to make tile pixel 0,0.
+    /// The y pixel to make tile pixel 0,0. Defaults to half the raster's height.
     #[clap(long, help_heading = "IO")]
     pub y_offset: Option<i32>,
+
+    /// How heights are encoded into the terrain tiles, which are always PNGs.
+    #[clap(long, value_enum, default_value_t = TerrainEncoding::Mapbox, help_heading = "TERRAIN")]
+    pub encoding: TerrainEncoding,
+
+    /// The height an encoded value of 0 stands for. Defaults to the encoding's base.
+    #[clap(long, allow_negative_numbers = true, help_heading = "TERRAIN")]
+    pub base: Option<f64>,
+
+    /// The height difference between two consecutive encoded values. Defaults to the encoding's
+    /// interval.
+    #[clap(long, help_heading = "TERRAIN")]
+    pub interval: Option<f64>,
+
+    /// Also generate grayscale hillshade tile layers, lined up with the terrain tiles, into this
+    /// directory.
+    #[clap(long, help_heading = "HILLSHADE")]
+    pub hillshade_output: Option<PathBuf>,
+
+    /// The width of a raster pixel, in height units. Defaults to the ground size in meters of the
+    /// pixels in the middle of the raster for rasters in EPSG:4326 or EPSG:3857, or else to the
+    /// pixel width of the raster's world file or GeoTIFF tags, or else to 1.
+    #[clap(long, help_heading = "HILLSHADE")]
+    pub pixel_size: Option<f64>,
+
+    /// The coordinate reference system of the raster's world file or GeoTIFF tags, to measure its
+    /// pixels in meters. Read from the GeoTIFF keys of GeoTIFFs that have them.
+    #[clap(long, value_enum, help_heading = "HILLSHADE")]
+    pub source_crs: Option<SourceCrs>,
+
+    /// How much heights are exaggerated for shading.
+    #[clap(long, default_value_t = 1.0, help_heading = "HILLSHADE")]
+    pub z_factor: f64,
+
+    /// The direction light comes from, in degrees clockwise from north.
+    #[clap(long, default_value_t = 315.0, help_heading = "HILLSHADE")]
+    pub sun_azimuth: f64,
+
+    /// The angle of the light above the horizon, in degrees.
+    #[clap(long, default_value_t = 45.0, help_heading = "HILLSHADE")]
+    pub sun_altitude: f64,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long, help_heading = "JOB")]
+    pub fsync: bool,
+
+    /// Clear the output directories even if they are not empty and were not written by tileproc
+    /// before. Only tiles, LOD layers and tileproc's own files are deleted.
+    #[clap(long, help_heading = "JOB")]
+    pub force: bool,
+}
+
+impl GenTerrainArgs {
+    /// Checks the arguments that can't be checked while they are parsed, before any directory is
+    /// cleared or tile is written.
+    pub fn validate(&self) -> Result<(), String> {
+        validate_tile_size((self.tile_dimensions, self.tile_dimensions))?;
+        if self.hillshade_output.as_ref() == Some(&self.output) {
+            return Err("the hillshade output can not be the output".to_string());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, clap::Parser)]
@@ -59,6 +654,15 @@ pub struct StitchImageArgs {
     /// The location to save the outputed image to.
     #[clap(long, short = 'o')]
     pub output: PathBuf,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE)]
+    pub name_template: NameTemplate,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long)]
+    pub fsync: bool,
 }
 
 #[derive(Debug, clap::Parser)]
@@ -66,4 +670,446 @@ pub struct TilesToLayersArgs {
     /// The directory of tiles to generate layers from.
     #[clap(long, short = 'i')]
     pub input: PathBuf,
+
+    /// The image format to write LOD tiles in.
+    #[clap(long, value_enum, default_value_t = TileFormat::Png)]
+    pub format: TileFormat,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE)]
+    pub name_template: NameTemplate,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long)]
+    pub fsync: bool,
+
+    /// Clear the output directory even if it is not empty and was not written by tileproc before.
+    /// Only tiles, LOD layers and tileproc's own files are deleted.
+    #[clap(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct ValidateArgs {
+    /// The directory of tiles or tile layers to validate.
+    #[clap(long, short = 'i')]
+    pub input: PathBuf,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE)]
+    pub name_template: NameTemplate,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct UpdateArgs {
+    /// The patch image to apply to the source image.
+    #[clap(long, short = 'i', help_heading = "IO")]
+    pub input: PathBuf,
+
+    /// The directory of tile layers to update, as generated by gen-tile-layers.
+    #[clap(long, short = 'o', help_heading = "IO")]
+    pub output: PathBuf,
+
+    /// The x pixel in the source image of the patch's top left corner.
+    #[clap(long, help_heading = "PATCH")]
+    pub patch_x: i32,
+
+    /// The y pixel in the source image of the patch's top left corner.
+    #[clap(long, help_heading = "PATCH")]
+    pub patch_y: i32,
+
+    /// The width and height (in pixels) the tiles were generated with.
+    #[clap(long, default_value_t = 256, help_heading = "IO")]
+    pub tile_dimensions: u32,
+
+    /// The width (in pixels) the tiles were generated with, if it differs from the tile
+    /// dimensions.
+    #[clap(long, help_heading = "IO")]
+    pub tile_width: Option<u32>,
+
+    /// The height (in pixels) the tiles were generated with, if it differs from the tile
+    /// dimensions.
+    #[clap(long, help_heading = "IO")]
+    pub tile_height: Option<u32>,
+
+    /// The x offset the tiles were generated with. gen-tile-layers defaults this to half the
+    /// source image's width.
+    #[clap(long, help_heading = "IO")]
+    pub x_offset: i32,
+
+    /// The y offset the tiles were generated with. gen-tile-layers defaults this to half the
+    /// source image's height.
+    #[clap(long, help_heading = "IO")]
+    pub y_offset: i32,
+
+    /// The image format the tiles were generated in.
+    #[clap(long, value_enum, default_value_t = TileFormat::Png, help_heading = "IO")]
+    pub format: TileFormat,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE, help_heading = "IO")]
+    pub name_template: NameTemplate,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long, help_heading = "IO")]
+    pub fsync: bool,
+}
+
+impl UpdateArgs {
+    /// The width and height the tiles were generated with.
+    pub fn tile_size(&self) -> (u32, u32) {
+        (
+            self.tile_width.unwrap_or(self.tile_dimensions),
+            self.tile_height.unwrap_or(self.tile_dimensions),
+        )
+    }
+
+    /// Checks the arguments that can't be checked while they are parsed, before any tile is
+    /// written.
+    pub fn validate(&self) -> Result<(), String> {
+        validate_tile_size(self.tile_size())
+    }
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct MergeArgs {
+    /// A tile directory or tile layer directory to merge. Give it once for every input, from the
+    /// bottom one to the top one.
+    #[clap(long, short = 'i', required = true, help_heading = "IO")]
+    pub input: Vec<PathBuf>,
+
+    /// The directory to save the merged tiles to.
+    #[clap(long, short = 'o', help_heading = "IO")]
+    pub output: PathBuf,
+
+    /// The image format to write merged tiles in. Tiles only one input has are copied in their
+    /// own format.
+    #[clap(long, value_enum, default_value_t = TileFormat::Png, help_heading = "IO")]
+    pub format: TileFormat,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE, help_heading = "IO")]
+    pub name_template: NameTemplate,
+
+    /// How each input is combined with the inputs below it.
+    #[clap(long, value_enum, default_value_t = BlendMode::Over, help_heading = "MERGE")]
+    pub blend: BlendMode,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long, help_heading = "JOB")]
+    pub fsync: bool,
+
+    /// Clear the output directory even if it is not empty and was not written by tileproc before.
+    /// Only tiles, LOD layers and tileproc's own files are deleted.
+    #[clap(long, help_heading = "JOB")]
+    pub force: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct PackAtlasArgs {
+    /// The directory of tiles or tile layers to pack.
+    #[clap(long, short = 'i', help_heading = "IO")]
+    pub input: PathBuf,
+
+    /// The directory to save the atlas pages and their index to.
+    #[clap(long, short = 'o', help_heading = "IO")]
+    pub output: PathBuf,
+
+    /// The image format to write atlas pages in.
+    #[clap(long, value_enum, default_value_t = TileFormat::Png, help_heading = "IO")]
+    pub format: TileFormat,
+
+    /// How tile files are named, such as "{x}_{y}.{ext}", "r{row}_c{col}.{ext}" or
+    /// "{quadkey}.{ext}".
+    #[clap(long, default_value = DEFAULT_NAME_TEMPLATE, help_heading = "IO")]
+    pub name_template: NameTemplate,
+
+    /// The block compression of KTX2 and DDS atlas pages.
+    #[clap(long, value_enum, default_value_t = BlockCompression::Bc7, help_heading = "IO")]
+    pub block_compression: BlockCompression,
+
+    /// The LOD layer of a directory of tile layers to pack.
+    #[clap(long, default_value_t = 0, help_heading = "ATLAS")]
+    pub level: u32,
+
+    /// The width and height (in pixels) of atlas pages.
+    #[clap(long, default_value_t = 2048, help_heading = "ATLAS")]
+    pub page_size: u32,
+
+    /// Only pack tiles with an x coordinate of at least this.
+    #[clap(long, allow_negative_numbers = true, help_heading = "ATLAS")]
+    pub min_x: Option<i32>,
+
+    /// Only pack tiles with a y coordinate of at least this.
+    #[clap(long, allow_negative_numbers = true, help_heading = "ATLAS")]
+    pub min_y: Option<i32>,
+
+    /// Only pack tiles with an x coordinate of at most this.
+    #[clap(long, allow_negative_numbers = true, help_heading = "ATLAS")]
+    pub max_x: Option<i32>,
+
+    /// Only pack tiles with a y coordinate of at most this.
+    #[clap(long, allow_negative_numbers = true, help_heading = "ATLAS")]
+    pub max_y: Option<i32>,
+
+    /// Flush every written image to disk before moving it into place.
+    #[clap(long, help_heading = "JOB")]
+    pub fsync: bool,
+
+    /// Clear the output directory even if it is not empty and was not written by tileproc before.
+    /// Only atlas pages, tiles, LOD layers and tileproc's own files are deleted.
+    #[clap(long, help_heading = "JOB")]
+    pub force: bool,
+}
+
+impl PackAtlasArgs {
+    /// Returns true if the tile at the given coordinates is inside the region to pack.
+    pub fn in_region(&self, x: i32, y: i32) -> bool {
+        self.min_x.is_none_or(|min_x| x >= min_x)
+            && self.min_y.is_none_or(|min_y| y >= min_y)
+            && self.max_x.is_none_or(|max_x| x <= max_x)
+            && self.max_y.is_none_or(|max_y| y <= max_y)
+    }
+}
+
+/// Parses a pair of coordinates such as `"-3,12"`.
+fn parse_coordinates<T: FromStr>(value: &str) -> Result<(T, T), String> {
+    let invalid = || format!("\"{}\" is not a pair of coordinates like 3,-12", value);
+    let (x, y) = value.split_once(',').ok_or_else(invalid)?;
+    Ok((
+        x.trim().parse().map_err(|_| invalid())?,
+        y.trim().parse().map_err(|_| invalid())?,
+    ))
+}
+
+#[derive(Debug, clap::Parser)]
+#[clap(group(clap::ArgGroup::new("position").required(true).args(["pixel", "tile"])))]
+pub struct CoordsArgs {
+    /// The pixel of the source image to find the tile of, as x,y.
+    #[clap(long, value_parser = parse_coordinates::<i64>, allow_hyphen_values = true, help_heading = "POSITION")]
+    pub pixel: Option<(i64, i64)>,
+
+    /// The tile to find the pixels of, as x,y.
+    #[clap(long, value_parser = parse_coordinates::<i32>, allow_hyphen_values = true, help_heading = "POSITION")]
+    pub tile: Option<(i32, i32)>,
+
+    /// The LOD layer of the tile.
+    #[clap(long, default_value_t = 0, help_heading = "POSITION")]
+    pub level: u32,
+
+    /// The width and height (in pixels) of the tiles.
+    #[clap(long, default_value_t = 256, help_heading = "PYRAMID")]
+    pub tile_dimensions: u32,
+
+    /// The width (in pixels) of the tiles, if it differs from the tile dimensions.
+    #[clap(long, help_heading = "PYRAMID")]
+    pub tile_width: Option<u32>,
+
+    /// The height (in pixels) of the tiles, if it differs from the tile dimensions.
+    #[clap(long, help_heading = "PYRAMID")]
+    pub tile_height: Option<u32>,
+
+    /// The x pixel of the source image that was made tile pixel 0,0.
+    #[clap(
+        long,
+        default_value_t = 0,
+        allow_negative_numbers = true,
+        help_heading = "PYRAMID"
+    )]
+    pub x_offset: i64,
+
+    /// The y pixel of the source image that was made tile pixel 0,0.
+    #[clap(
+        long,
+        default_value_t = 0,
+        allow_negative_numbers = true,
+        help_heading = "PYRAMID"
+    )]
+    pub y_offset: i64,
+}
+
+impl CoordsArgs {
+    /// The width and height of the tiles.
+    pub fn tile_size(&self) -> (u32, u32) {
+        (
+            self.tile_width.unwrap_or(self.tile_dimensions),
+            self.tile_height.unwrap_or(self.tile_dimensions),
+        )
+    }
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct RunArgs {
+    /// The TOML job file to run. Relative paths in it are relative to the job file.
+    pub job_file: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn gen_tiles_args(extra: &[&str]) -> GenTilesArgs {
+        let args = ["gen-tiles", "-i", "in.png", "-o", "out"];
+        GenTilesArgs::parse_from(args.iter().chain(extra))
+    }
+
+    #[test]
+    fn validate_accepts_the_defaults() {
+        assert_eq!(gen_tiles_args(&[]).validate(), Ok(()));
+        assert_eq!(
+            gen_tiles_args(&["--gutter", "8", "--rotate", "-30", "--scale", "0.5"]).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_rejects_gutters_wider_than_a_tile() {
+        assert!(
+            gen_tiles_args(&["--tile-dimensions", "16", "--gutter", "17"])
+                .validate()
+                .is_err()
+        );
+        assert!(gen_tiles_args(&["--tile-height", "8", "--gutter", "9"])
+            .validate()
+            .is_err());
+        assert_eq!(
+            gen_tiles_args(&["--tile-height", "8", "--gutter", "8"]).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_rejects_invalid_transforms() {
+        for extra in [
+            ["--scale", "0"],
+            ["--scale=-1", "--rotate=0"],
+            ["--scale", "NaN"],
+            ["--scale", "inf"],
+            ["--rotate", "inf"],
+            ["--rotate", "NaN"],
+        ] {
+            assert!(gen_tiles_args(&extra).validate().is_err(), "{:?}", extra);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_missing_masks() {
+        let args = gen_tiles_args(&["--mask", "does/not/exist.png"]);
+        assert_eq!(args.validate(), Err("the mask is not a file".to_string()));
+
+        let mask = tempfile::NamedTempFile::new().unwrap();
+        let args = gen_tiles_args(&["--mask", mask.path().to_str().unwrap()]);
+        assert_eq!(args.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_empty_tiles() {
+        for extra in [
+            ["--tile-dimensions", "0"],
+            ["--tile-width", "0"],
+            ["--tile-height", "0"],
+        ] {
+            assert_eq!(
+                gen_tiles_args(&extra).validate(),
+                Err("the tile dimensions must be positive".to_string()),
+                "{:?}",
+                extra
+            );
+        }
+
+        let update_args = |extra: &[&str]| {
+            let args = [
+                "update",
+                "-i",
+                "patch.png",
+                "-o",
+                "out",
+                "--patch-x=0",
+                "--patch-y=0",
+                "--x-offset=0",
+                "--y-offset=0",
+            ];
+            UpdateArgs::parse_from(args.iter().chain(extra))
+        };
+        assert!(update_args(&["--tile-width", "0"]).validate().is_err());
+        assert_eq!(update_args(&[]).validate(), Ok(()));
+
+        let terrain_args = |extra: &[&str]| {
+            GenTerrainArgs::parse_from(
+                ["gen-terrain", "-i", "dem.png", "-o", "out"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert!(terrain_args(&["--tile-dimensions", "0"])
+            .validate()
+            .is_err());
+        assert!(terrain_args(&["--hillshade-output", "out"])
+            .validate()
+            .is_err());
+        assert_eq!(
+            terrain_args(&["--hillshade-output", "shade"]).validate(),
+            Ok(())
+        );
+
+        let web_tiles_args = |extra: &[&str]| {
+            GenWebTilesArgs::parse_from(
+                ["gen-web-tiles", "-i", "in.tif", "-o", "out"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert!(web_tiles_args(&["--tile-dimensions", "0"])
+            .validate()
+            .is_err());
+        assert_eq!(web_tiles_args(&[]).validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_float_output_in_or_around_the_output() {
+        assert!(gen_tiles_args(&["--float-output", "out"])
+            .validate()
+            .is_err());
+        assert!(gen_tiles_args(&["--float-output", "out/float"])
+            .validate()
+            .is_err());
+        let mut args = gen_tiles_args(&["--float-output", "float"]);
+        args.output = PathBuf::from("float/out");
+        assert!(args.validate().is_err());
+
+        assert_eq!(
+            gen_tiles_args(&["--float-output", "out-float"]).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn job_files_name_args_like_the_command_line() {
+        let args: GenTilesArgs = toml::from_str(
+            r#"
+            input = "in.png"
+            output = "out"
+            tile_dimensions = 512
+            gutter = 600
+            "#,
+        )
+        .unwrap();
+        assert_eq!(args.tile_size(), (512, 512));
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn tile_formats_are_found_by_extension() {
+        assert_eq!(TileFormat::from_extension("jpg"), Some(TileFormat::Jpeg));
+        assert_eq!(TileFormat::from_extension("KTX2"), Some(TileFormat::Ktx2));
+        assert_eq!(TileFormat::from_extension("jpeg"), None);
+        assert_eq!(TileFormat::from_extension("txt"), None);
+        assert_eq!(TileFormat::from_extension(""), None);
+    }
 }
@@ -0,0 +1,375 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use image::{imageops, DynamicImage, GenericImageView};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::args::PackAtlasArgs;
+use crate::error::TileError;
+use crate::pixel::{blank_tile, buffer, is_blank, with_pixel_type, TilePixel};
+use crate::texture::open_tile;
+use crate::tiler::{dir_paths, JobContext};
+
+/// The name of the index of the tiles on atlas pages, written next to the pages.
+pub const ATLAS_INDEX_FILE_NAME: &str = "tileproc-atlas.json";
+
+/// The start of the file names of atlas pages, which are followed by the page's index.
+const PAGE_PREFIX: &str = "page-";
+
+/// Returns true for the files `pack_atlas` writes: the atlas index, and pages such as
+/// `"page-3.png"` and their interrupted writes.
+pub fn is_atlas_file(path: &Path) -> bool {
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+
+    file_name == ATLAS_INDEX_FILE_NAME
+        || file_name
+            .strip_prefix(PAGE_PREFIX)
+            .and_then(|rest| rest.split('.').next())
+            .is_some_and(|index| index.parse::<usize>().is_ok())
+}
+
+/// Where a tile was packed. UV coordinates go from 0 to 1 across a page, starting at its top left
+/// corner, so v grows downwards like image rows.
+#[derive(Debug, Clone, Serialize)]
+pub struct AtlasTile {
+    /// The x coordinate of the tile in its LOD layer.
+    pub x: i32,
+    /// The y coordinate of the tile in its LOD layer.
+    pub y: i32,
+    /// The index of the tile's page in `AtlasIndex::pages`.
+    pub page: usize,
+    /// The pixel position of the tile's top left corner on its page.
+    pub position: (u32, u32),
+    /// The rectangle the tile covers on its page, as `[u_min, v_min, u_max, v_max]`.
+    pub uv: [f64; 4],
+}
+
+/// The index of atlas pages, written as JSON next to them.
+#[derive(Debug, Clone, Serialize)]
+pub struct AtlasIndex {
+    /// The LOD layer the tiles were packed from.
+    pub level: u32,
+    pub tile_dimensions: (u32, u32),
+    pub page_dimensions: (u32, u32),
+    /// The file names of the pages, relative to the index.
+    pub pages: Vec<String>,
+    /// Every packed tile, in the order they were packed.
+    pub tiles: Vec<AtlasTile>,
+}
+
+/// Returns true if every pixel of a tile is blank, like the tiles that aren't written at all.
+fn is_empty_tile(tile: &DynamicImage) -> bool {
+    with_pixel_type!(tile.color(), P => buffer::<P>(tile).pixels().all(is_blank))
+}
+
+/// Packs the tiles of `dir` within the region of `args` into atlas pages in `args.output`, and
+/// writes their index next to them.
+///
+/// Tiles are packed in rows, in the order of their coordinates from top to bottom and left to
+/// right, so that neighboring tiles tend to share a page. Empty tiles are skipped. Every page has
+/// the page size of `args`, even the last one, and the pixel type of the first tile.
+pub fn pack_atlas(
+    dir: &Path,
+    args: &PackAtlasArgs,
+    context: JobContext,
+) -> Result<AtlasIndex, TileError> {
+    // ordered by row, then column
+    let tiles: BTreeMap<(i32, i32), PathBuf> = dir_paths(dir)?
+        .into_iter()
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let (x, y) = context.writer.tile_coords(&path)?;
+            args.in_region(x, y).then_some(((y, x), path))
+        })
+        .collect();
+
+    let page_dimensions = (args.page_size, args.page_size);
+    let mut index = AtlasIndex {
+        level: args.level,
+        tile_dimensions: (0, 0),
+        page_dimensions,
+        pages: Vec::new(),
+        tiles: Vec::new(),
+    };
+
+    if let Some(first_tile) = tiles.values().next() {
+        let first_tile = open_tile(first_tile).map_err(TileError::image(first_tile))?;
+        index.tile_dimensions = first_tile.dimensions();
+
+        with_pixel_type!(first_tile.color(), P => {
+            pack_pages::<P>(&tiles, args, &mut index, context)
+        })?;
+    }
+
+    let index_path = args.output.join(ATLAS_INDEX_FILE_NAME);
+    fs::write(&index_path, serde_json::to_string_pretty(&index).unwrap())
+        .map_err(TileError::io(&index_path))?;
+    Ok(index)
+}
+
+fn pack_pages<P: TilePixel>(
+    tiles: &BTreeMap<(i32, i32), PathBuf>,
+    args: &PackAtlasArgs,
+    index: &mut AtlasIndex,
+    context: JobContext,
+) -> Result<(), TileError> {
+    let (tile_width, tile_height) = index.tile_dimensions;
+    let (page_width, page_height) = index.page_dimensions;
+
+    let columns = page_width / tile_width;
+    let tiles_per_page = (columns * (page_height / tile_height)) as usize;
+    if tiles_per_page == 0 {
+        return Err(TileError::TileLargerThanPage {
+            tile_dimensions: index.tile_dimensions,
+            page_dimensions: index.page_dimensions,
+        });
+    }
+
+    context.progress.message("finding empty tiles...");
+    let tiles: Vec<(&(i32, i32), &PathBuf)> = tiles.iter().collect();
+    let packed_tiles: Vec<((i32, i32), &PathBuf)> = tiles
+        .par_iter()
+        .map(|&(&(y, x), path)| {
+            context.cancel.check()?;
+
+            let tile = open_tile(path).map_err(TileError::image(path))?;
+            if tile.dimensions() != index.tile_dimensions {
+                return Err(TileError::DimensionMismatch {
+                    path: path.clone(),
+                    expected: index.tile_dimensions,
+                    found: tile.dimensions(),
+                });
+            }
+            Ok((!is_empty_tile(&tile)).then_some(((x, y), path)))
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let pages: Vec<&[((i32, i32), &PathBuf)]> = packed_tiles.chunks(tiles_per_page).collect();
+    let position = |slot: usize| {
+        let slot = slot as u32;
+        (
+            (slot % columns) * tile_width,
+            (slot / columns) * tile_height,
+        )
+    };
+
+    context
+        .progress
+        .start_level(args.level, packed_tiles.len() as u64);
+
+    pages
+        .par_iter()
+        .enumerate()
+        .try_for_each(|(page_index, page_tiles)| {
+            let mut page = blank_tile::<P>(page_width, page_height);
+
+            for (slot, (_, path)) in page_tiles.iter().enumerate() {
+                context.cancel.check()?;
+
+                let tile = open_tile(path).map_err(TileError::image(path))?;
+                let (x, y) = position(slot);
+                imageops::replace(&mut page, &*buffer::<P>(&tile), x as i64, y as i64);
+            }
+
+            let page_path = args.output.join(page_file_name(page_index, context));
+            let bytes_written = context
+                .writer
+                .write(&P::into_dynamic(page), &page_path)
+                .map_err(TileError::image(&page_path))?;
+
+            // the page's bytes are counted with its first tile
+            context.progress.tile_done(bytes_written);
+            for _ in 1..page_tiles.len() {
+                context.progress.tile_done(0);
+            }
+            Ok::<(), TileError>(())
+        })?;
+
+    context.progress.finish_level();
+
+    for (page_index, page_tiles) in pages.iter().enumerate() {
+        index.pages.push(page_file_name(page_index, context));
+
+        for (slot, &((x, y), _)) in page_tiles.iter().enumerate() {
+            let (left, top) = position(slot);
+            index.tiles.push(AtlasTile {
+                x,
+                y,
+                page: page_index,
+                position: (left, top),
+                uv: [
+                    left as f64 / page_width as f64,
+                    top as f64 / page_height as f64,
+                    (left + tile_width) as f64 / page_width as f64,
+                    (top + tile_height) as f64 / page_height as f64,
+                ],
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the file name of the page at `page_index`, such as `"page-3.png"`.
+fn page_file_name(page_index: usize, context: JobContext) -> String {
+    format!(
+        "{}{}.{}",
+        PAGE_PREFIX,
+        page_index,
+        context.writer.format.extension()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::args::TileFormat;
+    use crate::cancel::CancelToken;
+    use crate::progress::Progress;
+    use crate::writer::TileWriter;
+
+    fn write_tile(dir: &Path, x: i32, y: i32, color: [u8; 4]) {
+        RgbaImage::from_pixel(4, 4, Rgba(color))
+            .save(dir.join(format!("{},{}.png", x, y)))
+            .unwrap();
+    }
+
+    fn pack(dir: &Path, extra: &[&str]) -> Result<AtlasIndex, TileError> {
+        let output = dir.join("atlas");
+        fs::create_dir_all(&output).unwrap();
+        let args = PackAtlasArgs::parse_from(
+            [
+                "pack-atlas",
+                "-i",
+                dir.to_str().unwrap(),
+                "-o",
+                output.to_str().unwrap(),
+            ]
+            .iter()
+            .chain(extra),
+        );
+        let context = JobContext {
+            writer: &TileWriter::new(TileFormat::Png, false),
+            journal: None,
+            progress: &Progress::none(),
+            cancel: &CancelToken::new(),
+        };
+        pack_atlas(dir, &args, context)
+    }
+
+    #[test]
+    fn packs_tiles_row_by_row_and_skips_empty_tiles() {
+        let temp = tempfile::tempdir().unwrap();
+        write_tile(temp.path(), 3, 2, [255, 255, 255, 255]);
+        write_tile(temp.path(), 0, 1, [0, 0, 255, 255]);
+        write_tile(temp.path(), 1, 0, [0, 0, 0, 0]);
+        write_tile(temp.path(), 0, 0, [255, 0, 0, 255]);
+        write_tile(temp.path(), -1, 1, [0, 255, 0, 255]);
+        write_tile(temp.path(), 2, 2, [255, 255, 0, 255]);
+
+        // 4 tiles fit on a page
+        let index = pack(temp.path(), &["--page-size", "8"]).unwrap();
+        let coords: Vec<_> = index.tiles.iter().map(|tile| (tile.x, tile.y)).collect();
+        assert_eq!(coords, [(0, 0), (-1, 1), (0, 1), (2, 2), (3, 2)]);
+        assert_eq!(index.tile_dimensions, (4, 4));
+        assert_eq!(index.pages, ["page-0.png", "page-1.png"]);
+
+        let placement: Vec<_> = index
+            .tiles
+            .iter()
+            .map(|tile| (tile.page, tile.position))
+            .collect();
+        assert_eq!(
+            placement,
+            [
+                (0, (0, 0)),
+                (0, (4, 0)),
+                (0, (0, 4)),
+                (0, (4, 4)),
+                (1, (0, 0))
+            ]
+        );
+
+        let page = |name: &str| {
+            image::open(temp.path().join("atlas").join(name))
+                .unwrap()
+                .to_rgba8()
+        };
+        assert_eq!(page("page-0.png").dimensions(), (8, 8));
+        assert_eq!(*page("page-0.png").get_pixel(5, 1), Rgba([0, 255, 0, 255]));
+        assert_eq!(
+            *page("page-0.png").get_pixel(7, 7),
+            Rgba([255, 255, 0, 255])
+        );
+        assert_eq!(*page("page-1.png").get_pixel(0, 0), Rgba([255; 4]));
+        assert_eq!(*page("page-1.png").get_pixel(4, 4), Rgba([0; 4]));
+        assert!(temp
+            .path()
+            .join("atlas")
+            .join(ATLAS_INDEX_FILE_NAME)
+            .is_file());
+    }
+
+    #[test]
+    fn packs_only_tiles_in_the_region() {
+        let temp = tempfile::tempdir().unwrap();
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            write_tile(temp.path(), x, y, [255, 0, 0, 255]);
+        }
+
+        let index = pack(temp.path(), &["--page-size", "8", "--min-x", "1"]).unwrap();
+        let coords: Vec<_> = index.tiles.iter().map(|tile| (tile.x, tile.y)).collect();
+        assert_eq!(coords, [(1, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn uvs_cover_the_tile_on_its_page() {
+        let temp = tempfile::tempdir().unwrap();
+        for x in 0..3 {
+            write_tile(temp.path(), x, 0, [255, 0, 0, 255]);
+        }
+
+        // 3 tiles fit in a row of a 12x12 page
+        let index = pack(temp.path(), &["--page-size", "12"]).unwrap();
+        let uvs: Vec<_> = index.tiles.iter().map(|tile| tile.uv).collect();
+        let third = 1.0 / 3.0;
+        let expected = [
+            [0.0, 0.0, third, third],
+            [third, 0.0, 2.0 * third, third],
+            [2.0 * third, 0.0, 1.0, third],
+        ];
+        for (uv, expected) in uvs.iter().zip(expected) {
+            for (value, expected) in uv.iter().zip(expected) {
+                assert!(
+                    (value - expected).abs() < 1e-9,
+                    "{uv:?} is not {expected:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tiles_larger_than_a_page() {
+        let temp = tempfile::tempdir().unwrap();
+        write_tile(temp.path(), 0, 0, [255, 0, 0, 255]);
+
+        assert!(matches!(
+            pack(temp.path(), &["--page-size", "2"]),
+            Err(TileError::TileLargerThanPage {
+                tile_dimensions: (4, 4),
+                page_dimensions: (2, 2),
+            })
+        ));
+    }
+}
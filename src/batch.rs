@@ -0,0 +1,561 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::args::GenTilesArgs;
+use crate::cancel::CancelToken;
+use crate::error::TileError;
+use crate::progress::{Progress, ProgressEvent};
+use crate::tiler::{gen_tile_layers, gen_tiles_to_dir, start_journal, JobContext};
+use crate::writer::TileWriter;
+
+/// How the tiles of a job are laid out in its output directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// Only the tiles sliced from the source image, like `gen-tiles`.
+    Flat,
+    /// The sliced tiles in a "0" directory and LOD layers above them, like `gen-tile-layers`.
+    #[default]
+    Layers,
+}
+
+impl Layout {
+    /// The command a job with this layout is journaled as, so that the command line can resume
+    /// it.
+    fn command(self) -> &'static str {
+        match self {
+            Layout::Flat => "gen-tiles",
+            Layout::Layers => "gen-tile-layers",
+        }
+    }
+}
+
+/// One job of a job file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Job {
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(flatten)]
+    pub args: GenTilesArgs,
+    /// Keys that are not options, which are rejected when the job file is loaded.
+    #[serde(flatten)]
+    unknown_keys: BTreeMap<String, toml::Value>,
+}
+
+impl Job {
+    pub fn new(layout: Layout, args: GenTilesArgs) -> Job {
+        Job {
+            layout,
+            args,
+            unknown_keys: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_parallel_jobs() -> usize {
+    1
+}
+
+/// A list of tiling jobs, read from a TOML file.
+/// ```toml
+/// threads = 8
+/// parallel_jobs = 2
+///
+/// [[jobs]]
+/// input = "maps/north.png"
+/// output = "tiles/north"
+///
+/// [[jobs]]
+/// input = "maps/south.png"
+/// output = "tiles/south"
+/// layout = "flat"
+/// tile_dimensions = 512
+/// x_offset = 0
+/// y_offset = -4096
+/// format = "jpeg"
+/// name_template = "{x}_{y}.{ext}"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobFile {
+    /// The number of threads slicing and shrinking tiles, shared by all jobs. Defaults to one per
+    /// CPU.
+    pub threads: Option<usize>,
+    /// The number of jobs that run at once.
+    #[serde(default = "default_parallel_jobs")]
+    pub parallel_jobs: usize,
+    pub jobs: Vec<Job>,
+}
+
+impl JobFile {
+    /// Reads a job file. Relative input and output paths are resolved against the directory of
+    /// the job file.
+    pub fn load(path: &Path) -> io::Result<JobFile> {
+        let contents = fs::read_to_string(path)?;
+        let mut job_file: JobFile = toml::from_str(&contents).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "invalid job file {}: {}",
+                    path.display(),
+                    err.to_string().trim_end()
+                ),
+            )
+        })?;
+
+        let invalid_data = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid job file {}: {}", path.display(), message),
+            )
+        };
+
+        if job_file.parallel_jobs == 0 {
+            return Err(invalid_data("parallel_jobs must be at least 1".to_string()));
+        }
+
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        for (index, job) in job_file.jobs.iter_mut().enumerate() {
+            if let Some(key) = job.unknown_keys.keys().next() {
+                return Err(invalid_data(format!(
+                    "unknown key `{}` in job {}",
+                    key,
+                    index + 1
+                )));
+            }
+
+            job.args.input = base_dir.join(&job.args.input);
+            job.args.output = base_dir.join(&job.args.output);
+            if let Some(float_output) = &mut job.args.float_output {
+                *float_output = base_dir.join(&*float_output);
+            }
+            if let Some(mask) = &mut job.args.mask {
+                *mask = base_dir.join(&*mask);
+            }
+        }
+
+        Ok(job_file)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Succeeded,
+    Failed,
+    /// The job was stopped by its cancel token, or never started because of it.
+    Cancelled,
+}
+
+/// The outcome of one job.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobReport {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub status: JobStatus,
+    /// Why the job failed.
+    pub error: Option<String>,
+    /// Tiles written, found to be empty, or kept from a previous run, over all LOD layers.
+    pub tiles_done: u64,
+    pub bytes_written: u64,
+    pub seconds: f64,
+}
+
+/// The outcome of every job of a job file.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchReport {
+    pub jobs: Vec<JobReport>,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    pub tiles_done: u64,
+    pub bytes_written: u64,
+    pub seconds: f64,
+}
+
+impl BatchReport {
+    pub fn is_success(&self) -> bool {
+        self.succeeded == self.jobs.len()
+    }
+}
+
+/// Something that happened while running the jobs of a job file. `job` is the index of the job
+/// in the job file.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BatchEvent {
+    JobStarted {
+        job: usize,
+        input: PathBuf,
+        output: PathBuf,
+    },
+    JobProgress {
+        job: usize,
+        progress: ProgressEvent,
+    },
+    JobFinished {
+        job: usize,
+        report: JobReport,
+    },
+}
+
+type BatchCallback = Arc<dyn Fn(&BatchEvent) + Send + Sync>;
+
+/// Runs one job on the current rayon pool.
+fn run_job(index: usize, job: &Job, callback: &BatchCallback, cancel: &CancelToken) -> JobReport {
+    let started = Instant::now();
+    let tiles_done = Arc::new(AtomicU64::new(0));
+    let bytes_written = Arc::new(AtomicU64::new(0));
+
+    let progress = {
+        let callback = Arc::clone(callback);
+        let tiles_done = Arc::clone(&tiles_done);
+        let bytes_written = Arc::clone(&bytes_written);
+        Progress::new(move |event| {
+            if let ProgressEvent::LevelFinished {
+                tiles_done: level_tiles,
+                bytes_written: level_bytes,
+                ..
+            } = event
+            {
+                tiles_done.fetch_add(*level_tiles, Ordering::Relaxed);
+                bytes_written.fetch_add(*level_bytes, Ordering::Relaxed);
+            }
+            callback(&BatchEvent::JobProgress {
+                job: index,
+                progress: event.clone(),
+            });
+        })
+    };
+
+    let result: Result<(), String> = if cancel.is_cancelled() {
+        Err(TileError::Cancelled.to_string())
+    } else if !job.args.input.is_file() {
+        Err(format!("{} is not a file", job.args.input.display()))
+    } else if let Err(err) = job.args.validate() {
+        Err(err)
+    } else {
+        callback(&BatchEvent::JobStarted {
+            job: index,
+            input: job.args.input.clone(),
+            output: job.args.output.clone(),
+        });
+
+        start_journal(job.layout.command(), &job.args)
+            .map_err(|err| err.to_string())
+            .and_then(|journal| {
+                let context = JobContext {
+                    writer: &TileWriter::new(job.args.format, job.args.fsync)
+                        .with_texture(job.args.block_compression, job.args.mipmaps)
+                        .with_name_template(job.args.name_template.clone()),
+                    journal: Some(&journal),
+                    progress: &progress,
+                    cancel,
+                };
+
+                match job.layout {
+                    Layout::Flat => gen_tiles_to_dir(&job.args, context),
+                    Layout::Layers => gen_tile_layers(&job.args, context),
+                }
+                .map_err(|err| err.to_string())
+            })
+    };
+
+    let status = match &result {
+        Ok(()) => JobStatus::Succeeded,
+        Err(_) if cancel.is_cancelled() => JobStatus::Cancelled,
+        Err(_) => JobStatus::Failed,
+    };
+
+    JobReport {
+        input: job.args.input.clone(),
+        output: job.args.output.clone(),
+        status,
+        error: result.err(),
+        tiles_done: tiles_done.load(Ordering::Relaxed),
+        bytes_written: bytes_written.load(Ordering::Relaxed),
+        seconds: started.elapsed().as_secs_f64(),
+    }
+}
+
+/// Runs every job of a job file, `parallel_jobs` of them at a time, in the order they are listed.
+///
+/// All jobs share one rayon pool of `threads` threads for slicing and shrinking tiles. A job that
+/// fails doesn't stop the others. Once `cancel` is cancelled, running jobs stop between tiles and
+/// the remaining ones are not started, so they can all be resumed.
+pub fn run_jobs(
+    job_file: &JobFile,
+    callback: impl Fn(&BatchEvent) + Send + Sync + 'static,
+    cancel: &CancelToken,
+) -> BatchReport {
+    let started = Instant::now();
+    let callback: BatchCallback = Arc::new(callback);
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(job_file.threads.unwrap_or(0))
+        .build()
+        .unwrap();
+
+    let next_job = AtomicUsize::new(0);
+    let mut reports: Vec<(usize, JobReport)> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..job_file.parallel_jobs.min(job_file.jobs.len()))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut reports = Vec::new();
+                    loop {
+                        let index = next_job.fetch_add(1, Ordering::Relaxed);
+                        let Some(job) = job_file.jobs.get(index) else {
+                            break;
+                        };
+
+                        let report = pool.install(|| run_job(index, job, &callback, cancel));
+                        callback(&BatchEvent::JobFinished {
+                            job: index,
+                            report: report.clone(),
+                        });
+                        reports.push((index, report));
+                    }
+                    reports
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    reports.sort_by_key(|(index, _)| *index);
+
+    let jobs: Vec<JobReport> = reports.into_iter().map(|(_, report)| report).collect();
+    let count = |status| jobs.iter().filter(|job| job.status == status).count();
+
+    BatchReport {
+        succeeded: count(JobStatus::Succeeded),
+        failed: count(JobStatus::Failed),
+        cancelled: count(JobStatus::Cancelled),
+        tiles_done: jobs.iter().map(|job| job.tiles_done).sum(),
+        bytes_written: jobs.iter().map(|job| job.bytes_written).sum(),
+        seconds: started.elapsed().as_secs_f64(),
+        jobs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn write_job_file(dir: &Path, contents: &str) -> PathBuf {
+        let path = dir.join("jobs.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_job_files_next_to_their_inputs() {
+        let temp = tempfile::tempdir().unwrap();
+        let job_file = JobFile::load(&write_job_file(
+            temp.path(),
+            r#"
+            threads = 2
+
+            [[jobs]]
+            input = "maps/north.png"
+            output = "tiles/north"
+            mask = "masks/north.png"
+
+            [[jobs]]
+            input = "south.png"
+            output = "tiles/south"
+            layout = "flat"
+            tile_dimensions = 512
+            "#,
+        ))
+        .unwrap();
+
+        assert_eq!((job_file.threads, job_file.parallel_jobs), (Some(2), 1));
+        let [north, south] = &job_file.jobs[..] else {
+            panic!("expected 2 jobs");
+        };
+        assert_eq!(north.layout, Layout::Layers);
+        assert_eq!(north.args.input, temp.path().join("maps/north.png"));
+        assert_eq!(north.args.output, temp.path().join("tiles/north"));
+        assert_eq!(north.args.mask, Some(temp.path().join("masks/north.png")));
+        assert_eq!(south.layout, Layout::Flat);
+        assert_eq!(south.args.tile_size(), (512, 512));
+    }
+
+    #[test]
+    fn rejects_invalid_job_files() {
+        let temp = tempfile::tempdir().unwrap();
+        for contents in [
+            "parallel_jobs = 0\njobs = []",
+            "[[jobs]]\noutput = \"a\"",
+            "jobs = []\nthreads = \"many\"",
+        ] {
+            let err = JobFile::load(&write_job_file(temp.path(), contents)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{contents}");
+        }
+
+        let err = JobFile::load(&write_job_file(
+            temp.path(),
+            "[[jobs]]\ninput = \"a.png\"\noutput = \"a\"\ntile_size = 512",
+        ))
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("unknown key `tile_size` in job 1"));
+    }
+
+    #[test]
+    fn runs_every_job_and_reports_the_failed_ones() {
+        let temp = tempfile::tempdir().unwrap();
+        RgbaImage::from_pixel(64, 64, Rgba([10, 20, 30, 255]))
+            .save(temp.path().join("in.png"))
+            .unwrap();
+        let job_file = JobFile::load(&write_job_file(
+            temp.path(),
+            r#"
+            parallel_jobs = 2
+
+            [[jobs]]
+            input = "in.png"
+            output = "layers"
+            tile_dimensions = 16
+            x_offset = 0
+            y_offset = 0
+
+            [[jobs]]
+            input = "missing.png"
+            output = "missing"
+
+            [[jobs]]
+            input = "in.png"
+            output = "flat"
+            layout = "flat"
+            tile_dimensions = 32
+            x_offset = 0
+            y_offset = 0
+
+            [[jobs]]
+            input = "in.png"
+            output = "gutter"
+            tile_dimensions = 16
+            gutter = 20
+            "#,
+        ))
+        .unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let report = {
+            let events = Arc::clone(&events);
+            run_jobs(
+                &job_file,
+                move |event| {
+                    if let BatchEvent::JobStarted { job, .. }
+                    | BatchEvent::JobFinished { job, .. } = event
+                    {
+                        events.lock().unwrap().push(*job);
+                    }
+                },
+                &CancelToken::new(),
+            )
+        };
+
+        let statuses: Vec<_> = report.jobs.iter().map(|job| job.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                JobStatus::Succeeded,
+                JobStatus::Failed,
+                JobStatus::Succeeded,
+                JobStatus::Failed
+            ]
+        );
+        assert_eq!(
+            (report.succeeded, report.failed, report.cancelled),
+            (2, 2, 0)
+        );
+        assert!(!report.is_success());
+        assert!(report.jobs[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .ends_with("is not a file"));
+        assert!(report.jobs[3].error.is_some());
+
+        // the tiles along the right and bottom edges of the image are empty, and done too: 5x5
+        // tiles in level 0 and 2x2 in level 1, and 3x3 flat tiles
+        assert_eq!(report.jobs[0].tiles_done, 25 + 4);
+        assert_eq!(report.jobs[2].tiles_done, 9);
+        assert_eq!(report.tiles_done, 25 + 4 + 9);
+        assert!(temp.path().join("layers/1/0,0.png").is_file());
+        assert!(temp.path().join("flat/1,1.png").is_file());
+        assert!(!temp.path().join("gutter").exists());
+
+        // only jobs that start are reported as started, every job is reported as finished
+        let mut events = events.lock().unwrap().clone();
+        events.sort();
+        assert_eq!(events, [0, 0, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn undecodable_inputs_fail_only_their_job() {
+        let temp = tempfile::tempdir().unwrap();
+        RgbaImage::from_pixel(32, 32, Rgba([10, 20, 30, 255]))
+            .save(temp.path().join("in.png"))
+            .unwrap();
+        fs::write(temp.path().join("broken.png"), b"not a png").unwrap();
+        let job_file = JobFile::load(&write_job_file(
+            temp.path(),
+            r#"
+            [[jobs]]
+            input = "broken.png"
+            output = "broken"
+
+            [[jobs]]
+            input = "in.png"
+            output = "out"
+            tile_dimensions = 16
+            "#,
+        ))
+        .unwrap();
+
+        let report = run_jobs(&job_file, |_| {}, &CancelToken::new());
+        let statuses: Vec<_> = report.jobs.iter().map(|job| job.status).collect();
+        assert_eq!(statuses, [JobStatus::Failed, JobStatus::Succeeded]);
+        let error = report.jobs[0].error.as_ref().unwrap();
+        assert!(error.contains("broken.png"), "{error}");
+    }
+
+    #[test]
+    fn cancelled_jobs_are_not_started() {
+        let temp = tempfile::tempdir().unwrap();
+        let job_file = JobFile::load(&write_job_file(
+            temp.path(),
+            "[[jobs]]\ninput = \"jobs.toml\"\noutput = \"out\"",
+        ))
+        .unwrap();
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let report = run_jobs(&job_file, |_| {}, &cancel);
+        assert_eq!(report.jobs[0].status, JobStatus::Cancelled);
+        assert_eq!(report.cancelled, 1);
+        assert!(!temp.path().join("out").exists());
+    }
+}
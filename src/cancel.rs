@@ -0,0 +1,39 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::error::TileError;
+
+/// Asks long-running tiling functions to stop.
+///
+/// Clones share the same flag, so a token can be handed to a job and cancelled from another
+/// thread. Tiling functions check it between tiles and between LOD layers, and return
+/// `TileError::Cancelled` once it is set.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Returns `TileError::Cancelled` if the token was cancelled.
+    pub fn check(&self) -> Result<(), TileError> {
+        if self.is_cancelled() {
+            Err(TileError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,473 @@
+use serde::Serialize;
+
+/// Divides `value` by `divisor`, rounding towards negative infinity, so that the pixels left of or
+/// above the origin fall in negative tiles. `divisor` must be positive.
+///
+/// ```
+/// use tileproc::coords::floor_div;
+///
+/// assert_eq!(floor_div(255, 256), 0);
+/// assert_eq!(floor_div(256, 256), 1);
+/// assert_eq!(floor_div(-1, 256), -1);
+/// assert_eq!(floor_div(-256, 256), -1);
+/// assert_eq!(floor_div(-257, 256), -2);
+/// ```
+pub fn floor_div(value: i64, divisor: i64) -> i64 {
+    value.div_euclid(divisor)
+}
+
+/// Returns the width and height a tile of LOD `level` covers, in pixels of layer 0. Every level
+/// up, tiles cover twice as many pixels along each axis.
+///
+/// Returns `None` if they are more pixels than an `i64` holds, or if a tile is 0 pixels wide or
+/// high, since spans are divided by.
+///
+/// ```
+/// use tileproc::coords::tile_span;
+///
+/// assert_eq!(tile_span((256, 128), 0), Some((256, 128)));
+/// assert_eq!(tile_span((256, 128), 3), Some((2048, 1024)));
+/// assert_eq!(tile_span((256, 128), 60), None);
+/// assert_eq!(tile_span((256, 0), 0), None);
+/// ```
+pub fn tile_span(tile_dimensions: (u32, u32), level: u32) -> Option<(i64, i64)> {
+    if tile_dimensions.0 == 0 || tile_dimensions.1 == 0 {
+        return None;
+    }
+    let scale = 2i64.checked_pow(level)?;
+    Some((
+        i64::from(tile_dimensions.0).checked_mul(scale)?,
+        i64::from(tile_dimensions.1).checked_mul(scale)?,
+    ))
+}
+
+/// Returns the tile of LOD `level` holding the layer 0 pixel at `position`, or `None` if its
+/// coordinates don't fit in an `i32`.
+///
+/// ```
+/// use tileproc::coords::tile_at;
+///
+/// assert_eq!(tile_at((0, 0), (256, 256), 0), Some((0, 0)));
+/// assert_eq!(tile_at((-256, 511), (256, 256), 0), Some((-1, 1)));
+/// assert_eq!(tile_at((-513, 512), (256, 256), 1), Some((-2, 1)));
+/// // past the 2^24 pixels `f32` holds exactly
+/// assert_eq!(
+///     tile_at((16_777_471, -16_777_217), (256, 256), 0),
+///     Some((65_536, -65_537))
+/// );
+/// assert_eq!(tile_at((1 << 40, 0), (1, 1), 0), None);
+/// ```
+pub fn tile_at(
+    position: (i64, i64),
+    tile_dimensions: (u32, u32),
+    level: u32,
+) -> Option<(i32, i32)> {
+    let (span_x, span_y) = tile_span(tile_dimensions, level)?;
+    Some((
+        floor_div(position.0, span_x).try_into().ok()?,
+        floor_div(position.1, span_y).try_into().ok()?,
+    ))
+}
+
+/// Returns the layer 0 pixel at the top left corner of a tile of LOD `level`, or `None` if it is
+/// further away than an `i64` holds.
+///
+/// ```
+/// use tileproc::coords::tile_origin;
+///
+/// assert_eq!(tile_origin((-1, 2), (256, 256), 0), Some((-256, 512)));
+/// assert_eq!(tile_origin((-1, 2), (256, 256), 2), Some((-1024, 2048)));
+/// ```
+pub fn tile_origin(
+    tile: (i32, i32),
+    tile_dimensions: (u32, u32),
+    level: u32,
+) -> Option<(i64, i64)> {
+    let (span_x, span_y) = tile_span(tile_dimensions, level)?;
+    Some((
+        i64::from(tile.0).checked_mul(span_x)?,
+        i64::from(tile.1).checked_mul(span_y)?,
+    ))
+}
+
+/// Returns the tile of the next LOD level up that `tile` is shrunk into.
+///
+/// ```
+/// use tileproc::coords::parent_tile;
+///
+/// assert_eq!(parent_tile((3, 4)), (1, 2));
+/// assert_eq!(parent_tile((-1, -2)), (-1, -1));
+/// assert_eq!(parent_tile((-3, 0)), (-2, 0));
+/// ```
+pub fn parent_tile(tile: (i32, i32)) -> (i32, i32) {
+    (tile.0.div_euclid(2), tile.1.div_euclid(2))
+}
+
+/// Returns the top left and bottom right tiles of LOD `level` holding the layer 0 pixels from
+/// `top_left` to `bottom_right`, both included, or `None` if their coordinates don't fit in an
+/// `i32`.
+///
+/// ```
+/// use tileproc::coords::tile_range;
+///
+/// // a 512x512 image with its top left pixel at -256,-256
+/// assert_eq!(
+///     tile_range((-256, -256), (255, 255), (256, 256), 0),
+///     Some(((-1, -1), (0, 0)))
+/// );
+/// assert_eq!(
+///     tile_range((-256, -256), (255, 255), (256, 256), 1),
+///     Some(((-1, -1), (0, 0)))
+/// );
+/// ```
+pub fn tile_range(
+    top_left: (i64, i64),
+    bottom_right: (i64, i64),
+    tile_dimensions: (u32, u32),
+    level: u32,
+) -> Option<((i32, i32), (i32, i32))> {
+    Some((
+        tile_at(top_left, tile_dimensions, level)?,
+        tile_at(bottom_right, tile_dimensions, level)?,
+    ))
+}
+
+/// Returns the tiles around `tile` at the given offsets, leaving out the ones whose coordinates
+/// don't fit in an `i32`.
+fn offset_tiles(tile: (i32, i32), offsets: &[(i32, i32)]) -> Vec<(i32, i32)> {
+    offsets
+        .iter()
+        .filter_map(|&(x, y)| Some((tile.0.checked_add(x)?, tile.1.checked_add(y)?)))
+        .collect()
+}
+
+/// Returns the 4 tiles of the next LOD level down that are shrunk into `tile`, row by row. Tiles
+/// whose coordinates don't fit in an `i32` are left out.
+///
+/// ```
+/// use tileproc::coords::{child_tiles, parent_tile};
+///
+/// assert_eq!(child_tiles((-1, 0)), [(-2, 0), (-1, 0), (-2, 1), (-1, 1)]);
+/// assert!(child_tiles((-1, 0)).iter().all(|&child| parent_tile(child) == (-1, 0)));
+/// assert!(child_tiles((i32::MAX, 0)).is_empty());
+/// ```
+pub fn child_tiles(tile: (i32, i32)) -> Vec<(i32, i32)> {
+    let (Some(x), Some(y)) = (tile.0.checked_mul(2), tile.1.checked_mul(2)) else {
+        return Vec::new();
+    };
+    offset_tiles((x, y), &[(0, 0), (1, 0), (0, 1), (1, 1)])
+}
+
+/// Returns the (up to) 8 tiles around `tile` on its LOD level, row by row. Tiles whose
+/// coordinates don't fit in an `i32` are left out.
+///
+/// ```
+/// use tileproc::coords::neighbor_tiles;
+///
+/// assert_eq!(neighbor_tiles((0, 0))[0], (-1, -1));
+/// assert_eq!(neighbor_tiles((0, 0))[3], (-1, 0));
+/// assert_eq!(neighbor_tiles((0, 0))[7], (1, 1));
+/// assert_eq!(neighbor_tiles((i32::MAX, 0)).len(), 5);
+/// ```
+pub fn neighbor_tiles(tile: (i32, i32)) -> Vec<(i32, i32)> {
+    offset_tiles(
+        tile,
+        &[
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ],
+    )
+}
+
+/// A rectangle of pixels of a source image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PixelRect {
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+/// Where a tile of a pyramid is, and the tiles related to it, as printed by `coords`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TileInfo {
+    pub level: u32,
+    pub tile: (i32, i32),
+    /// The pixels of the source image the tile covers, at the resolution of the source image.
+    pub pixel_rect: PixelRect,
+    /// The tile of the next LOD level up this tile is shrunk into.
+    pub parent: (i32, i32),
+    /// The tiles of the next LOD level down shrunk into this tile, empty on layer 0.
+    pub children: Vec<(i32, i32)>,
+    /// The tiles around this tile on its LOD level, row by row.
+    pub neighbors: Vec<(i32, i32)>,
+}
+
+impl TileInfo {
+    /// Describes a tile of LOD `level` of tiles sliced from a source image with `offset`, the
+    /// pixel of the source image at tile pixel 0,0.
+    ///
+    /// Returns `None` if the pixels the tile covers are further away than an `i64` holds.
+    ///
+    /// ```
+    /// use tileproc::coords::{PixelRect, TileInfo};
+    ///
+    /// let info = TileInfo::new((-1, 0), 1, (256, 256), (500, 300)).unwrap();
+    /// assert_eq!(
+    ///     info.pixel_rect,
+    ///     PixelRect { x: -12, y: 300, width: 512, height: 512 }
+    /// );
+    /// assert_eq!(info.parent, (-1, 0));
+    /// assert_eq!(info.children, vec![(-2, 0), (-1, 0), (-2, 1), (-1, 1)]);
+    /// ```
+    pub fn new(
+        tile: (i32, i32),
+        level: u32,
+        tile_dimensions: (u32, u32),
+        offset: (i64, i64),
+    ) -> Option<TileInfo> {
+        let (origin_x, origin_y) = tile_origin(tile, tile_dimensions, level)?;
+        let (width, height) = tile_span(tile_dimensions, level)?;
+        Some(TileInfo {
+            level,
+            tile,
+            pixel_rect: PixelRect {
+                x: origin_x.checked_add(offset.0)?,
+                y: origin_y.checked_add(offset.1)?,
+                width,
+                height,
+            },
+            parent: parent_tile(tile),
+            children: if level == 0 {
+                Vec::new()
+            } else {
+                child_tiles(tile)
+            },
+            neighbors: neighbor_tiles(tile),
+        })
+    }
+}
+
+/// Where a pixel of a source image is in a pyramid, as printed by `coords`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PixelInfo {
+    pub pixel: (i64, i64),
+    /// The pixel of the tile's image the source pixel is shrunk into.
+    pub pixel_in_tile: (i64, i64),
+    #[serde(flatten)]
+    pub tile_info: TileInfo,
+}
+
+impl PixelInfo {
+    /// Finds the tile of LOD `level` holding a pixel of a source image that was sliced with
+    /// `offset`, the pixel of the source image at tile pixel 0,0.
+    ///
+    /// Returns `None` if the coordinates of the tile don't fit in an `i32`.
+    ///
+    /// ```
+    /// use tileproc::coords::PixelInfo;
+    ///
+    /// let info = PixelInfo::new((0, 299), 1, (256, 256), (500, 300)).unwrap();
+    /// assert_eq!(info.tile_info.tile, (-1, -1));
+    /// assert_eq!(info.pixel_in_tile, (6, 255));
+    ///
+    /// assert!(PixelInfo::new((i64::MAX, 0), 0, (1, 1), (0, 0)).is_none());
+    /// ```
+    pub fn new(
+        pixel: (i64, i64),
+        level: u32,
+        tile_dimensions: (u32, u32),
+        offset: (i64, i64),
+    ) -> Option<PixelInfo> {
+        let position = (
+            pixel.0.checked_sub(offset.0)?,
+            pixel.1.checked_sub(offset.1)?,
+        );
+        let tile = tile_at(position, tile_dimensions, level)?;
+        let (origin_x, origin_y) = tile_origin(tile, tile_dimensions, level)?;
+        Some(PixelInfo {
+            pixel,
+            pixel_in_tile: (
+                (position.0 - origin_x) >> level,
+                (position.1 - origin_y) >> level,
+            ),
+            tile_info: TileInfo::new(tile, level, tile_dimensions, offset)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floor_div_rounds_towards_negative_infinity() {
+        for (value, expected) in [
+            (-513, -3),
+            (-512, -2),
+            (-511, -2),
+            (-257, -2),
+            (-256, -1),
+            (-1, -1),
+            (0, 0),
+            (255, 0),
+            (256, 1),
+            (511, 1),
+            (512, 2),
+        ] {
+            assert_eq!(floor_div(value, 256), expected, "{}", value);
+        }
+        assert_eq!(floor_div(i64::MIN, 1), i64::MIN);
+        assert_eq!(floor_div(i64::MAX, 3), i64::MAX / 3);
+        assert_eq!(floor_div(-7, 3), -3);
+    }
+
+    #[test]
+    fn negative_positions_fall_in_negative_tiles() {
+        assert_eq!(tile_at((-1, -1), (256, 256), 0), Some((-1, -1)));
+        assert_eq!(tile_at((-256, -257), (256, 256), 0), Some((-1, -2)));
+        assert_eq!(tile_at((-1, -1), (256, 256), 3), Some((-1, -1)));
+        assert_eq!(tile_at((-2048, -2049), (256, 256), 3), Some((-1, -2)));
+        assert_eq!(tile_origin((-1, -2), (256, 256), 0), Some((-256, -512)));
+    }
+
+    #[test]
+    fn non_square_tiles_divide_each_axis_by_its_own_size() {
+        let dimensions = (512, 128);
+        assert_eq!(tile_span(dimensions, 2), Some((2048, 512)));
+        assert_eq!(tile_at((511, 511), dimensions, 0), Some((0, 3)));
+        assert_eq!(tile_at((512, 512), dimensions, 0), Some((1, 4)));
+        assert_eq!(tile_at((-1, -257), dimensions, 1), Some((-1, -2)));
+        assert_eq!(tile_origin((1, -1), dimensions, 1), Some((1024, -256)));
+
+        let info = TileInfo::new((1, -1), 1, dimensions, (10, 20)).unwrap();
+        assert_eq!(
+            info.pixel_rect,
+            PixelRect {
+                x: 1034,
+                y: -236,
+                width: 1024,
+                height: 256
+            }
+        );
+    }
+
+    #[test]
+    fn high_levels_cover_huge_spans() {
+        assert_eq!(tile_span((256, 256), 30), Some((1 << 38, 1 << 38)));
+        assert_eq!(tile_span((1, 1), 62), Some((1 << 62, 1 << 62)));
+        assert_eq!(tile_span((2, 1), 62), None);
+        assert_eq!(tile_span((1, 1), 63), None);
+        assert_eq!(tile_span((1, 1), u32::MAX), None);
+
+        // every pixel of an i64 is in one of 4 tiles of 2^62 pixels
+        assert_eq!(tile_at((i64::MIN, i64::MAX), (1, 1), 62), Some((-2, 1)));
+        assert_eq!(tile_origin((-2, 1), (1, 1), 62), Some((i64::MIN, 1 << 62)));
+        assert_eq!(tile_origin((2, 0), (1, 1), 62), None);
+        assert!(TileInfo::new((1, 0), 62, (1, 1), (1 << 62, 0)).is_none());
+        assert!(PixelInfo::new((0, 0), 63, (1, 1), (0, 0)).is_none());
+    }
+
+    #[test]
+    fn empty_tiles_have_no_span() {
+        assert_eq!(tile_span((0, 256), 0), None);
+        assert_eq!(tile_span((256, 0), 3), None);
+        assert_eq!(tile_at((10, 10), (0, 0), 0), None);
+        assert_eq!(tile_origin((1, 1), (256, 0), 0), None);
+        assert_eq!(tile_range((0, 0), (10, 10), (0, 256), 0), None);
+        assert!(PixelInfo::new((0, 0), 0, (0, 256), (0, 0)).is_none());
+        assert!(TileInfo::new((0, 0), 0, (256, 0), (0, 0)).is_none());
+    }
+
+    #[test]
+    fn out_of_range_tiles_are_none() {
+        assert_eq!(tile_at((1 << 31, 0), (1, 1), 0), None);
+        assert_eq!(tile_at((0, -(1 << 31) - 1), (1, 1), 0), None);
+        assert_eq!(tile_at((-(1 << 31), 0), (1, 1), 0), Some((i32::MIN, 0)));
+        assert_eq!(tile_range((0, 0), (i64::MAX, 0), (256, 256), 0), None);
+        assert!(PixelInfo::new((i64::MIN, 0), 0, (256, 256), (1, 0)).is_none());
+        assert!(PixelInfo::new((i64::MAX, 0), 0, (1, 1), (0, 0)).is_none());
+    }
+
+    #[test]
+    fn related_tiles_leave_out_the_ones_out_of_range() {
+        assert_eq!(child_tiles((1 << 30, 0)), []);
+        assert_eq!(child_tiles(((1 << 30) - 1, 0)).len(), 4);
+        assert_eq!(child_tiles((-(1 << 30), -(1 << 30))).len(), 4);
+        assert!(child_tiles((i32::MIN, 0)).is_empty());
+
+        assert_eq!(neighbor_tiles((0, 0)).len(), 8);
+        assert_eq!(
+            neighbor_tiles((i32::MAX, i32::MIN)),
+            [
+                (i32::MAX - 1, i32::MIN),
+                (i32::MAX - 1, i32::MIN + 1),
+                (i32::MAX, i32::MIN + 1)
+            ]
+        );
+        assert_eq!(
+            parent_tile((i32::MIN, i32::MAX)),
+            (i32::MIN / 2, i32::MAX / 2)
+        );
+    }
+
+    #[test]
+    fn tile_origin_and_tile_at_roundtrip() {
+        for dimensions in [(1, 1), (256, 256), (512, 128), (3, 7)] {
+            for level in [0, 1, 5, 20] {
+                for tile in [(0, 0), (-1, -1), (5, -3), (-1000, 77), (i32::MAX, i32::MIN)] {
+                    let (origin_x, origin_y) = tile_origin(tile, dimensions, level).unwrap();
+                    let (span_x, span_y) = tile_span(dimensions, level).unwrap();
+                    assert_eq!(tile_at((origin_x, origin_y), dimensions, level), Some(tile));
+                    assert_eq!(
+                        tile_at(
+                            (origin_x + span_x - 1, origin_y + span_y - 1),
+                            dimensions,
+                            level
+                        ),
+                        Some(tile)
+                    );
+                    if tile.0 > i32::MIN {
+                        assert_eq!(
+                            tile_at((origin_x - 1, origin_y), dimensions, level),
+                            Some((tile.0 - 1, tile.1))
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pixels_and_tiles_roundtrip() {
+        let offset = (-37, 1000);
+        for level in [0, 1, 4] {
+            for pixel in [(0, 0), (-37, 1000), (-38, 999), (12345, -6789)] {
+                let info = PixelInfo::new(pixel, level, (256, 128), offset).unwrap();
+                let rect = info.tile_info.pixel_rect;
+                assert!((rect.x..rect.x + rect.width).contains(&pixel.0));
+                assert!((rect.y..rect.y + rect.height).contains(&pixel.1));
+                assert_eq!(
+                    info.pixel_in_tile,
+                    ((pixel.0 - rect.x) >> level, (pixel.1 - rect.y) >> level)
+                );
+                assert!(info.pixel_in_tile.0 < 256 && info.pixel_in_tile.1 < 128);
+            }
+        }
+    }
+
+    #[test]
+    fn children_and_parents_match() {
+        for tile in [(0, 0), (-1, 0), (3, -5), (-(1 << 30), (1 << 30) - 1)] {
+            let children = child_tiles(tile);
+            assert_eq!(children.len(), 4);
+            assert!(children.iter().all(|&child| parent_tile(child) == tile));
+        }
+    }
+}
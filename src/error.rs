@@ -0,0 +1,136 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use image::ImageError;
+
+/// The ways a tiling function can fail without panicking.
+#[derive(Debug)]
+pub enum TileError {
+    /// The job's `CancelToken` was cancelled. Tiles written before that are complete, and a
+    /// journal records them so that the job can be resumed.
+    Cancelled,
+    /// A tile's dimensions differ from the other tiles it is combined with.
+    DimensionMismatch {
+        path: PathBuf,
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    /// An image or tile could not be decoded or encoded.
+    Image { path: PathBuf, source: ImageError },
+    /// The arguments of a job contradict each other, or name files that don't exist.
+    InvalidArgs(String),
+    /// The world file or GeoTIFF tags of an image could not be read, or describe a placement
+    /// tileproc does not support.
+    InvalidGeoreference(String),
+    /// A mosaic manifest could not be read, or lists images that don't exist.
+    InvalidManifest { path: PathBuf, message: String },
+    /// A file or directory could not be read or written.
+    Io { path: PathBuf, source: io::Error },
+    /// Tiles are too large to fit on a single atlas page.
+    TileLargerThanPage {
+        tile_dimensions: (u32, u32),
+        page_dimensions: (u32, u32),
+    },
+    /// Tiles would have coordinates that don't fit in an `i32`, such as the tiles of an image
+    /// placed far away from the origin.
+    TileOutOfRange,
+    /// The name template can't name a tile, such as a quadkey template a tile with a negative
+    /// coordinate, or a tile of zoom level 0.
+    UnnamedTile {
+        x: i32,
+        y: i32,
+        zoom: u32,
+        name_template: String,
+    },
+}
+
+impl TileError {
+    /// Returns a function that wraps an image error with the path of the image it occurred on,
+    /// for `map_err`.
+    pub(crate) fn image(path: &Path) -> impl FnOnce(ImageError) -> TileError + '_ {
+        move |source| TileError::Image {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Returns a function that wraps an I/O error with the path of the file or directory it
+    /// occurred on, for `map_err`.
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> TileError + '_ {
+        move |source| TileError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileError::Cancelled => write!(f, "cancelled"),
+            TileError::DimensionMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} is {}x{}, expected {}x{}",
+                path.display(),
+                found.0,
+                found.1,
+                expected.0,
+                expected.1
+            ),
+            // some image errors end with a period, which the CLI adds to every error
+            TileError::Image { path, source } => write!(
+                f,
+                "{}: {}",
+                path.display(),
+                source.to_string().trim_end_matches('.')
+            ),
+            TileError::InvalidArgs(message) => write!(f, "{}", message),
+            TileError::InvalidGeoreference(message) => {
+                write!(f, "invalid georeference: {}", message)
+            }
+            TileError::InvalidManifest { path, message } => {
+                write!(f, "invalid manifest {}: {}", path.display(), message)
+            }
+            TileError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            TileError::TileLargerThanPage {
+                tile_dimensions,
+                page_dimensions,
+            } => write!(
+                f,
+                "tiles of {}x{} don't fit on atlas pages of {}x{}",
+                tile_dimensions.0, tile_dimensions.1, page_dimensions.0, page_dimensions.1
+            ),
+            TileError::TileOutOfRange => write!(
+                f,
+                "the tiles are out of range, their coordinates don't fit in 32 bits"
+            ),
+            TileError::UnnamedTile {
+                x,
+                y,
+                zoom,
+                name_template,
+            } => write!(
+                f,
+                "the name template {} can't name tile {},{} of zoom level {}, quadkeys need \
+                 coordinates of 0 and up within zoom levels 1 to 31",
+                name_template, x, y, zoom
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TileError::Image { source, .. } => Some(source),
+            TileError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tiff::{decoder::Decoder, tags::Tag};
+
+use crate::error::TileError;
+
+/// The name of the file describing where the tiles of a georeferenced image are in the world,
+/// written to the root of an output directory.
+pub const MANIFEST_FILE_NAME: &str = "tileproc-manifest.json";
+
+/// Maps the pixels of a north-up image to world coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GeoTransform {
+    /// The world coordinates of the top left corner of the image's top left pixel.
+    pub origin: (f64, f64),
+    /// The world size of a pixel. The y size is negative, since image rows go down while world
+    /// y coordinates go up.
+    pub pixel_size: (f64, f64),
+}
+
+fn invalid_data(path: &Path, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), message),
+    )
+}
+
+impl GeoTransform {
+    /// Builds a transform from the 6 coefficients of an affine pixel to world transform, in
+    /// world file order. Rotated and south-up images are not supported.
+    fn from_coefficients(path: &Path, coefficients: [f64; 6]) -> io::Result<GeoTransform> {
+        let [a, d, b, e, c, f] = coefficients;
+
+        if b != 0.0 || d != 0.0 {
+            return Err(invalid_data(path, "rotated images are not supported"));
+        }
+        if a <= 0.0 || e >= 0.0 {
+            return Err(invalid_data(
+                path,
+                "only images with positive x and negative y pixel sizes are supported",
+            ));
+        }
+
+        Ok(GeoTransform {
+            origin: (c, f),
+            pixel_size: (a, e),
+        })
+    }
+
+    /// Reads a world file, such as a `.tfw`, `.pgw` or `.wld` file.
+    ///
+    /// A world file holds the pixel size, rotation and the world coordinates of the center of
+    /// the top left pixel, one number per line.
+    pub fn from_world_file(path: &Path) -> io::Result<GeoTransform> {
+        let contents = fs::read_to_string(path)?;
+        let numbers: Vec<f64> = contents
+            .split_whitespace()
+            .map(|word| word.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| {
+                invalid_data(path, "world file contains something that is not a number")
+            })?;
+
+        let [a, d, b, e, c, f]: [f64; 6] = numbers
+            .try_into()
+            .map_err(|_| invalid_data(path, "world file does not contain 6 numbers"))?;
+
+        // move the origin from the center of the top left pixel to its corner
+        GeoTransform::from_coefficients(path, [a, d, b, e, c - a / 2.0, f - e / 2.0])
+    }
+
+    /// Reads the ModelPixelScale and ModelTiepoint, or the ModelTransformation tags of a
+    /// GeoTIFF.
+    ///
+    /// Returns `None` if the file is not a TIFF or has no georeferencing tags.
+    pub fn from_geotiff(path: &Path) -> io::Result<Option<GeoTransform>> {
+        let Ok(mut decoder) = Decoder::new(BufReader::new(File::open(path)?)) else {
+            return Ok(None);
+        };
+        let mut read_tag = |tag| -> io::Result<Option<Vec<f64>>> {
+            decoder
+                .find_tag(tag)
+                .and_then(|value| value.map(|value| value.into_f64_vec()).transpose())
+                .map_err(|err| invalid_data(path, &err.to_string()))
+        };
+
+        if let Some(matrix) = read_tag(Tag::ModelTransformationTag)? {
+            if matrix.len() < 8 {
+                return Err(invalid_data(path, "ModelTransformation tag is too short"));
+            }
+            let coefficients = [
+                matrix[0], matrix[4], matrix[1], matrix[5], matrix[3], matrix[7],
+            ];
+            return GeoTransform::from_coefficients(path, coefficients).map(Some);
+        }
+
+        let (Some(scale), Some(tiepoint)) = (
+            read_tag(Tag::ModelPixelScaleTag)?,
+            read_tag(Tag::ModelTiepointTag)?,
+        ) else {
+            return Ok(None);
+        };
+        if scale.len() < 2 || tiepoint.len() < 6 {
+            return Err(invalid_data(path, "georeferencing tags are too short"));
+        }
+
+        // the tiepoint ties the corner of raster pixel I,J to world coordinates X,Y
+        let (i, j, x, y) = (tiepoint[0], tiepoint[1], tiepoint[3], tiepoint[4]);
+        let coefficients = [
+            scale[0],
+            0.0,
+            0.0,
+            -scale[1],
+            x - i * scale[0],
+            y + j * scale[1],
+        ];
+        GeoTransform::from_coefficients(path, coefficients).map(Some)
+    }
+
+    /// Returns the EPSG code of a GeoTIFF's projected coordinate system, or else of its
+    /// geographic coordinate system, from its GeoKeyDirectory tag.
+    ///
+    /// Returns `None` if the file is not a TIFF or has no such keys.
+    pub fn geotiff_epsg_code(path: &Path) -> io::Result<Option<u16>> {
+        let Ok(mut decoder) = Decoder::new(BufReader::new(File::open(path)?)) else {
+            return Ok(None);
+        };
+        let Some(directory) = decoder
+            .find_tag(Tag::GeoKeyDirectoryTag)
+            .and_then(|value| value.map(|value| value.into_u16_vec()).transpose())
+            .map_err(|err| invalid_data(path, &err.to_string()))?
+        else {
+            return Ok(None);
+        };
+
+        // a header of 4 shorts, followed by keys of 4 shorts: id, tag location, count, value
+        let mut projected = None;
+        let mut geographic = None;
+        for key in directory.chunks_exact(4).skip(1) {
+            // values stored outside of the directory are not codes
+            if key[1] != 0 {
+                continue;
+            }
+            match key[0] {
+                3072 => projected = Some(key[3]),
+                2048 => geographic = Some(key[3]),
+                _ => {}
+            }
+        }
+        Ok(projected.or(geographic))
+    }
+
+    /// Returns the world file next to an image, such as `photo.tfw` or `photo.tifw` for
+    /// `photo.tif`, or `photo.wld`.
+    pub fn find_world_file(image_path: &Path) -> Option<PathBuf> {
+        let extension = image_path.extension()?.to_str()?.to_lowercase();
+
+        let mut candidates = vec!["wld".to_string(), format!("{}w", extension)];
+        if let (Some(first), Some(last)) = (extension.chars().next(), extension.chars().last()) {
+            candidates.push(format!("{}{}w", first, last));
+        }
+
+        candidates
+            .into_iter()
+            .flat_map(|candidate| [candidate.to_uppercase(), candidate])
+            .map(|candidate| image_path.with_extension(candidate))
+            .find(|path| path.is_file())
+    }
+
+    /// Reads the georeference of an image from its world file, or else from its GeoTIFF tags.
+    ///
+    /// Returns `None` if the image is not georeferenced.
+    pub fn read(image_path: &Path) -> io::Result<Option<GeoTransform>> {
+        match GeoTransform::find_world_file(image_path) {
+            Some(world_file) => GeoTransform::from_world_file(&world_file).map(Some),
+            None => GeoTransform::from_geotiff(image_path),
+        }
+    }
+}
+
+/// The world coordinate space tiles of georeferenced images are placed in. Images tiled with the
+/// same grid line up with each other.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WorldGrid {
+    /// The world size of a tile pixel.
+    pub resolution: f64,
+    /// The world coordinates of the top left corner of tile pixel 0,0.
+    pub origin: (f64, f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WorldBounds {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+}
+
+/// Where a georeferenced image is placed in a world grid.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Placement {
+    /// The size the image is scaled to, so that its pixels have the grid's resolution.
+    pub dimensions: (u32, u32),
+    /// The x pixel of the scaled image that is tile pixel 0,0.
+    pub x_offset: i32,
+    /// The y pixel of the scaled image that is tile pixel 0,0.
+    pub y_offset: i32,
+}
+
+/// Rounds a number of grid pixels to the nearest integer, or returns `None` if it is not a number
+/// or doesn't fit in a `T`.
+fn round_pixels<T: TryFrom<i64>>(pixels: f64) -> Option<T> {
+    let pixels = pixels.round();
+    // the range fails for NaN, and keeps the conversion to i64 from saturating
+    if !(-(2f64.powi(63))..2f64.powi(63)).contains(&pixels) {
+        return None;
+    }
+    T::try_from(pixels as i64).ok()
+}
+
+impl Placement {
+    /// Places an image in a grid. The image is moved by less than half a tile pixel, so that
+    /// its pixels line up with tile pixels.
+    ///
+    /// Fails if the scaled image is too large, or too far from the grid origin, for its pixels to
+    /// be tile pixels.
+    pub fn new(
+        transform: &GeoTransform,
+        image_dimensions: (u32, u32),
+        grid: &WorldGrid,
+    ) -> Result<Placement, TileError> {
+        let width = image_dimensions.0 as f64 * transform.pixel_size.0 / grid.resolution;
+        let height = image_dimensions.1 as f64 * -transform.pixel_size.1 / grid.resolution;
+        let x = (transform.origin.0 - grid.origin.0) / grid.resolution;
+        let y = (grid.origin.1 - transform.origin.1) / grid.resolution;
+
+        let (Some(width), Some(height)) = (round_pixels::<u32>(width), round_pixels::<u32>(height))
+        else {
+            return Err(TileError::InvalidGeoreference(format!(
+                "scaled to the grid resolution, the image would be {}x{} pixels",
+                width, height
+            )));
+        };
+        let (Some(x_offset), Some(y_offset)) = (round_pixels::<i32>(-x), round_pixels::<i32>(-y))
+        else {
+            return Err(TileError::InvalidGeoreference(format!(
+                "the image is {},{} grid pixels from the grid origin, which is too far",
+                x, y
+            )));
+        };
+
+        Ok(Placement {
+            dimensions: (width.max(1), height.max(1)),
+            x_offset,
+            y_offset,
+        })
+    }
+
+    /// The world bounds of the placed image.
+    pub fn world_bounds(&self, grid: &WorldGrid) -> WorldBounds {
+        let min_x = grid.origin.0 - self.x_offset as f64 * grid.resolution;
+        let max_y = grid.origin.1 + self.y_offset as f64 * grid.resolution;
+
+        WorldBounds {
+            min_x,
+            min_y: max_y - self.dimensions.1 as f64 * grid.resolution,
+            max_x: min_x + self.dimensions.0 as f64 * grid.resolution,
+            max_y,
+        }
+    }
+}
+
+/// Describes where the tiles of a georeferenced image are in the world, so that tiles of
+/// separately processed images can be lined up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoManifest {
+    pub source: PathBuf,
+    pub source_transform: GeoTransform,
+    pub grid: WorldGrid,
+    /// The width and height of the tiles.
+    pub tile_dimensions: (u32, u32),
+    /// The border tiles have around their `tile_dimensions`, copied from their neighbors.
+    pub gutter: u32,
+    pub placement: Placement,
+    /// The world bounds of the tiled image, after it was lined up with tile pixels.
+    pub world_bounds: WorldBounds,
+}
+
+impl GeoManifest {
+    pub fn write(&self, dir: &Path) -> io::Result<()> {
+        fs::write(
+            dir.join(MANIFEST_FILE_NAME),
+            serde_json::to_string_pretty(self).unwrap(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(origin: (f64, f64), pixel_size: f64) -> GeoTransform {
+        GeoTransform {
+            origin,
+            pixel_size: (pixel_size, -pixel_size),
+        }
+    }
+
+    fn grid(resolution: f64) -> WorldGrid {
+        WorldGrid {
+            resolution,
+            origin: (0.0, 0.0),
+        }
+    }
+
+    #[test]
+    fn places_images_on_the_grid() {
+        let placement =
+            Placement::new(&transform((100.2, -49.7), 0.5), (200, 100), &grid(1.0)).unwrap();
+        assert_eq!(
+            placement,
+            Placement {
+                dimensions: (100, 50),
+                x_offset: -100,
+                y_offset: -50,
+            }
+        );
+
+        let bounds = placement.world_bounds(&grid(1.0));
+        assert_eq!((bounds.min_x, bounds.max_y), (100.0, -50.0));
+        assert_eq!((bounds.max_x, bounds.min_y), (200.0, -100.0));
+    }
+
+    #[test]
+    fn tiny_images_keep_a_pixel() {
+        let placement = Placement::new(&transform((0.0, 0.0), 1.0), (3, 3), &grid(100.0)).unwrap();
+        assert_eq!(placement.dimensions, (1, 1));
+    }
+
+    #[test]
+    fn rejects_images_far_from_the_origin() {
+        for origin in [(3e9, 0.0), (-3e9, 0.0), (0.0, 3e9), (0.0, -1e300)] {
+            let placement = Placement::new(&transform(origin, 1.0), (10, 10), &grid(1.0));
+            assert!(
+                matches!(placement, Err(TileError::InvalidGeoreference(_))),
+                "{:?}",
+                origin
+            );
+        }
+
+        // the last pixels an i32 offset holds
+        let placement = Placement::new(&transform((-2147483647.0, 0.0), 1.0), (1, 1), &grid(1.0));
+        assert_eq!(placement.unwrap().x_offset, i32::MAX);
+    }
+
+    #[test]
+    fn rejects_images_too_large_for_the_grid() {
+        for resolution in [1e-9, 0.0, -1.0, f64::NAN] {
+            let placement =
+                Placement::new(&transform((0.0, 0.0), 1.0), (10, 10), &grid(resolution));
+            assert!(
+                matches!(placement, Err(TileError::InvalidGeoreference(_))),
+                "{}",
+                resolution
+            );
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, GenericImageView};
+use rayon::prelude::*;
+
+use crate::error::TileError;
+use crate::pixel::{buffer, with_pixel_type, TileBuffer, TilePixel};
+use crate::texture::open_tile;
+use crate::tiler::{dir_paths, JobContext};
+
+/// The pixels of a tile within `gutter` pixels of its edges, which its neighbors copy into their
+/// gutters.
+struct Borders<P: TilePixel> {
+    left: TileBuffer<P>,
+    right: TileBuffer<P>,
+    top: TileBuffer<P>,
+    bottom: TileBuffer<P>,
+}
+
+impl<P: TilePixel> Borders<P> {
+    fn new(tile: &DynamicImage, gutter: u32) -> Borders<P> {
+        let (width, height) = tile.dimensions();
+        let strip =
+            |x, y, width, height| buffer::<P>(&tile.crop_imm(x, y, width, height)).into_owned();
+
+        Borders {
+            left: strip(0, 0, gutter, height),
+            right: strip(width - gutter, 0, gutter, height),
+            top: strip(0, 0, width, gutter),
+            bottom: strip(0, height - gutter, width, gutter),
+        }
+    }
+
+    /// Returns a pixel of the tile that is within the gutter width of its edges.
+    fn get_pixel(&self, x: u32, y: u32) -> P {
+        let (width, gutter) = (self.top.width(), self.left.width());
+        let height = self.left.height();
+
+        if x < gutter {
+            *self.left.get_pixel(x, y)
+        } else if x >= width - gutter {
+            *self.right.get_pixel(x - (width - gutter), y)
+        } else if y < gutter {
+            *self.top.get_pixel(x, y)
+        } else {
+            *self.bottom.get_pixel(x, y - (height - gutter))
+        }
+    }
+}
+
+/// Decodes a tile, without the gutter it may already have.
+fn open_inner_tile(
+    path: &Path,
+    tile_dimensions: (u32, u32),
+    gutter: u32,
+) -> Result<DynamicImage, TileError> {
+    let tile = open_tile(path).map_err(TileError::image(path))?;
+    let (width, height) = tile_dimensions;
+
+    if tile.dimensions() == tile_dimensions {
+        Ok(tile)
+    } else if tile.dimensions() == (width + 2 * gutter, height + 2 * gutter) {
+        Ok(tile.crop_imm(gutter, gutter, width, height))
+    } else {
+        Err(TileError::DimensionMismatch {
+            path: path.to_path_buf(),
+            expected: tile_dimensions,
+            found: tile.dimensions(),
+        })
+    }
+}
+
+/// Adds a border of `gutter` pixels to every tile directly inside `dir`, so that a tile's edge
+/// can be filtered like the inside of it, such as when it is sampled bilinearly as a texture.
+///
+/// The border is copied from the edges of the neighboring tiles. Where there is no neighboring
+/// tile, the tile's own edge is extruded. Tiles become `tile_dimensions` plus twice the gutter in
+/// each direction, and keep their pixel type.
+///
+/// Tiles that already have a gutter are left alone, so that an interrupted job can add the rest.
+pub fn add_gutters(
+    dir: &Path,
+    tile_dimensions: (u32, u32),
+    gutter: u32,
+    level: u32,
+    context: JobContext,
+) -> Result<(), TileError> {
+    if gutter == 0 {
+        return Ok(());
+    }
+    context
+        .writer
+        .remove_temp_files(dir)
+        .map_err(TileError::io(dir))?;
+
+    let tiles: HashMap<(i32, i32), PathBuf> = dir_paths(dir)?
+        .into_iter()
+        .filter_map(|path| Some((context.writer.tile_coords(&path)?, path)))
+        .collect();
+    let Some(first_tile) = tiles.values().next() else {
+        return Ok(());
+    };
+
+    // every tile of a layer has the same pixel type
+    let color_type = open_tile(first_tile)
+        .map_err(TileError::image(first_tile))?
+        .color();
+    with_pixel_type!(color_type, P => {
+        add_gutters_of_type::<P>(&tiles, tile_dimensions, gutter, level, context)
+    })
+}
+
+fn add_gutters_of_type<P: TilePixel>(
+    tiles: &HashMap<(i32, i32), PathBuf>,
+    tile_dimensions: (u32, u32),
+    gutter: u32,
+    level: u32,
+    context: JobContext,
+) -> Result<(), TileError> {
+    let (width, height) = tile_dimensions;
+    let guttered_dimensions = (width + 2 * gutter, height + 2 * gutter);
+
+    // collect the edges of every tile before any tile is rewritten
+    context.progress.message("reading tile edges...");
+    let borders: HashMap<(i32, i32), Borders<P>> = tiles
+        .par_iter()
+        .map(|(&coords, path)| {
+            let tile = open_inner_tile(path, tile_dimensions, gutter)?;
+            Ok((coords, Borders::new(&tile, gutter)))
+        })
+        .collect::<Result<_, _>>()?;
+
+    context.progress.message("adding gutters...");
+    context.progress.start_level(level, tiles.len() as u64);
+
+    tiles.par_iter().try_for_each(|(&(tile_x, tile_y), path)| {
+        context.cancel.check()?;
+
+        let tile = open_tile(path).map_err(TileError::image(path))?;
+        if tile.dimensions() == guttered_dimensions {
+            context.progress.tile_done(0);
+            return Ok(());
+        }
+        let tile = buffer::<P>(&tile);
+
+        let mut guttered = TileBuffer::<P>::new(guttered_dimensions.0, guttered_dimensions.1);
+        for (x, y, pixel) in guttered.enumerate_pixels_mut() {
+            // position of the pixel relative to the tile's top left pixel
+            let inner_x = x as i32 - gutter as i32;
+            let inner_y = y as i32 - gutter as i32;
+
+            let neighbor_x = inner_x.div_euclid(width as i32);
+            let neighbor_y = inner_y.div_euclid(height as i32);
+
+            *pixel = if neighbor_x == 0 && neighbor_y == 0 {
+                *tile.get_pixel(inner_x as u32, inner_y as u32)
+            } else if let Some(neighbor) = borders.get(&(tile_x + neighbor_x, tile_y + neighbor_y))
+            {
+                neighbor.get_pixel(
+                    inner_x.rem_euclid(width as i32) as u32,
+                    inner_y.rem_euclid(height as i32) as u32,
+                )
+            } else {
+                // extrude the tile's own edge
+                *tile.get_pixel(
+                    inner_x.clamp(0, width as i32 - 1) as u32,
+                    inner_y.clamp(0, height as i32 - 1) as u32,
+                )
+            };
+        }
+
+        let bytes_written = context
+            .writer
+            .write(&P::into_dynamic(guttered), path)
+            .map_err(TileError::image(path))?;
+        context.progress.tile_done(bytes_written);
+        Ok(())
+    })?;
+
+    context.progress.finish_level();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::args::TileFormat;
+    use crate::cancel::CancelToken;
+    use crate::progress::Progress;
+    use crate::writer::TileWriter;
+
+    /// The pixel at a position of the image a 2x2 grid of 4x4 tiles is cut from.
+    fn pixel(x: i32, y: i32) -> Rgba<u8> {
+        Rgba([x as u8 * 10, y as u8 * 10, 100, 255])
+    }
+
+    #[test]
+    fn copies_edges_and_corners_from_neighbors() {
+        let temp = tempfile::tempdir().unwrap();
+        let writer = TileWriter::new(TileFormat::Png, false);
+        for tile_x in 0..2 {
+            for tile_y in 0..2 {
+                RgbaImage::from_fn(4, 4, |x, y| {
+                    pixel(tile_x * 4 + x as i32, tile_y * 4 + y as i32)
+                })
+                .save(temp.path().join(writer.tile_file_name(tile_x, tile_y)))
+                .unwrap();
+            }
+        }
+
+        let context = JobContext {
+            writer: &writer,
+            journal: None,
+            progress: &Progress::none(),
+            cancel: &CancelToken::new(),
+        };
+        add_gutters(temp.path(), (4, 4), 1, 0, context).unwrap();
+        // tiles that already have a gutter are left alone
+        add_gutters(temp.path(), (4, 4), 1, 0, context).unwrap();
+
+        for tile_x in 0..2 {
+            for tile_y in 0..2 {
+                let path = temp.path().join(writer.tile_file_name(tile_x, tile_y));
+                let tile = image::open(&path).unwrap().into_rgba8();
+                assert_eq!(tile.dimensions(), (6, 6));
+
+                for (x, y, found) in tile.enumerate_pixels() {
+                    let (x, y) = (tile_x * 4 + x as i32 - 1, tile_y * 4 + y as i32 - 1);
+                    // outside the grid, the tile's own edge is extruded
+                    let expected = if (0..8).contains(&x) && (0..8).contains(&y) {
+                        pixel(x, y)
+                    } else {
+                        pixel(
+                            x.clamp(tile_x * 4, tile_x * 4 + 3),
+                            y.clamp(tile_y * 4, tile_y * 4 + 3),
+                        )
+                    };
+                    assert_eq!(*found, expected, "{:?} at {},{}", path, x, y);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use image::{
+    codecs::hdr::HdrDecoder, io::Reader, DynamicImage, ImageFormat, ImageResult, Rgb32FImage,
+    RgbaImage,
+};
+use rayon::prelude::*;
+
+use crate::args::ToneMapping;
+
+/// Decodes an image. Radiance HDR images are decoded into floating point colors, rather than
+/// the 8-bit colors `image::open` clips them to.
+pub fn open_image(path: &Path) -> ImageResult<DynamicImage> {
+    let reader = Reader::open(path)?.with_guessed_format()?;
+    if reader.format() != Some(ImageFormat::Hdr) {
+        return reader.decode();
+    }
+
+    let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?;
+
+    let image = Rgb32FImage::from_raw(
+        metadata.width,
+        metadata.height,
+        pixels.iter().flat_map(|pixel| pixel.0).collect(),
+    )
+    .unwrap();
+    Ok(DynamicImage::ImageRgb32F(image))
+}
+
+/// Returns true for images with floating point colors, which can be brighter than white.
+pub fn is_float_image(image: &DynamicImage) -> bool {
+    matches!(
+        image,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    )
+}
+
+/// Maps a linear color channel to the 0 to 1 range.
+fn tone_map_channel(tone_mapping: ToneMapping, color: f32) -> f32 {
+    let color = color.max(0.0);
+    match tone_mapping {
+        ToneMapping::Linear => color,
+        ToneMapping::Reinhard => color / (1.0 + color),
+        // Krzysztof Narkowicz's fit of the ACES reference rendering transform
+        ToneMapping::Aces => {
+            (color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14)
+        }
+    }
+    .clamp(0.0, 1.0)
+}
+
+/// Encodes a linear color channel from 0 to 1 with the sRGB transfer function.
+fn srgb_encode(color: f32) -> u8 {
+    let encoded = if color <= 0.003_130_8 {
+        12.92 * color
+    } else {
+        1.055 * color.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+/// Tone maps the linear colors of a floating point image into an 8-bit sRGB image, after
+/// scaling them by `exposure` stops. Alpha is kept as it is.
+pub fn tone_map(image: &DynamicImage, tone_mapping: ToneMapping, exposure: f64) -> DynamicImage {
+    let scale = 2f32.powf(exposure as f32);
+    let map_color = |color: f32| srgb_encode(tone_map_channel(tone_mapping, color * scale));
+
+    let source = image.to_rgba32f();
+    let mut mapped = RgbaImage::new(source.width(), source.height());
+    mapped
+        .par_chunks_mut(4)
+        .zip(source.par_chunks(4))
+        .for_each(|(mapped, source)| {
+            for channel in 0..3 {
+                mapped[channel] = map_color(source[channel]);
+            }
+            mapped[3] = (source[3].clamp(0.0, 1.0) * 255.0).round() as u8;
+        });
+
+    let mapped = DynamicImage::ImageRgba8(mapped);
+    if image.color().has_alpha() {
+        mapped
+    } else {
+        DynamicImage::ImageRgb8(mapped.into_rgb8())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::hdr::HdrEncoder, Rgb, Rgba, Rgba32FImage};
+
+    use super::*;
+
+    /// Tone maps a single gray pixel, and returns its 8-bit channel.
+    fn map_gray(color: f32, tone_mapping: ToneMapping, exposure: f64) -> u8 {
+        let image = DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(1, 1, Rgb([color; 3])));
+        tone_map(&image, tone_mapping, exposure)
+            .into_rgb8()
+            .get_pixel(0, 0)[0]
+    }
+
+    #[test]
+    fn tone_mappings_fit_colors_into_8_bits() {
+        // linear 0.5 is 188 in sRGB
+        assert_eq!(map_gray(0.5, ToneMapping::Linear, 0.0), 188);
+        assert_eq!(map_gray(4.0, ToneMapping::Linear, 0.0), 255);
+        assert_eq!(map_gray(-1.0, ToneMapping::Linear, 0.0), 0);
+
+        // Reinhard maps 1 to 0.5, and never quite reaches white
+        assert_eq!(map_gray(1.0, ToneMapping::Reinhard, 0.0), 188);
+        assert!(map_gray(100.0, ToneMapping::Reinhard, 0.0) < 255);
+
+        assert_eq!(map_gray(0.0, ToneMapping::Aces, 0.0), 0);
+        assert_eq!(map_gray(100.0, ToneMapping::Aces, 0.0), 255);
+        assert!(map_gray(1.0, ToneMapping::Aces, 0.0) > map_gray(0.5, ToneMapping::Aces, 0.0));
+    }
+
+    #[test]
+    fn exposure_scales_colors_by_stops_before_tone_mapping() {
+        for tone_mapping in [
+            ToneMapping::Linear,
+            ToneMapping::Reinhard,
+            ToneMapping::Aces,
+        ] {
+            let expected = map_gray(0.5, tone_mapping, 0.0);
+            assert_eq!(map_gray(0.25, tone_mapping, 1.0), expected);
+            assert_eq!(map_gray(2.0, tone_mapping, -2.0), expected);
+        }
+    }
+
+    #[test]
+    fn tone_mapping_keeps_alpha() {
+        let image =
+            DynamicImage::ImageRgba32F(Rgba32FImage::from_pixel(1, 1, Rgba([0.5, 0.5, 0.5, 0.5])));
+        let mapped = tone_map(&image, ToneMapping::Linear, 0.0);
+        assert_eq!(
+            mapped.as_rgba8().unwrap().get_pixel(0, 0),
+            &Rgba([188, 188, 188, 128])
+        );
+
+        let image = DynamicImage::ImageRgb32F(Rgb32FImage::new(1, 1));
+        assert!(tone_map(&image, ToneMapping::Linear, 0.0)
+            .as_rgb8()
+            .is_some());
+    }
+
+    #[test]
+    fn radiance_images_keep_colors_brighter_than_white() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("bright.hdr");
+        HdrEncoder::new(File::create(&path).unwrap())
+            .encode(&[Rgb([4.0, 0.5, 0.0])], 1, 1)
+            .unwrap();
+
+        let image = open_image(&path).unwrap();
+        assert!(is_float_image(&image));
+        assert_eq!(
+            image.as_rgb32f().unwrap().get_pixel(0, 0),
+            &Rgb([4.0, 0.5, 0.0])
+        );
+    }
+}
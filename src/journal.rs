@@ -0,0 +1,216 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::error::TileError;
+
+/// The name of the journal file written to the root of an output directory.
+pub const JOURNAL_FILE_NAME: &str = "tileproc-journal.txt";
+
+/// A record of the tiles and LOD layers a job has finished, so that an interrupted job can be
+/// resumed without redoing them.
+///
+/// The journal is a text file with one entry per line. The first line describes the job, so
+/// that a journal is never resumed by a different job.
+/// ```text
+/// job gen-tile-layers input=big.png tile_dimensions=(256, 256) x_offset=None y_offset=None format=png gutter=0
+/// tile 0 -3,2
+/// level 0
+/// ```
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+    done_tiles: HashSet<(u32, i32, i32)>,
+    done_levels: HashSet<u32>,
+}
+
+/// The part of a journal that belongs to one LOD layer.
+#[derive(Clone, Copy)]
+pub struct LevelJournal<'a> {
+    journal: &'a Journal,
+    level: u32,
+}
+
+impl Journal {
+    /// Starts a new, empty journal in `dir`, replacing any existing one.
+    pub fn create(dir: &Path, job: &str) -> io::Result<Journal> {
+        let path = dir.join(JOURNAL_FILE_NAME);
+        let mut file = File::create(&path)?;
+        file.write_all(format!("job {}\n", job).as_bytes())?;
+
+        Ok(Journal {
+            path,
+            file: Mutex::new(file),
+            done_tiles: HashSet::new(),
+            done_levels: HashSet::new(),
+        })
+    }
+
+    /// Opens the journal in `dir` to resume a job, or starts a new one if there is none.
+    ///
+    /// Fails if the existing journal was written by a different job.
+    pub fn resume(dir: &Path, job: &str) -> io::Result<Journal> {
+        let path = dir.join(JOURNAL_FILE_NAME);
+        if !path.is_file() {
+            return Journal::create(dir, job);
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let mut lines = contents.lines();
+
+        if lines.next() != Some(&format!("job {}", job)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the journal in the output directory was written by a different job",
+            ));
+        }
+
+        let mut done_tiles = HashSet::new();
+        let mut done_levels = HashSet::new();
+
+        for line in lines {
+            let mut words = line.split(' ');
+            match (words.next(), words.next(), words.next()) {
+                (Some("tile"), Some(level), Some(coords)) => {
+                    let coords = coords.split_once(',').and_then(|(x, y)| {
+                        Some((level.parse().ok()?, x.parse().ok()?, y.parse().ok()?))
+                    });
+                    // a line cut short by a crash is ignored
+                    if let Some(coords) = coords {
+                        done_tiles.insert(coords);
+                    }
+                }
+                (Some("level"), Some(level), None) => {
+                    if let Ok(level) = level.parse() {
+                        done_levels.insert(level);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // start on a fresh line, in case the last one was cut short
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        if !contents.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+
+        Ok(Journal {
+            path,
+            file: Mutex::new(file),
+            done_tiles,
+            done_levels,
+        })
+    }
+
+    pub fn level(&self, level: u32) -> LevelJournal<'_> {
+        LevelJournal {
+            journal: self,
+            level,
+        }
+    }
+
+    /// Returns true if every tile of a LOD layer was finished.
+    pub fn is_level_done(&self, level: u32) -> bool {
+        self.done_levels.contains(&level)
+    }
+
+    pub fn record_level(&self, level: u32) -> Result<(), TileError> {
+        self.write_line(&format!("level {}\n", level))
+    }
+
+    fn write_line(&self, line: &str) -> Result<(), TileError> {
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .map_err(TileError::io(&self.path))
+    }
+}
+
+impl LevelJournal<'_> {
+    /// Returns true if the tile was finished by a previous run.
+    pub fn is_tile_done(&self, x: i32, y: i32) -> bool {
+        self.journal.done_tiles.contains(&(self.level, x, y))
+    }
+
+    /// Records that a tile was saved, or that it was found to be empty.
+    pub fn record_tile(&self, x: i32, y: i32) -> Result<(), TileError> {
+        self.journal
+            .write_line(&format!("tile {} {},{}\n", self.level, x, y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_skips_recorded_tiles_and_levels() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let journal = Journal::create(dir.path(), "gen-tile-layers a").unwrap();
+            journal.level(0).record_tile(-3, 2).unwrap();
+            journal.level(0).record_tile(4, 5).unwrap();
+            journal.record_level(0).unwrap();
+            journal.level(1).record_tile(-2, 1).unwrap();
+        }
+
+        let journal = Journal::resume(dir.path(), "gen-tile-layers a").unwrap();
+        assert!(journal.is_level_done(0));
+        assert!(!journal.is_level_done(1));
+        assert!(journal.level(0).is_tile_done(-3, 2));
+        assert!(journal.level(0).is_tile_done(4, 5));
+        assert!(journal.level(1).is_tile_done(-2, 1));
+        assert!(!journal.level(1).is_tile_done(-3, 2));
+        assert!(!journal.level(0).is_tile_done(0, 0));
+    }
+
+    #[test]
+    fn resume_rejects_journals_of_other_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        Journal::create(dir.path(), "gen-tiles a").unwrap();
+
+        let err = Journal::resume(dir.path(), "gen-tiles b").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // a job that only starts like the journaled one is still a different job
+        assert!(Journal::resume(dir.path(), "gen-tiles a b").is_err());
+    }
+
+    #[test]
+    fn resume_without_a_journal_starts_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::resume(dir.path(), "gen-tiles a").unwrap();
+        assert!(!journal.is_level_done(0));
+        assert_eq!(
+            fs::read_to_string(dir.path().join(JOURNAL_FILE_NAME)).unwrap(),
+            "job gen-tiles a\n"
+        );
+    }
+
+    #[test]
+    fn resume_ignores_a_line_cut_short() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(JOURNAL_FILE_NAME),
+            "job gen-tiles a\ntile 0 1,2\ntile 0 3,",
+        )
+        .unwrap();
+
+        {
+            let journal = Journal::resume(dir.path(), "gen-tiles a").unwrap();
+            assert!(journal.level(0).is_tile_done(1, 2));
+            assert!(!journal.level(0).is_tile_done(3, 0));
+            journal.level(0).record_tile(3, 4).unwrap();
+        }
+
+        // the next entry starts on a fresh line, so that it is read back
+        let journal = Journal::resume(dir.path(), "gen-tiles a").unwrap();
+        assert!(journal.level(0).is_tile_done(1, 2));
+        assert!(journal.level(0).is_tile_done(3, 4));
+    }
+}
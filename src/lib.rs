@@ -1,18 +1,69 @@
 pub mod args;
+pub mod atlas;
+pub mod batch;
+pub mod cancel;
+pub mod coords;
+pub mod error;
+pub mod georef;
+pub mod gutter;
+pub mod hdr;
+pub mod journal;
+pub mod mask;
+pub mod mercator;
+pub mod merge;
+pub mod mosaic;
+pub mod naming;
+pub mod nodata;
+pub mod pixel;
+pub mod progress;
+pub mod terrain;
+pub mod texture;
+pub mod transform;
+pub mod validate;
+pub mod writer;
 
 pub mod tiler {
     use glob::{glob, GlobError};
-    use image::{
-        imageops::FilterType, io::Reader, DynamicImage, GenericImageView, ImageBuffer, Rgba,
-        RgbaImage,
-    };
+    use image::{imageops::FilterType, io::Reader, DynamicImage, GenericImageView};
+    use rayon::prelude::*;
     use std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet},
         fs, io,
         path::{Path, PathBuf},
+        sync::Mutex,
     };
 
-    use crate::args::GenTilesArgs;
+    use crate::args::{GenTilesArgs, TileFormat};
+    use crate::atlas::{is_atlas_file, ATLAS_INDEX_FILE_NAME};
+    use crate::cancel::CancelToken;
+    use crate::coords::{parent_tile, tile_origin, tile_range};
+    use crate::error::TileError;
+    use crate::georef::{GeoManifest, GeoTransform, Placement, WorldGrid, MANIFEST_FILE_NAME};
+    use crate::gutter::add_gutters;
+    use crate::hdr::{is_float_image, open_image, tone_map};
+    use crate::journal::{Journal, JOURNAL_FILE_NAME};
+    use crate::mask::apply_mask;
+    use crate::mosaic::{is_manifest_path, mosaic_to_tiles, MosaicManifest};
+    use crate::naming::NameTemplate;
+    use crate::nodata::{content_bounds, nodata_to_alpha};
+    use crate::pixel::{
+        blank_tile, buffer, is_blank, resize, resize_image, with_pixel_type, TileBuffer, TilePixel,
+    };
+    use crate::progress::Progress;
+    use crate::texture::open_tile;
+    use crate::transform::{read_orientation, Transform};
+    use crate::writer::{is_temp_tile_path, TileWriter};
+
+    /// What the tiling functions of a job share: how tiles are written, the journal finished work
+    /// is recorded in, where progress is reported to, and the token that cancels the job.
+    #[derive(Clone, Copy)]
+    pub struct JobContext<'a> {
+        pub writer: &'a TileWriter,
+        /// Without a journal, output directories are cleared before tiles are generated into them.
+        pub journal: Option<&'a Journal>,
+        pub progress: &'a Progress,
+        pub cancel: &'a CancelToken,
+    }
 
     #[derive(Debug)]
     struct Bounds {
@@ -55,15 +106,29 @@ pub mod tiler {
         Ok(paths)
     }
 
-    /// Stitches image tiles into one image
-    pub fn consolidate_images(files: &[PathBuf]) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-        // get initial tile dimensions
-        let mut tile_dimensions: (u32, u32) = (0, 0);
-        let source_image = image::open(&files[0]).unwrap();
-        tile_dimensions.0 = source_image.width();
-        tile_dimensions.1 = source_image.height();
-        let tile_dimensions = tile_dimensions;
+    /// Stitches image tiles named by `name_template` into one image, with the pixel type of the
+    /// first tile.
+    ///
+    /// Every tile needs the dimensions of the first one.
+    pub fn consolidate_images(
+        files: &[PathBuf],
+        name_template: &NameTemplate,
+        cancel: &CancelToken,
+    ) -> Result<DynamicImage, TileError> {
+        let source_image = open_tile(&files[0]).unwrap();
+
+        with_pixel_type!(source_image.color(), P => {
+            consolidate_tiles::<P>(files, source_image.dimensions(), name_template, cancel)
+                .map(P::into_dynamic)
+        })
+    }
 
+    fn consolidate_tiles<P: TilePixel>(
+        files: &[PathBuf],
+        tile_dimensions: (u32, u32),
+        name_template: &NameTemplate,
+        cancel: &CancelToken,
+    ) -> Result<TileBuffer<P>, TileError> {
         let mut bounds = Bounds {
             max_x: i32::MIN,
             max_z: i32::MIN,
@@ -75,11 +140,7 @@ pub mod tiler {
 
         // find max and min dimensions
         for file in files {
-            let file_name = file.file_stem().unwrap().to_str().unwrap();
-            let split: Vec<&str> = file_name.split(',').collect();
-
-            let x: i32 = split[0].parse().unwrap();
-            let z: i32 = split[1].parse().unwrap();
+            let (x, z) = name_template.parse_path(file).unwrap();
 
             filename_and_numbers_vec.push(FilenameAndNumbers {
                 file_name: file.clone(),
@@ -105,13 +166,23 @@ pub mod tiler {
         let zdiff = bounds.max_z - bounds.min_z + 1;
 
         // Create a new ImgBuf with width: imgx and height: imgy
-        let mut output_imgbuf = RgbaImage::new(
+        let mut output_imgbuf = blank_tile::<P>(
             (xdiff * tile_dimensions.0 as i32).try_into().unwrap(),
             (zdiff * tile_dimensions.1 as i32).try_into().unwrap(),
         );
 
         for file_struc in &filename_and_numbers_vec {
-            let tile_img = image::open(&file_struc.file_name).unwrap();
+            cancel.check()?;
+
+            let tile_img = open_tile(&file_struc.file_name).unwrap();
+            if tile_img.dimensions() != tile_dimensions {
+                return Err(TileError::DimensionMismatch {
+                    path: file_struc.file_name.clone(),
+                    expected: tile_dimensions,
+                    found: tile_img.dimensions(),
+                });
+            }
+            let tile_img = P::from_dynamic(tile_img);
 
             let x_sector = file_struc.x + -bounds.min_x;
             let z_sector = file_struc.z + -bounds.min_z;
@@ -120,7 +191,7 @@ pub mod tiler {
             for x in 0..tile_dimensions.0 as i32 {
                 for z in 0..tile_dimensions.1 as i32 {
                     // get pixel from tile image
-                    let pixel = tile_img.get_pixel(x.try_into().unwrap(), z.try_into().unwrap());
+                    let pixel = *tile_img.get_pixel(x.try_into().unwrap(), z.try_into().unwrap());
 
                     // calculate where pixel should go on output image
                     let output_pixel_x = x + (x_sector * tile_dimensions.0 as i32);
@@ -135,50 +206,343 @@ pub mod tiler {
             }
         }
 
-        output_imgbuf
+        Ok(output_imgbuf)
     }
 
-    /// remove contents inside a directory, without deleting the directory itself.
-    fn remove_dir_contents<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    /// The name of the file marking a directory as the output of tileproc, written to the root
+    /// of every output directory that is cleared.
+    pub const OUTPUT_MARKER_FILE_NAME: &str = "tileproc-output.txt";
+
+    /// Returns true for the files tileproc writes next to tiles that are not tiles: the output
+    /// marker, the journal and the manifest of georeferenced tiles.
+    pub fn is_metadata_file(path: &Path) -> bool {
+        path.file_name().is_some_and(|name| {
+            name == OUTPUT_MARKER_FILE_NAME
+                || name == JOURNAL_FILE_NAME
+                || name == MANIFEST_FILE_NAME
+        })
+    }
+
+    /// Returns true if a directory holds a file only tileproc writes, so that it is known to be
+    /// the output of a previous run: the output marker, a journal, a manifest or an atlas index.
+    fn has_output_marker(path: &Path) -> bool {
+        [
+            OUTPUT_MARKER_FILE_NAME,
+            JOURNAL_FILE_NAME,
+            MANIFEST_FILE_NAME,
+            ATLAS_INDEX_FILE_NAME,
+        ]
+        .iter()
+        .any(|name| path.join(name).is_file())
+    }
+
+    /// Returns true for the entries tileproc generates inside of an output directory: tiles named
+    /// by `name_template`, interrupted tile writes, LOD layer directories, the output marker, the
+    /// journal, the manifest and atlas pages with their index.
+    ///
+    /// Symlinks and other special files are never considered generated.
+    fn is_generated_entry(
+        path: &Path,
+        file_type: fs::FileType,
+        name_template: &NameTemplate,
+    ) -> bool {
+        let file_name = path.file_name().and_then(|name| name.to_str());
+
+        if file_type.is_file() {
+            name_template.parse_path(path).is_some()
+                || is_temp_tile_path(path, name_template)
+                || is_metadata_file(path)
+                || is_atlas_file(path)
+        } else if file_type.is_dir() {
+            file_name.is_some_and(|name| name.parse::<u32>().is_ok())
+        } else {
+            false
+        }
+    }
+
+    /// Removes the content tileproc generated inside a directory, without deleting the directory
+    /// itself. Anything else, including symlinks, is left alone.
+    fn remove_dir_contents(path: &Path, name_template: &NameTemplate) -> io::Result<()> {
         for entry in fs::read_dir(path)? {
-            let path = entry.unwrap().path();
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = entry.path();
 
-            if path.is_file() {
-                fs::remove_file(path)?;
-            } else if path.is_dir() {
-                fs::remove_dir_all(path)?;
+            if !is_generated_entry(&path, file_type, name_template) {
+                continue;
+            }
+
+            if file_type.is_dir() {
+                remove_dir_contents(&path, name_template)?;
+
+                // keep LOD layer directories that still hold other files
+                if fs::read_dir(&path)?.next().is_none() {
+                    fs::remove_dir(&path)?;
+                }
             } else {
-                panic!("https://i.kym-cdn.com/entries/icons/original/000/013/306/2dd.jpg")
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erases the generated content of an existing directory, or creates an empty new one, and
+    /// marks it as an output directory.
+    ///
+    /// A directory that is not empty is only cleared if it has an output marker of a previous run
+    /// or `force` is set, since files that merely look like tiles may belong to someone else.
+    /// Even then only the tiles, LOD layers and metadata files in it are deleted. Tiles are
+    /// recognized by `name_template`.
+    pub fn clean_dir(path: &Path, force: bool, name_template: &NameTemplate) -> io::Result<()> {
+        if fs::symlink_metadata(path).is_ok() {
+            if !path.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is not a directory", path.display()),
+                ));
+            }
+            if !force && !has_output_marker(path) {
+                if let Some(entry) = fs::read_dir(path)?.next() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "refusing to clear {}, it contains {} and was not written by tileproc. Pass --force to clear it anyway",
+                            path.display(),
+                            entry?.path().display()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        clear_dir(path, name_template)?;
+        fs::write(
+            path.join(OUTPUT_MARKER_FILE_NAME),
+            "This directory was written by tileproc, which clears it when it writes to it again.\n",
+        )
+    }
+
+    /// Erases the generated content of a directory inside an output directory that was cleaned
+    /// with `clean_dir`, such as a LOD layer, or creates an empty new one. It is not marked.
+    fn clear_dir(path: &Path, name_template: &NameTemplate) -> io::Result<()> {
+        if fs::symlink_metadata(path).is_err() {
+            fs::create_dir_all(path)
+        } else {
+            remove_dir_contents(path, name_template)
+        }
+    }
+
+    /// Clears the output directory of a `gen-tiles` or `gen-tile-layers` job, and its float
+    /// output directory, and starts a new journal in it, or with `--resume`, continues the journal
+    /// already in it.
+    ///
+    /// The arguments are expected to be checked with `GenTilesArgs::validate` before.
+    pub fn start_journal(command: &str, gen_tiles_args: &GenTilesArgs) -> io::Result<Journal> {
+        let job = format!(
+            "{} input={} tile_dimensions={:?} x_offset={:?} y_offset={:?} format={} block_compression={:?} mipmaps={} name_template={} gutter={} resolution={:?} world_origin={:?} tone_map={:?} exposure={:?} float_output={:?} ignore_orientation={} flip={:?} rotate={:?} scale={:?} mask={:?} nodata={:?} nodata_tolerance={} auto_crop={}",
+            command,
+            gen_tiles_args.input.display(),
+            gen_tiles_args.tile_size(),
+            gen_tiles_args.x_offset,
+            gen_tiles_args.y_offset,
+            gen_tiles_args.format.extension(),
+            gen_tiles_args.block_compression,
+            gen_tiles_args.mipmaps,
+            gen_tiles_args.name_template,
+            gen_tiles_args.gutter,
+            gen_tiles_args.resolution,
+            (gen_tiles_args.world_origin_x, gen_tiles_args.world_origin_y),
+            gen_tiles_args.tone_map,
+            gen_tiles_args.exposure,
+            gen_tiles_args.float_output,
+            gen_tiles_args.ignore_orientation,
+            gen_tiles_args.flip,
+            gen_tiles_args.rotate,
+            gen_tiles_args.scale,
+            gen_tiles_args.mask,
+            gen_tiles_args.nodata,
+            gen_tiles_args.nodata_tolerance,
+            gen_tiles_args.auto_crop
+        );
+
+        if gen_tiles_args.resume {
+            fs::create_dir_all(&gen_tiles_args.output)?;
+            Journal::resume(&gen_tiles_args.output, &job)
+                .map_err(|err| io::Error::new(err.kind(), format!("can not resume: {}", err)))
+        } else {
+            clean_dir(
+                &gen_tiles_args.output,
+                gen_tiles_args.force,
+                &gen_tiles_args.name_template,
+            )?;
+            if let Some(float_output) = &gen_tiles_args.float_output {
+                clean_dir(
+                    float_output,
+                    gen_tiles_args.force,
+                    &gen_tiles_args.name_template,
+                )?;
+            }
+            Journal::create(&gen_tiles_args.output, &job)
+        }
+    }
+
+    /// Removes the tiles named by `name_template` and interrupted tile writes directly inside a
+    /// directory.
+    pub(crate) fn remove_tiles(dir: &Path, name_template: &NameTemplate) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file()
+                && (name_template.parse_path(&path).is_some()
+                    || is_temp_tile_path(&path, name_template))
+            {
+                fs::remove_file(path)?;
             }
         }
         Ok(())
     }
 
-    /// Erases all content of an existing directory, or creates an empty new one.
-    pub fn clean_dir(path: &Path) {
-        // clear any existing output_dir
-        if path.is_dir() {
-            remove_dir_contents(path).unwrap();
+    /// Returns the paths of the entries of a directory.
+    pub(crate) fn dir_paths(dir: &Path) -> Result<Vec<PathBuf>, TileError> {
+        fs::read_dir(dir)
+            .and_then(|entries| entries.map(|entry| Ok(entry?.path())).collect())
+            .map_err(TileError::io(dir))
+    }
+
+    /// Returns false if a tile file is missing or can't be decoded, e.g. because writing it was
+    /// interrupted or it was deleted after the journal recorded it.
+    fn tile_is_intact(path: &Path) -> bool {
+        open_tile(path).is_ok()
+    }
+
+    /// Prepares a tile output directory. Without a journal the directory is cleared, with one the
+    /// tiles of a previous run are kept, and only its interrupted writes are removed.
+    pub(crate) fn prepare_output_dir(
+        output_dir: &Path,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        if context.journal.is_some() {
+            fs::create_dir_all(output_dir).map_err(TileError::io(output_dir))?;
+            context
+                .writer
+                .remove_temp_files(output_dir)
+                .map_err(TileError::io(output_dir))
         } else {
-            fs::create_dir(path).unwrap();
+            // the directories of a job are in an output directory that was cleaned before, or
+            // are its own LOD layers
+            clear_dir(output_dir, &context.writer.name_template).map_err(TileError::io(output_dir))
+        }
+    }
+
+    /// Renders one tile of a LOD layer from the (up to) 4 tiles it covers in the layer below,
+    /// with the pixel type of those tiles.
+    ///
+    /// Returns the number of bytes written, or an error if a tile doesn't have the dimensions
+    /// `tile_dimensions`.
+    fn shrink_tile<P: AsRef<Path>>(
+        filenums_map: &HashMap<(i32, i32), P>,
+        tile_dimensions: (u32, u32),
+        output_tile_x: i32,
+        output_tile_y: i32,
+        output_dir: &Path,
+        writer: &TileWriter,
+    ) -> Result<u64, TileError> {
+        // decode the tiles of the 4 sectors of the new tile
+        let mut input_tiles = Vec::new();
+        for x_sector in 0..=1u32 {
+            for y_sector in 0..=1u32 {
+                let real_x = output_tile_x * 2 + x_sector as i32;
+                let real_y = output_tile_y * 2 + y_sector as i32;
+
+                if let Some(path) = filenums_map.get(&(real_x, real_y)) {
+                    let path = path.as_ref();
+                    let input_tile = open_tile(path).map_err(TileError::image(path))?;
+                    if input_tile.dimensions() != tile_dimensions {
+                        return Err(TileError::DimensionMismatch {
+                            path: path.to_path_buf(),
+                            expected: tile_dimensions,
+                            found: input_tile.dimensions(),
+                        });
+                    }
+                    input_tiles.push(((x_sector, y_sector), input_tile));
+                }
+            }
+        }
+
+        let dynamic = with_pixel_type!(input_tiles[0].1.color(), T => {
+            T::into_dynamic(shrink_sectors::<T>(input_tiles, tile_dimensions))
+        });
+
+        // save file
+        let path = output_dir.join(writer.tile_file_name(output_tile_x, output_tile_y));
+        writer
+            .write(&dynamic, &path)
+            .map_err(TileError::image(&path))
+    }
+
+    /// Combines the tiles of the 4 sectors of a LOD layer tile into one, and shrinks it to the
+    /// size of a tile.
+    fn shrink_sectors<P: TilePixel>(
+        input_tiles: Vec<((u32, u32), DynamicImage)>,
+        tile_dimensions: (u32, u32),
+    ) -> TileBuffer<P> {
+        // initialize output image
+        let mut output_imgbuf = blank_tile::<P>(2 * tile_dimensions.0, 2 * tile_dimensions.1);
+
+        // convert 4 images into one big image
+        for ((x_sector, y_sector), input_tile_img) in input_tiles {
+            let input_tile_img = P::from_dynamic(input_tile_img);
+
+            // transfer image
+            for x in 0..tile_dimensions.0 {
+                for y in 0..tile_dimensions.1 {
+                    // get pixel from tile image
+                    let pixel = *input_tile_img.get_pixel(x, y);
+
+                    // calculate where pixel should go on output image
+                    let output_pixel_x = x + x_sector * tile_dimensions.0;
+                    let output_pixel_z = y + y_sector * tile_dimensions.1;
+
+                    output_imgbuf.put_pixel(output_pixel_x, output_pixel_z, pixel)
+                }
+            }
         }
+
+        // resize output image
+        resize(
+            &output_imgbuf,
+            tile_dimensions.0,
+            tile_dimensions.1,
+            FilterType::Lanczos3,
+        )
     }
 
     /// Compresses one lod layer
-    pub fn shrink_tiles(input_files: Vec<PathBuf>, output_dir: &Path) {
+    ///
+    /// Every input tile needs the dimensions of the first one. Tiles recorded as done in the
+    /// journal are skipped.
+    pub fn shrink_tiles(
+        input_files: Vec<PathBuf>,
+        output_dir: &Path,
+        level: u32,
+        context: JobContext,
+    ) -> Result<(), TileError> {
         // cancel if nothing to do
         if input_files.is_empty() {
-            return;
+            return Ok(());
         }
 
-        clean_dir(output_dir);
+        // the quadkeys of the layer are one digit shorter than those of the tiles below it
+        let writer = &context.writer.at_zoom_of(&input_files).above();
+        let context = JobContext { writer, ..context };
+
+        prepare_output_dir(output_dir, context)?;
+        let journal = context.journal.map(|journal| journal.level(level));
 
         let mut filenums_map = HashMap::new();
-        let mut rendered_output_tiles_map = HashMap::new();
 
         // get initial tile dimensions
         let mut tile_dimensions: (u32, u32) = (0, 0);
-        let source_image = image::open(&input_files[0]).unwrap();
+        let source_image = open_tile(&input_files[0]).map_err(TileError::image(&input_files[0]))?;
         tile_dimensions.0 = source_image.width();
         tile_dimensions.1 = source_image.height();
         let tile_dimensions = tile_dimensions;
@@ -186,11 +550,10 @@ pub mod tiler {
         for file in &input_files {
             let mut filename_and_numbers_vec: Vec<FilenameAndNumbers> = Vec::new();
 
-            let file_name = file.file_stem().unwrap().to_str().unwrap();
-            let split: Vec<&str> = file_name.split(',').collect();
-
-            let x: i32 = split[0].parse().unwrap();
-            let z: i32 = split[1].parse().unwrap();
+            // files that aren't tiles have no place in the layer
+            let Some((x, z)) = context.writer.tile_coords(file) else {
+                continue;
+            };
 
             filename_and_numbers_vec.push(FilenameAndNumbers {
                 file_name: file.clone(),
@@ -203,173 +566,266 @@ pub mod tiler {
 
         let filenums_map = filenums_map;
 
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(num_cpus::get())
-            .build()
-            .unwrap();
-
-        pool.scope(|s| {
-            for (x, y) in filenums_map.keys() {
-                // determine coords of output tile
-                let output_tile_x = if *x < 0 { (*x - 1) / 2 } else { *x / 2 };
-                let output_tile_y = if *y < 0 { (*y - 1) / 2 } else { *y / 2 };
-
-                //skip any already rendered tiles
-                if rendered_output_tiles_map.contains_key(&(output_tile_x, output_tile_y)) {
-                    continue;
-                }
-
-                // mark output image tile as rendered
-                rendered_output_tiles_map.insert((output_tile_x, output_tile_y), true);
-
-                let test_closure = |output_tile_x: i32, output_tile_y: i32| {
-                    let output_tile_x = output_tile_x;
-                    let output_tile_y = output_tile_y;
-
-                    // initialize output image
-                    let mut output_imgbuf =
-                        RgbaImage::new(2 * tile_dimensions.0, 2 * tile_dimensions.1);
-
-                    // convert 4 images into one big image
-                    // for the 4 sectors of the new tile
-                    for x_sector in 0..=1 {
-                        for y_sector in 0..=1 {
-                            let real_x = output_tile_x * 2 + x_sector;
-                            let real_y = output_tile_y * 2 + y_sector;
-
-                            match &filenums_map.get(&(real_x, real_y)) {
-                                Some(path) => {
-                                    let input_tile_img = image::open(&path).unwrap();
-
-                                    // transfer image
-                                    for x in 0..tile_dimensions.0 {
-                                        for y in 0..tile_dimensions.1 {
-                                            // get pixel from tile image
-                                            let pixel = input_tile_img.get_pixel(x, y);
-
-                                            // calculate where pixel should go on output image
-                                            let output_pixel_x =
-                                                x as i32 + (x_sector * tile_dimensions.0 as i32);
-                                            let output_pixel_z =
-                                                y as i32 + (y_sector * tile_dimensions.1 as i32);
-
-                                            output_imgbuf.put_pixel(
-                                                output_pixel_x.try_into().unwrap(),
-                                                output_pixel_z.try_into().unwrap(),
-                                                pixel,
-                                            )
-                                        }
-                                    }
-                                }
-                                None => continue,
-                            }
-                        }
+        // determine coords of output tiles
+        let output_tiles: HashSet<(i32, i32)> =
+            filenums_map.keys().map(|&tile| parent_tile(tile)).collect();
+
+        context
+            .progress
+            .start_level(level, output_tiles.len() as u64);
+
+        // run on the current rayon pool, so that jobs can share one
+        output_tiles
+            .par_iter()
+            .try_for_each(|&(output_tile_x, output_tile_y)| {
+                context.cancel.check()?;
+
+                // skip tiles finished by a previous run
+                if let Some(journal) = journal {
+                    if journal.is_tile_done(output_tile_x, output_tile_y)
+                        && tile_is_intact(
+                            &output_dir
+                                .join(context.writer.tile_file_name(output_tile_x, output_tile_y)),
+                        )
+                    {
+                        context.progress.tile_done(0);
+                        return Ok(());
                     }
+                }
 
-                    // resize output image
-                    let mut dynamic = DynamicImage::ImageRgba8(output_imgbuf);
-                    dynamic = dynamic.resize(
-                        dynamic.dimensions().0 / 2,
-                        dynamic.dimensions().1 / 2,
-                        FilterType::Lanczos3,
-                    );
-
-                    // save file
-                    let output_tile_filename =
-                        output_tile_x.to_string() + "," + &output_tile_y.to_string() + ".png";
-                    dynamic
-                        .save(output_dir.join(&output_tile_filename))
-                        .expect("failed to save file");
-                };
+                let bytes_written = shrink_tile(
+                    &filenums_map,
+                    tile_dimensions,
+                    output_tile_x,
+                    output_tile_y,
+                    output_dir,
+                    context.writer,
+                )?;
+
+                if let Some(journal) = journal {
+                    journal.record_tile(output_tile_x, output_tile_y)?;
+                }
+                context.progress.tile_done(bytes_written);
+                Ok(())
+            })?;
 
-                s.spawn(move |_| test_closure(output_tile_x, output_tile_y));
-            }
-        });
+        context.progress.finish_level();
+        Ok(())
     }
 
-    /// converts an image into square image tiles.
+    /// converts an image into image tiles of `tile_dimensions`, oriented as its EXIF orientation
+    /// says.
+    ///
+    /// Tiles recorded as done in the journal are skipped.
     pub fn image_to_tiles(
         image_path: &Path,
         x_offset: i32,
         y_offset: i32,
         output_dir: &Path,
-        tile_dimensions: u32,
-    ) {
-        clean_dir(output_dir);
-
-        println!("decoding image...");
-        let source_image = image::open(image_path).unwrap();
-        let out_tile_width = tile_dimensions;
-        let out_tile_height = tile_dimensions;
+        tile_dimensions: (u32, u32),
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        prepare_output_dir(output_dir, context)?;
+
+        context.progress.message("decoding image...");
+        let source_image = open_image(image_path).map_err(TileError::image(image_path))?;
+        let transform = Transform::new(
+            read_orientation(image_path),
+            None,
+            0.0,
+            1.0,
+            source_image.dimensions(),
+        );
 
-        println!("slicing tiles...");
+        tiles_from_image(
+            &source_image,
+            &transform,
+            x_offset,
+            y_offset,
+            output_dir,
+            tile_dimensions,
+            context,
+        )
+    }
 
-        let (top_left_sector, bottom_right_sector) = get_limit_sectors(
+    /// Slices a decoded image into layer 0 tiles, like `image_to_tiles`, into a prepared output
+    /// directory. Tiles have the pixel type of the image.
+    fn tiles_from_image(
+        source_image: &DynamicImage,
+        transform: &Transform,
+        x_offset: i32,
+        y_offset: i32,
+        output_dir: &Path,
+        tile_dimensions: (u32, u32),
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        with_pixel_type!(source_image.color(), P => slice_tiles(
+            &*buffer::<P>(source_image),
+            transform,
             x_offset,
             y_offset,
-            (out_tile_width as f32, out_tile_height as f32),
-            (source_image.width() as f32, source_image.height() as f32),
-        );
+            output_dir,
+            tile_dimensions,
+            context,
+        ))
+    }
 
-        std::thread::scope(|s| {
+    /// Slices an image into tiles of its own pixel type, after transforming it with `transform`.
+    /// The offsets are in pixels of the transformed image.
+    ///
+    /// Where the image doesn't cover a tile, the tile is transparent black, or black for pixel
+    /// types without alpha. Tiles the image doesn't cover, or only covers with transparent black
+    /// pixels, are not written.
+    fn slice_tiles<P: TilePixel>(
+        source_image: &TileBuffer<P>,
+        transform: &Transform,
+        x_offset: i32,
+        y_offset: i32,
+        output_dir: &Path,
+        tile_dimensions: (u32, u32),
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        let journal = context.journal.map(|journal| journal.level(0));
+        let (out_tile_width, out_tile_height) = tile_dimensions;
+
+        context.progress.message("slicing tiles...");
+
+        // the pixels on the right and bottom edges are included, since sampling can fade into them
+        let (left, top, right, bottom) = transform.bounds();
+        let (top_left_sector, bottom_right_sector) = tile_range(
+            (
+                left.floor() as i64 - x_offset as i64,
+                top.floor() as i64 - y_offset as i64,
+            ),
+            (
+                right.floor() as i64 - x_offset as i64,
+                bottom.floor() as i64 - y_offset as i64,
+            ),
+            tile_dimensions,
+            0,
+        )
+        .ok_or(TileError::TileOutOfRange)?;
+        let writer = &context.writer.reaching(bottom_right_sector);
+        let context = JobContext { writer, ..context };
+        context
+            .writer
+            .check_tile_names(top_left_sector, bottom_right_sector)?;
+        // pixels that are only moved are copied, the others are resampled
+        let pixel_offset = transform.whole_pixel_offset();
+
+        let total_tiles = (bottom_right_sector.0 - top_left_sector.0 + 1) as u64
+            * (bottom_right_sector.1 - top_left_sector.1 + 1) as u64;
+        context.progress.start_level(0, total_tiles);
+
+        // the first tile that failed to save stops the others
+        let save_error = &Mutex::new(None);
+        rayon::scope(|s| {
             // for every sector in source image
-            for sector_y in top_left_sector.1..=bottom_right_sector.1 {
+            'sectors: for sector_y in top_left_sector.1..=bottom_right_sector.1 {
                 for sector_x in top_left_sector.0..=bottom_right_sector.0 {
-                    let mut tile_image = RgbaImage::new(out_tile_width, out_tile_height);
+                    if context.cancel.is_cancelled() || save_error.lock().unwrap().is_some() {
+                        break 'sectors;
+                    }
+
+                    // skip tiles finished by a previous run
+                    if let Some(journal) = journal {
+                        if journal.is_tile_done(sector_x, sector_y)
+                            && tile_is_intact(
+                                &output_dir.join(context.writer.tile_file_name(sector_x, sector_y)),
+                            )
+                        {
+                            context.progress.tile_done(0);
+                            continue;
+                        }
+                    }
+
+                    let mut tile_image = blank_tile::<P>(out_tile_width, out_tile_height);
                     let mut tile_empty = true;
+                    // level 0 tiles of i32 coordinates are always in range
+                    let (origin_x, origin_y) =
+                        tile_origin((sector_x, sector_y), tile_dimensions, 0).unwrap();
 
                     // for every pixel in new tile
                     for y in 0..out_tile_height as i32 {
                         for x in 0..out_tile_width as i32 {
-                            // calculate where pixel is in source image
-                            let souce_x = (out_tile_width as i32 * sector_x + x) + x_offset;
-                            let souce_y = (out_tile_width as i32 * sector_y + y) + y_offset;
-
-                            let pixel = if souce_x >= 0
-                                && souce_x < source_image.width().try_into().unwrap()
-                                && souce_y >= 0
-                                && souce_y < source_image.height().try_into().unwrap()
-                            {
-                                let pixel = source_image.get_pixel(
-                                    souce_x.try_into().unwrap(),
-                                    souce_y.try_into().unwrap(),
-                                );
-
-                                if pixel != Rgba([0, 0, 0, 0]) {
-                                    tile_empty = false;
+                            // calculate where pixel is in the transformed image
+                            let image_x = origin_x + x as i64 + x_offset as i64;
+                            let image_y = origin_y + y as i64 + y_offset as i64;
+
+                            let pixel = match pixel_offset {
+                                Some((offset_x, offset_y)) => {
+                                    let souce_x = image_x - offset_x as i64;
+                                    let souce_y = image_y - offset_y as i64;
+
+                                    (souce_x >= 0
+                                        && souce_x < i64::from(source_image.width())
+                                        && souce_y >= 0
+                                        && souce_y < i64::from(source_image.height()))
+                                    .then(|| {
+                                        *source_image.get_pixel(
+                                            souce_x.try_into().unwrap(),
+                                            souce_y.try_into().unwrap(),
+                                        )
+                                    })
                                 }
-
-                                pixel
-                            } else {
-                                Rgba([0, 0, 0, 0])
+                                None => transform.sample(source_image, image_x, image_y),
                             };
 
-                            tile_image.put_pixel(
-                                x.try_into().unwrap(),
-                                y.try_into().unwrap(),
-                                pixel,
-                            )
+                            if let Some(pixel) = pixel {
+                                if !is_blank(&pixel) {
+                                    tile_empty = false;
+                                }
+
+                                tile_image.put_pixel(
+                                    x.try_into().unwrap(),
+                                    y.try_into().unwrap(),
+                                    pixel,
+                                )
+                            }
                         }
                     }
 
                     let file_save_closure =
-                        |sector_x: i32,
-                         sector_y: i32,
-                         tile_image: ImageBuffer<Rgba<u8>, Vec<u8>>| {
-                            let output_tile_filename =
-                                sector_x.to_string() + "," + &sector_y.to_string() + ".png";
-                            tile_image
-                                .save(output_dir.join(output_tile_filename))
-                                .expect("failed to save file");
+                        |sector_x: i32, sector_y: i32, tile_image: TileBuffer<P>| {
+                            let path =
+                                output_dir.join(context.writer.tile_file_name(sector_x, sector_y));
+                            let bytes_written = context
+                                .writer
+                                .write(&P::into_dynamic(tile_image), &path)
+                                .map_err(TileError::image(&path))?;
+
+                            if let Some(journal) = journal {
+                                journal.record_tile(sector_x, sector_y)?;
+                            }
+                            context.progress.tile_done(bytes_written);
+                            Ok(())
                         };
 
                     // save file
                     if !tile_empty {
-                        s.spawn(move || file_save_closure(sector_x, sector_y, tile_image));
+                        s.spawn(move |_| {
+                            if let Err(err) = file_save_closure(sector_x, sector_y, tile_image) {
+                                save_error.lock().unwrap().get_or_insert(err);
+                            }
+                        });
+                    } else {
+                        if let Some(journal) = journal {
+                            if let Err(err) = journal.record_tile(sector_x, sector_y) {
+                                save_error.lock().unwrap().get_or_insert(err);
+                            }
+                        }
+                        context.progress.tile_done(0);
                     }
                 }
             }
         });
+
+        if let Some(err) = save_error.lock().unwrap().take() {
+            return Err(err);
+        }
+        context.cancel.check()?;
+        context.progress.finish_level();
+        Ok(())
     }
 
     /// Generates LOD layers
@@ -379,84 +835,1441 @@ pub mod tiler {
     /// LOD layers will be generated until the most recent one consists of 4 tiles or less
     ///
     /// Something like https://raw.githubusercontent.com/banesullivan/localtileserver/main/imgs/tile-diagram.gif
-    pub fn generate_lods(output_dir: &Path) {
-        let mut count = 0;
+    ///
+    /// LOD layers recorded as done in the journal are skipped.
+    pub fn generate_lods(output_dir: &Path, context: JobContext) -> Result<(), TileError> {
+        generate_lods_from(output_dir, 0, context)
+    }
+
+    /// Generates LOD layers above an existing LOD layer.
+    ///
+    /// Block compressed layers are staged uncompressed until every layer is generated, so that
+    /// each layer is shrunk from pixels that were never compressed.
+    pub(crate) fn generate_lods_from(
+        output_dir: &Path,
+        count: u32,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        let Some(staging_writer) = staging_writer(context.writer) else {
+            return build_lods_from(output_dir, count, context);
+        };
+        let staged_context = JobContext {
+            writer: &staging_writer,
+            ..context
+        };
+        build_lods_from(output_dir, count, staged_context)?;
+        compress_staged_layers(output_dir, count + 1, context)
+    }
+
+    fn build_lods_from(
+        output_dir: &Path,
+        mut count: u32,
+        context: JobContext,
+    ) -> Result<(), TileError> {
         let mut files: Vec<PathBuf>;
         while {
-            // get num files in dir
-            let dirs = fs::read_dir(output_dir.join(count.to_string())).unwrap();
-            files = dirs.map(|dir| dir.unwrap().path()).collect();
+            context.cancel.check()?;
+
+            // get num tiles in dir
+            files = dir_paths(&output_dir.join(count.to_string()))?
+                .into_iter()
+                .filter(|path| context.writer.tile_coords(path).is_some())
+                .collect();
             files.len() > 4
         } {
             count += 1;
-            shrink_tiles(files, &output_dir.join(count.to_string()));
+            if context
+                .journal
+                .is_some_and(|journal| journal.is_level_done(count))
+            {
+                continue;
+            }
+
+            shrink_tiles(files, &output_dir.join(count.to_string()), count, context)?;
+
+            if let Some(journal) = context.journal {
+                journal.record_level(count)?;
+            }
         }
+        Ok(())
     }
 
-    pub fn sector_at_pos(x: f32, y: f32, tile_dimensions: (f32, f32)) -> (i32, i32) {
-        let two: f32 = 2.0;
-        let lod = 0;
-        // let screen_point_coords = screen_pos_to_coord(x, y, camera);
+    /// Returns the writer tiles are staged with while a pyramid is built, if `writer` block
+    /// compresses them. Staged tiles are lossless PNG, so that LOD layers and gutters are made
+    /// from pixels that were never compressed, and are compressed once by
+    /// `compress_staged_tiles` when the pyramid is done.
+    pub(crate) fn staging_writer(writer: &TileWriter) -> Option<TileWriter> {
+        matches!(writer.format, TileFormat::Ktx2 | TileFormat::Dds).then(|| TileWriter {
+            format: TileFormat::Png,
+            ..writer.clone()
+        })
+    }
+
+    /// Writes the PNG tiles staged directly inside a directory in the format of the writer of
+    /// `context`, and removes them. A job that is interrupted while compressing compresses the
+    /// rest when it is resumed.
+    pub(crate) fn compress_staged_tiles(dir: &Path, context: JobContext) -> Result<(), TileError> {
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        context
+            .writer
+            .remove_temp_files(dir)
+            .map_err(TileError::io(dir))?;
+
+        let staged_tiles: Vec<((i32, i32), PathBuf)> = dir_paths(dir)?
+            .into_iter()
+            .filter(|path| path.extension() == Some(TileFormat::Png.extension().as_ref()))
+            .filter_map(|path| Some((context.writer.tile_coords(&path)?, path)))
+            .collect();
+        let writer = context
+            .writer
+            .at_zoom_of(staged_tiles.iter().map(|(_, path)| path));
+
+        context.progress.message("compressing tiles...");
+        staged_tiles.par_iter().try_for_each(|((x, y), path)| {
+            context.cancel.check()?;
+
+            let tile = open_tile(path).map_err(TileError::image(path))?;
+            let compressed_path = dir.join(writer.tile_file_name(*x, *y));
+            writer
+                .write(&tile, &compressed_path)
+                .map_err(TileError::image(&compressed_path))?;
+            fs::remove_file(path).map_err(TileError::io(path))
+        })
+    }
 
-        let screen_point_coords = (x, y);
+    /// Compresses the staged tiles of every LOD layer of `output_dir`, from layer `level` up.
+    fn compress_staged_layers(
+        output_dir: &Path,
+        mut level: u32,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        while output_dir.join(level.to_string()).is_dir() {
+            compress_staged_tiles(&output_dir.join(level.to_string()), context)?;
+            level += 1;
+        }
+        Ok(())
+    }
 
-        // get sector x
-        let tile_world_x_size = tile_dimensions.0 as f32 * two.powf(lod as f32);
-        let screen_point_sector_x = if screen_point_coords.0 < 0.0 {
-            (screen_point_coords.0 / tile_world_x_size) as i32 - 1
-        } else {
-            (screen_point_coords.0 / tile_world_x_size) as i32
-        };
+    /// Returns the tiles an image overlaps, when its top left pixel is at `position` in tile
+    /// pixel space, or an error if their coordinates don't fit in an `i32`.
+    pub(crate) fn overlapped_tiles(
+        position: (i32, i32),
+        image_dimensions: (u32, u32),
+        tile_dimensions: (u32, u32),
+    ) -> Result<Vec<(i32, i32)>, TileError> {
+        let (top_left_sector, bottom_right_sector) = tile_range(
+            (position.0 as i64, position.1 as i64),
+            (
+                position.0 as i64 + image_dimensions.0 as i64 - 1,
+                position.1 as i64 + image_dimensions.1 as i64 - 1,
+            ),
+            tile_dimensions,
+            0,
+        )
+        .ok_or(TileError::TileOutOfRange)?;
+
+        Ok((top_left_sector.1..=bottom_right_sector.1)
+            .flat_map(|sector_y| {
+                (top_left_sector.0..=bottom_right_sector.0)
+                    .map(move |sector_x| (sector_x, sector_y))
+            })
+            .collect())
+    }
 
-        // get sector y
-        let tile_world_y_size = tile_dimensions.1 as f32 * two.powf(lod as f32);
-        let screen_point_sector_y = if screen_point_coords.1 < 0.0 {
-            (screen_point_coords.1 / tile_world_y_size) as i32 - 1
-        } else {
-            (screen_point_coords.1 / tile_world_y_size) as i32
-        };
+    /// Draws an image onto the tiles of `output_dir` it overlaps, creating tiles that don't exist
+    /// yet. `draw_pixel` combines a pixel of the image into the tile pixel below it. Existing
+    /// tiles are converted to the pixel type of the image.
+    ///
+    /// `position` is where the image's top left pixel is in tile pixel space. Tiles that end up
+    /// empty are removed. Reports a tile done for every overlapped tile, but doesn't start or
+    /// finish a level.
+    ///
+    /// Returns the coordinates of every overlapped tile, or an error if an existing tile can't be
+    /// read or doesn't have the dimensions `tile_dimensions`, or a tile can't be written.
+    pub(crate) fn draw_onto_tiles<P: TilePixel>(
+        image: &TileBuffer<P>,
+        position: (i32, i32),
+        output_dir: &Path,
+        tile_dimensions: (u32, u32),
+        draw_pixel: impl Fn(&mut P, P) + Sync,
+        context: JobContext,
+    ) -> Result<Vec<(i32, i32)>, TileError> {
+        let sectors = overlapped_tiles(position, image.dimensions(), tile_dimensions)?;
+        if let (Some(&top_left), Some(&bottom_right)) = (sectors.first(), sectors.last()) {
+            context.writer.check_tile_names(top_left, bottom_right)?;
+        }
+
+        sectors.par_iter().try_for_each(|&(sector_x, sector_y)| {
+            context.cancel.check()?;
+
+            let tile_path = output_dir.join(context.writer.tile_file_name(sector_x, sector_y));
+            let mut tile_image = if tile_path.is_file() {
+                let tile_image = open_tile(&tile_path).map_err(TileError::image(&tile_path))?;
+                if tile_image.dimensions() != tile_dimensions {
+                    return Err(TileError::DimensionMismatch {
+                        path: tile_path,
+                        expected: tile_dimensions,
+                        found: tile_image.dimensions(),
+                    });
+                }
+                P::from_dynamic(tile_image)
+            } else {
+                blank_tile::<P>(tile_dimensions.0, tile_dimensions.1)
+            };
+
+            // for every pixel in the tile
+            let (origin_x, origin_y) =
+                tile_origin((sector_x, sector_y), tile_dimensions, 0).unwrap();
+            for y in 0..tile_dimensions.1 {
+                for x in 0..tile_dimensions.0 {
+                    // calculate where pixel is in the drawn image
+                    let image_x = origin_x + x as i64 - position.0 as i64;
+                    let image_y = origin_y + y as i64 - position.1 as i64;
+
+                    if image_x >= 0
+                        && image_x < image.width() as i64
+                        && image_y >= 0
+                        && image_y < image.height() as i64
+                    {
+                        draw_pixel(
+                            tile_image.get_pixel_mut(x, y),
+                            *image.get_pixel(image_x as u32, image_y as u32),
+                        );
+                    }
+                }
+            }
+
+            if tile_image.pixels().all(is_blank) {
+                if tile_path.is_file() {
+                    fs::remove_file(&tile_path).map_err(TileError::io(&tile_path))?;
+                }
+                context.progress.tile_done(0);
+            } else {
+                let bytes_written = context
+                    .writer
+                    .write(&P::into_dynamic(tile_image), &tile_path)
+                    .map_err(TileError::image(&tile_path))?;
+                context.progress.tile_done(bytes_written);
+            }
+            Ok(())
+        })?;
 
-        (screen_point_sector_x, screen_point_sector_y)
+        Ok(sectors)
     }
 
-    pub fn get_limit_sectors(
+    /// Overwrites the part of existing layer 0 tiles that a patch image covers.
+    ///
+    /// `patch_position` is the position of the patch in the source image the tiles were
+    /// generated from, and `x_offset` and `y_offset` are the offsets they were generated with.
+    /// Tiles that end up empty are removed.
+    ///
+    /// Returns the coordinates of every tile that was rewritten or removed. If the job is cancelled,
+    /// some of the tiles may already have been patched.
+    pub fn patch_tiles(
+        patch_path: &Path,
+        patch_position: (i32, i32),
         x_offset: i32,
         y_offset: i32,
-        tile_dimensions: (f32, f32),
-        image_dimensions: (f32, f32),
-    ) -> ((i32, i32), (i32, i32)) {
-        let top_left_sector =
-            sector_at_pos(0. - x_offset as f32, 0. - y_offset as f32, tile_dimensions);
-
-        let bottom_right_sector = sector_at_pos(
-            image_dimensions.0 - x_offset as f32,
-            image_dimensions.1 - y_offset as f32,
+        output_dir: &Path,
+        tile_dimensions: (u32, u32),
+        context: JobContext,
+    ) -> Result<Vec<(i32, i32)>, TileError> {
+        context
+            .writer
+            .remove_temp_files(output_dir)
+            .map_err(TileError::io(output_dir))?;
+
+        let patch = image::open(patch_path).map_err(TileError::image(patch_path))?;
+
+        // position of the patch in tile pixel space
+        let position = (patch_position.0 - x_offset, patch_position.1 - y_offset);
+
+        let overlapped = overlapped_tiles(position, patch.dimensions(), tile_dimensions)?;
+        context.progress.start_level(0, overlapped.len() as u64);
+
+        // patched tiles keep the zoom level of the existing ones
+        let writer = &context
+            .writer
+            .reaching(overlapped.last().copied().unwrap_or_default())
+            .at_zoom_of(dir_paths(output_dir)?);
+        let context = JobContext { writer, ..context };
+
+        // patched tiles keep the pixel type they were generated with
+        let color_type = match overlapped
+            .into_iter()
+            .map(|(x, y)| output_dir.join(context.writer.tile_file_name(x, y)))
+            .find(|path| path.is_file())
+        {
+            Some(path) => open_tile(&path).map_err(TileError::image(&path))?.color(),
+            None => patch.color(),
+        };
+
+        let sectors = with_pixel_type!(color_type, P => draw_onto_tiles(
+            &*buffer::<P>(&patch),
+            position,
+            output_dir,
             tile_dimensions,
-        );
+            |tile_pixel, patch_pixel| *tile_pixel = patch_pixel,
+            context,
+        ))?;
+
+        context.progress.finish_level();
 
-        (top_left_sector, bottom_right_sector)
+        Ok(sectors)
     }
 
-    pub fn gen_tiles_to_dir(gen_tiles_args: &GenTilesArgs) {
-        // get input image dimensions
-        let dimensions = Reader::open(&gen_tiles_args.input)
-            .unwrap()
+    /// Re-renders the LOD layer tiles above a set of changed layer 0 tiles, for every LOD layer
+    /// that already exists in `output_dir`.
+    ///
+    /// If the top layer ends up with more than 4 tiles, new LOD layers are generated above it.
+    pub fn update_lods(
+        output_dir: &Path,
+        changed_tiles: &[(i32, i32)],
+        tile_dimensions: (u32, u32),
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        let mut changed_tiles: HashSet<(i32, i32)> = changed_tiles.iter().copied().collect();
+
+        let mut count: u32 = 1;
+        while output_dir.join(count.to_string()).is_dir() && !changed_tiles.is_empty() {
+            let input_dir = output_dir.join((count - 1).to_string());
+            let level_dir = output_dir.join(count.to_string());
+            context
+                .writer
+                .remove_temp_files(&level_dir)
+                .map_err(TileError::io(&level_dir))?;
+            let input_writer = &context.writer.at_zoom_of(dir_paths(&input_dir)?);
+            let writer = &input_writer.above().at_zoom_of(dir_paths(&level_dir)?);
+
+            let parent_tiles: HashSet<(i32, i32)> = changed_tiles
+                .iter()
+                .map(|(x, y)| (x.div_euclid(2), y.div_euclid(2)))
+                .collect();
+
+            context
+                .progress
+                .start_level(count, parent_tiles.len() as u64);
+
+            parent_tiles
+                .par_iter()
+                .try_for_each(|&(parent_x, parent_y)| {
+                    context.cancel.check()?;
+
+                    let mut filenums_map = HashMap::new();
+                    for x in parent_x * 2..=parent_x * 2 + 1 {
+                        for y in parent_y * 2..=parent_y * 2 + 1 {
+                            let path = input_dir.join(input_writer.tile_file_name(x, y));
+                            if path.is_file() {
+                                filenums_map.insert((x, y), path);
+                            }
+                        }
+                    }
+
+                    if filenums_map.is_empty() {
+                        let parent_path = level_dir.join(writer.tile_file_name(parent_x, parent_y));
+                        if parent_path.is_file() {
+                            fs::remove_file(&parent_path).map_err(TileError::io(&parent_path))?;
+                        }
+                        context.progress.tile_done(0);
+                    } else {
+                        let bytes_written = shrink_tile(
+                            &filenums_map,
+                            tile_dimensions,
+                            parent_x,
+                            parent_y,
+                            &level_dir,
+                            writer,
+                        )?;
+                        context.progress.tile_done(bytes_written);
+                    }
+                    Ok(())
+                })?;
+
+            context.progress.finish_level();
+
+            changed_tiles = parent_tiles;
+            count += 1;
+        }
+
+        // the top layer may have grown
+        if !output_dir.join(count.to_string()).is_dir() {
+            generate_lods_from(output_dir, count - 1, context)?;
+        }
+        Ok(())
+    }
+
+    /// Slices the input image of `gen_tiles_args` into its output directory. An input with a
+    /// `.json` extension is read as a mosaic manifest.
+    ///
+    /// Without explicit offsets, an image with a world file or GeoTIFF tags is scaled to the
+    /// resolution of the world grid and placed in it, and a manifest of its world bounds is
+    /// written next to the tiles.
+    ///
+    /// With a journal, the output directory is not cleared, and tiles finished by a previous run
+    /// are kept.
+    ///
+    /// Block compressed tiles are staged uncompressed until their gutters are added.
+    pub fn gen_tiles_to_dir(
+        gen_tiles_args: &GenTilesArgs,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        gen_tiles_args.validate().map_err(TileError::InvalidArgs)?;
+        let staging_writer = staging_writer(context.writer);
+        let staged_context = staging_writer
+            .as_ref()
+            .map_or(context, |writer| JobContext { writer, ..context });
+
+        slice_source(
+            gen_tiles_args,
+            &gen_tiles_args.output,
+            &gen_tiles_args.output,
+            staged_context,
+        )?;
+
+        let tile_size = gen_tiles_args.tile_size();
+        add_gutters(
+            &gen_tiles_args.output,
+            tile_size,
+            gen_tiles_args.gutter,
+            0,
+            staged_context,
+        )?;
+        if staging_writer.is_some() {
+            compress_staged_tiles(&gen_tiles_args.output, context)?;
+        }
+
+        if let Some(float_output) = &gen_tiles_args.float_output {
+            let float_writer = TileWriter::new(TileFormat::Exr, context.writer.fsync)
+                .with_name_template(context.writer.name_template.clone());
+            add_gutters(
+                float_output,
+                tile_size,
+                gen_tiles_args.gutter,
+                0,
+                float_context(&float_writer, context),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Slices the input image of `gen_tiles_args` into the "0" directory of its output
+    /// directory, like `gen_tiles_to_dir`, and generates LOD layers above it, and above the
+    /// tiles of its float output directory.
+    ///
+    /// Block compressed tiles are staged uncompressed until every layer and gutter is done.
+    pub fn gen_tile_layers(
+        gen_tiles_args: &GenTilesArgs,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        gen_tiles_args.validate().map_err(TileError::InvalidArgs)?;
+        let staging_writer = staging_writer(context.writer);
+        let staged_context = staging_writer
+            .as_ref()
+            .map_or(context, |writer| JobContext { writer, ..context });
+
+        slice_source(
+            gen_tiles_args,
+            &gen_tiles_args.output.join("0"),
+            &gen_tiles_args.output,
+            staged_context,
+        )?;
+        generate_lods(&gen_tiles_args.output, staged_context)?;
+        add_layer_gutters(&gen_tiles_args.output, gen_tiles_args, staged_context)?;
+        if staging_writer.is_some() {
+            compress_staged_layers(&gen_tiles_args.output, 0, context)?;
+        }
+
+        if let Some(float_output) = &gen_tiles_args.float_output {
+            if float_output.join("0").is_dir() {
+                let float_writer = TileWriter::new(TileFormat::Exr, context.writer.fsync)
+                    .with_name_template(context.writer.name_template.clone());
+                let float_context = float_context(&float_writer, context);
+                generate_lods(float_output, float_context)?;
+                add_layer_gutters(float_output, gen_tiles_args, float_context)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the gutter of `gen_tiles_args` to the tiles of every LOD layer in `output_dir`, once
+    /// all of them are generated.
+    fn add_layer_gutters(
+        output_dir: &Path,
+        gen_tiles_args: &GenTilesArgs,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        let mut level = 0;
+        while output_dir.join(level.to_string()).is_dir() {
+            add_gutters(
+                &output_dir.join(level.to_string()),
+                gen_tiles_args.tile_size(),
+                gen_tiles_args.gutter,
+                level,
+                context,
+            )?;
+            level += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns a context like `context` that writes OpenEXR tiles, without a journal.
+    fn float_context<'a>(float_writer: &'a TileWriter, context: JobContext<'a>) -> JobContext<'a> {
+        JobContext {
+            writer: float_writer,
+            journal: None,
+            ..context
+        }
+    }
+
+    /// Reads the dimensions of an image without decoding it.
+    fn image_dimensions(path: &Path) -> Result<(u32, u32), TileError> {
+        Reader::open(path)
+            .map_err(TileError::io(path))?
             .into_dimensions()
+            .map_err(TileError::image(path))
+    }
+
+    /// Decodes the input image of `gen_tiles_args`, cut out with its mask if it has one.
+    fn open_source_image(
+        gen_tiles_args: &GenTilesArgs,
+        context: JobContext,
+    ) -> Result<DynamicImage, TileError> {
+        context.progress.message("decoding image...");
+        let source_image =
+            open_image(&gen_tiles_args.input).map_err(TileError::image(&gen_tiles_args.input))?;
+
+        match &gen_tiles_args.mask {
+            Some(mask) => {
+                context.progress.message("applying mask...");
+                apply_mask(source_image, mask)
+            }
+            None => Ok(source_image),
+        }
+    }
+
+    /// Makes the nodata pixels of a decoded source image transparent and crops it to its content,
+    /// if `gen_tiles_args` asks for it.
+    ///
+    /// Returns the image with the transform that keeps its pixels on the same tile pixels.
+    fn remove_margins(
+        source_image: DynamicImage,
+        transform: Transform,
+        gen_tiles_args: &GenTilesArgs,
+        context: JobContext,
+    ) -> (DynamicImage, Transform) {
+        let source_image = match gen_tiles_args.nodata {
+            Some(color) => {
+                context.progress.message("removing nodata pixels...");
+                nodata_to_alpha(source_image, color, gen_tiles_args.nodata_tolerance)
+            }
+            None => source_image,
+        };
+        if !gen_tiles_args.auto_crop {
+            return (source_image, transform);
+        }
+
+        context.progress.message("cropping image...");
+        match content_bounds(&source_image) {
+            Some((x, y, width, height)) if (width, height) != source_image.dimensions() => (
+                source_image.crop_imm(x, y, width, height),
+                transform.cropped(x, y, (width, height)),
+            ),
+            _ => (source_image, transform),
+        }
+    }
+
+    /// Slices a decoded source image into a prepared `output_dir`, without its nodata pixels and
+    /// margins and tone mapped if `gen_tiles_args` asks for it.
+    ///
+    /// With a float output directory, the image without tone mapping is also sliced into the
+    /// directory of it that matches `output_dir`. Without a journal of its own, it is sliced
+    /// completely.
+    fn slice_image(
+        source_image: DynamicImage,
+        transform: Transform,
+        offset: (i32, i32),
+        gen_tiles_args: &GenTilesArgs,
+        output_dir: &Path,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        let (source_image, transform) =
+            remove_margins(source_image, transform, gen_tiles_args, context);
+
+        if let Some(float_output) = &gen_tiles_args.float_output {
+            let float_writer = TileWriter::new(TileFormat::Exr, context.writer.fsync)
+                .with_name_template(context.writer.name_template.clone());
+            let float_context = float_context(&float_writer, context);
+            let float_dir =
+                float_output.join(output_dir.strip_prefix(&gen_tiles_args.output).unwrap());
+
+            prepare_output_dir(&float_dir, float_context)?;
+            tiles_from_image(
+                &source_image,
+                &transform,
+                offset.0,
+                offset.1,
+                &float_dir,
+                gen_tiles_args.tile_size(),
+                float_context,
+            )?;
+        }
+
+        let source_image = match gen_tiles_args.tone_map {
+            Some(tone_mapping) if is_float_image(&source_image) => {
+                context.progress.message("tone mapping image...");
+                tone_map(&source_image, tone_mapping, gen_tiles_args.exposure)
+            }
+            _ => source_image,
+        };
+
+        tiles_from_image(
+            &source_image,
+            &transform,
+            offset.0,
+            offset.1,
+            output_dir,
+            gen_tiles_args.tile_size(),
+            context,
+        )
+    }
+
+    /// Slices the input of `gen_tiles_args` into `output_dir`, and writes the manifest of a
+    /// georeferenced input to `manifest_dir`.
+    fn slice_source(
+        gen_tiles_args: &GenTilesArgs,
+        output_dir: &Path,
+        manifest_dir: &Path,
+        context: JobContext,
+    ) -> Result<(), TileError> {
+        let journal = context.journal;
+        if journal.is_some_and(|journal| journal.is_level_done(0)) {
+            return context
+                .writer
+                .remove_temp_files(output_dir)
+                .map_err(TileError::io(output_dir));
+        }
+
+        let clean_output_dir = || {
+            if journal.is_none() {
+                context.progress.message("cleaning dir...");
+                // like in `prepare_output_dir`, the output directory was cleaned before
+                clear_dir(output_dir, &context.writer.name_template)
+                    .map_err(TileError::io(output_dir))?;
+            }
+            Ok::<(), TileError>(())
+        };
+
+        let georeference = if is_manifest_path(&gen_tiles_args.input)
+            || gen_tiles_args.x_offset.is_some()
+            || gen_tiles_args.y_offset.is_some()
+            || gen_tiles_args.has_transforms()
+        {
+            None
+        } else {
+            GeoTransform::read(&gen_tiles_args.input)
+                .map_err(|err| TileError::InvalidGeoreference(err.to_string()))?
+        };
+
+        if is_manifest_path(&gen_tiles_args.input) {
+            let manifest = MosaicManifest::load(&gen_tiles_args.input)?;
+            clean_output_dir()?;
+
+            // the offsets of a mosaic shift all of its sources, which are already placed
+            mosaic_to_tiles(
+                &manifest,
+                gen_tiles_args.x_offset.unwrap_or(0),
+                gen_tiles_args.y_offset.unwrap_or(0),
+                output_dir,
+                gen_tiles_args.tile_size(),
+                context,
+            )?;
+        } else if let Some(transform) = georeference {
+            // get input image dimensions
+            let dimensions = image_dimensions(&gen_tiles_args.input)?;
+
+            let grid = WorldGrid {
+                resolution: gen_tiles_args.resolution.unwrap_or(transform.pixel_size.0),
+                origin: (gen_tiles_args.world_origin_x, gen_tiles_args.world_origin_y),
+            };
+            let placement = Placement::new(&transform, dimensions, &grid)?;
+
+            clean_output_dir()?;
+            prepare_output_dir(output_dir, context)?;
+
+            let mut source_image = open_source_image(gen_tiles_args, context)?;
+            if placement.dimensions != dimensions {
+                context.progress.message("scaling image...");
+                source_image = resize_image(
+                    &source_image,
+                    placement.dimensions.0,
+                    placement.dimensions.1,
+                    FilterType::Lanczos3,
+                );
+            }
+
+            // world files describe the stored pixels, so the EXIF orientation is not used
+            slice_image(
+                source_image,
+                Transform::identity(placement.dimensions),
+                (placement.x_offset, placement.y_offset),
+                gen_tiles_args,
+                output_dir,
+                context,
+            )?;
+
+            GeoManifest {
+                source: gen_tiles_args.input.clone(),
+                source_transform: transform,
+                grid,
+                tile_dimensions: gen_tiles_args.tile_size(),
+                gutter: gen_tiles_args.gutter,
+                placement,
+                world_bounds: placement.world_bounds(&grid),
+            }
+            .write(manifest_dir)
+            .map_err(TileError::io(&manifest_dir.join(MANIFEST_FILE_NAME)))?;
+        } else {
+            // get input image dimensions
+            let dimensions = image_dimensions(&gen_tiles_args.input)?;
+            let transform = Transform::from_args(gen_tiles_args, dimensions);
+            let dimensions = transform.dimensions();
+            clean_output_dir()?;
+            prepare_output_dir(output_dir, context)?;
+
+            let source_image = open_source_image(gen_tiles_args, context)?;
+
+            slice_image(
+                source_image,
+                transform,
+                (
+                    gen_tiles_args
+                        .x_offset
+                        .unwrap_or_else(|| (dimensions.0 / 2).try_into().unwrap()),
+                    gen_tiles_args
+                        .y_offset
+                        .unwrap_or_else(|| (dimensions.1 / 2).try_into().unwrap()),
+                ),
+                gen_tiles_args,
+                output_dir,
+                context,
+            )?;
+        }
+
+        if let Some(journal) = journal {
+            journal.record_level(0)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use clap::Parser;
+        use image::{ImageBuffer, Luma, LumaA, Rgb, Rgba, RgbaImage};
+
+        use super::*;
+        use crate::progress::ProgressEvent;
+
+        fn touch(path: &Path) {
+            fs::write(path, b"").unwrap();
+        }
+
+        /// Writes a 64x64 image with a different color in every pixel.
+        fn write_source_image(path: &Path) {
+            RgbaImage::from_fn(64, 64, |x, y| Rgba([x as u8 * 4, y as u8 * 4, 128, 255]))
+                .save(path)
+                .unwrap();
+        }
+
+        fn gen_tiles_args(dir: &Path, extra: &[&str]) -> GenTilesArgs {
+            let input = dir.join("in.png");
+            let output = dir.join("out");
+            let args = [
+                "gen-tiles",
+                "-i",
+                input.to_str().unwrap(),
+                "-o",
+                output.to_str().unwrap(),
+                "--tile-dimensions",
+                "32",
+                "--x-offset",
+                "0",
+                "--y-offset",
+                "0",
+            ];
+            GenTilesArgs::parse_from(args.iter().chain(extra))
+        }
+
+        fn run_gen_tiles(args: &GenTilesArgs) -> Result<(), TileError> {
+            let journal = start_journal("gen-tiles", args).unwrap();
+            let context = JobContext {
+                writer: &TileWriter::new(args.format, false)
+                    .with_texture(args.block_compression, args.mipmaps)
+                    .with_name_template(args.name_template.clone()),
+                journal: Some(&journal),
+                progress: &Progress::none(),
+                cancel: &CancelToken::new(),
+            };
+            gen_tiles_to_dir(args, context)
+        }
+
+        #[test]
+        fn resume_keeps_finished_tiles_and_redoes_the_others() {
+            let temp = tempfile::tempdir().unwrap();
+            write_source_image(&temp.path().join("in.png"));
+            let args = gen_tiles_args(temp.path(), &[]);
+            run_gen_tiles(&args).unwrap();
+
+            let output = &args.output;
+            let tile = |name: &str| image::open(output.join(name)).unwrap().to_rgba8();
+            let expected_tile = tile("1,1.png");
+            assert_eq!(*expected_tile.get_pixel(0, 0), Rgba([128, 128, 128, 255]));
+
+            // as if the run was interrupted after writing tile 0,0, with a tile written after
+            // it left over
+            let journal_path = output.join(JOURNAL_FILE_NAME);
+            let journal = fs::read_to_string(&journal_path).unwrap();
+            let job = journal.lines().next().unwrap();
+            fs::write(
+                &journal_path,
+                format!(
+                    "{}
+tile 0 0,0
+",
+                    job
+                ),
+            )
             .unwrap();
+            let finished_tile = RgbaImage::from_pixel(32, 32, Rgba([255, 0, 0, 255]));
+            finished_tile.save(output.join("0,0.png")).unwrap();
+            fs::remove_file(output.join("1,0.png")).unwrap();
+            RgbaImage::new(32, 32).save(output.join("1,1.png")).unwrap();
+
+            let resume_args = gen_tiles_args(temp.path(), &["--resume"]);
+            run_gen_tiles(&resume_args).unwrap();
+            assert_eq!(tile("0,0.png"), finished_tile);
+            assert!(output.join("1,0.png").is_file());
+            assert_eq!(tile("1,1.png"), expected_tile);
+        }
 
-        println!("cleaning dir...");
-        clean_dir(&gen_tiles_args.output);
-
-        image_to_tiles(
-            &gen_tiles_args.input,
-            gen_tiles_args
-                .x_offset
-                .unwrap_or_else(|| (dimensions.0 / 2).try_into().unwrap()),
-            gen_tiles_args
-                .y_offset
-                .unwrap_or_else(|| (dimensions.1 / 2).try_into().unwrap()),
-            &gen_tiles_args.output,
-            gen_tiles_args.tile_dimensions,
-        );
+        #[test]
+        fn resume_redoes_finished_tiles_that_were_deleted() {
+            let temp = tempfile::tempdir().unwrap();
+            write_source_image(&temp.path().join("in.png"));
+            let args = gen_tiles_args(temp.path(), &[]);
+            run_gen_tiles(&args).unwrap();
+
+            let output = &args.output;
+            let expected_tile = fs::read(output.join("1,1.png")).unwrap();
+
+            // as if the run was interrupted after writing tile 1,1, which was deleted since
+            let journal_path = output.join(JOURNAL_FILE_NAME);
+            let journal = fs::read_to_string(&journal_path).unwrap();
+            let job = journal.lines().next().unwrap();
+            fs::write(&journal_path, format!("{}\ntile 0 1,1\n", job)).unwrap();
+            fs::remove_file(output.join("1,1.png")).unwrap();
+
+            let resume_args = gen_tiles_args(temp.path(), &["--resume"]);
+            run_gen_tiles(&resume_args).unwrap();
+            assert_eq!(fs::read(output.join("1,1.png")).unwrap(), expected_tile);
+        }
+
+        #[test]
+        fn resume_rejects_a_different_job() {
+            let temp = tempfile::tempdir().unwrap();
+            write_source_image(&temp.path().join("in.png"));
+            run_gen_tiles(&gen_tiles_args(temp.path(), &[])).unwrap();
+
+            let changes: [&[&str]; 3] = [
+                &["--gutter", "2"],
+                &["--block-compression", "bc1"],
+                &["--mipmaps"],
+            ];
+            for extra in changes {
+                let args = gen_tiles_args(temp.path(), &[&["--resume"], extra].concat());
+                let err = start_journal("gen-tiles", &args).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", extra);
+            }
+        }
+
+        #[test]
+        fn block_compressed_layers_are_shrunk_from_uncompressed_layers() {
+            let temp = tempfile::tempdir().unwrap();
+            RgbaImage::from_fn(200, 120, |x, y| {
+                Rgba([
+                    (x * 7 % 256) as u8,
+                    (y * 13 % 256) as u8,
+                    (x * y % 256) as u8,
+                    255,
+                ])
+            })
+            .save(temp.path().join("in.png"))
+            .unwrap();
+
+            let generate = |format: &str| {
+                let mut args = gen_tiles_args(temp.path(), &["--format", format, "--gutter", "2"]);
+                args.output = temp.path().join(format);
+                let context = JobContext {
+                    writer: &TileWriter::new(args.format, false)
+                        .with_name_template(args.name_template.clone()),
+                    journal: None,
+                    progress: &Progress::none(),
+                    cancel: &CancelToken::new(),
+                };
+                gen_tile_layers(&args, context).unwrap();
+                args.output
+            };
+            let png_dir = generate("png");
+            let ktx2_dir = generate("ktx2");
+            // only output directories cleaned by `clean_dir` are marked, not their LOD layers
+            assert!(!png_dir.join("0").join(OUTPUT_MARKER_FILE_NAME).exists());
+
+            // every tile is compressed once, from the tile an uncompressed pyramid has
+            let ktx2_writer = TileWriter::new(TileFormat::Ktx2, false);
+            let mut tiles = 0;
+            for level in ["0", "1", "2"] {
+                for entry in fs::read_dir(png_dir.join(level)).unwrap() {
+                    let png_path = entry.unwrap().path();
+                    let Some((x, y)) = ktx2_writer.tile_coords(&png_path) else {
+                        continue;
+                    };
+                    let name = ktx2_writer.tile_file_name(x, y);
+
+                    let expected_path = temp.path().join(&name);
+                    ktx2_writer
+                        .write(&open_tile(&png_path).unwrap(), &expected_path)
+                        .unwrap();
+                    assert_eq!(
+                        open_tile(&ktx2_dir.join(level).join(&name)).unwrap(),
+                        open_tile(&expected_path).unwrap(),
+                        "{}/{}",
+                        level,
+                        name
+                    );
+                    tiles += 1;
+                }
+            }
+            assert!(tiles > 4);
+
+            let staged_tiles = fs::read_dir(ktx2_dir.join("0"))
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap() == "png")
+                .count();
+            assert_eq!(staged_tiles, 0);
+        }
+
+        #[test]
+        fn patching_matches_regenerating_the_patched_image() {
+            let temp = tempfile::tempdir().unwrap();
+            let original = RgbaImage::from_fn(160, 96, |x, y| {
+                Rgba([(x * 3 % 256) as u8, (y * 5 % 256) as u8, 200, 255])
+            });
+            // the patch covers tile 2,1 with transparent pixels, which removes it
+            let (patch_x, patch_y) = (60, 28);
+            let patch = RgbaImage::from_fn(40, 40, |x, y| {
+                let (x, y) = (x + patch_x, y + patch_y);
+                if (64..96).contains(&x) && (32..64).contains(&y) {
+                    Rgba([0; 4])
+                } else {
+                    Rgba([255 - x as u8, 0, y as u8, 255])
+                }
+            });
+            let patch_path = temp.path().join("patch.png");
+            patch.save(&patch_path).unwrap();
+            let mut patched = original.clone();
+            image::imageops::replace(&mut patched, &patch, patch_x as i64, patch_y as i64);
+
+            let writer = TileWriter::new(TileFormat::Png, false);
+            let context = JobContext {
+                writer: &writer,
+                journal: None,
+                progress: &Progress::none(),
+                cancel: &CancelToken::new(),
+            };
+            let generate = |image: &RgbaImage, name: &str| {
+                let mut args = gen_tiles_args(temp.path(), &[]);
+                args.input = temp.path().join(format!("{}.png", name));
+                args.output = temp.path().join(name);
+                image.save(&args.input).unwrap();
+                gen_tile_layers(&args, context).unwrap();
+                args
+            };
+
+            let args = generate(&original, "updated");
+            let changed_tiles = patch_tiles(
+                &patch_path,
+                (patch_x as i32, patch_y as i32),
+                0,
+                0,
+                &args.output.join("0"),
+                args.tile_size(),
+                context,
+            )
+            .unwrap();
+            update_lods(&args.output, &changed_tiles, args.tile_size(), context).unwrap();
+            let regenerated_args = generate(&patched, "regenerated");
+
+            let tiles = |dir: &Path| {
+                let mut tiles: Vec<PathBuf> = fs::read_dir(dir)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().path())
+                    .filter(|path| writer.tile_coords(path).is_some())
+                    .collect();
+                tiles.sort();
+                tiles
+            };
+            assert!(!args.output.join("0").join("2,1.png").exists());
+            for level in ["0", "1", "2"] {
+                let updated = tiles(&args.output.join(level));
+                let regenerated = tiles(&regenerated_args.output.join(level));
+                assert_eq!(
+                    updated
+                        .iter()
+                        .map(|path| path.file_name())
+                        .collect::<Vec<_>>(),
+                    regenerated
+                        .iter()
+                        .map(|path| path.file_name())
+                        .collect::<Vec<_>>(),
+                    "level {}",
+                    level
+                );
+                for (updated, regenerated) in updated.iter().zip(&regenerated) {
+                    assert!(
+                        open_tile(updated).unwrap() == open_tile(regenerated).unwrap(),
+                        "{} differs from the regenerated tile",
+                        updated.display()
+                    );
+                }
+            }
+            assert!(!args.output.join("3").exists());
+        }
+
+        #[test]
+        fn patch_tiles_reports_unreadable_patches() {
+            let temp = tempfile::tempdir().unwrap();
+            let patch_path = temp.path().join("patch.png");
+            fs::write(&patch_path, b"not a png").unwrap();
+
+            let context = JobContext {
+                writer: &TileWriter::new(TileFormat::Png, false),
+                journal: None,
+                progress: &Progress::none(),
+                cancel: &CancelToken::new(),
+            };
+            match patch_tiles(&patch_path, (0, 0), 0, 0, temp.path(), (32, 32), context) {
+                Err(TileError::Image { path, .. }) => assert_eq!(path, patch_path),
+                result => panic!("expected an image error, got {:?}", result),
+            }
+        }
+
+        /// Returns the sorted file names of the tiles directly inside a directory.
+        fn tile_names(dir: &Path) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.ends_with(".png"))
+                .collect();
+            names.sort();
+            names
+        }
+
+        #[test]
+        fn quadkey_layers_have_one_digit_less_per_level() {
+            let temp = tempfile::tempdir().unwrap();
+            RgbaImage::from_pixel(120, 60, Rgba([0, 128, 255, 255]))
+                .save(temp.path().join("in.png"))
+                .unwrap();
+            let args = gen_tiles_args(temp.path(), &["--name-template", "{quadkey}.{ext}"]);
+            let context = JobContext {
+                writer: &TileWriter::new(args.format, false)
+                    .with_name_template(args.name_template.clone()),
+                journal: None,
+                progress: &Progress::none(),
+                cancel: &CancelToken::new(),
+            };
+            gen_tile_layers(&args, context).unwrap();
+
+            // 4x2 tiles need zoom level 2
+            assert_eq!(
+                tile_names(&args.output.join("0")),
+                ["00", "01", "02", "03", "10", "11", "12", "13"].map(|key| format!("{}.png", key))
+            );
+            assert_eq!(tile_names(&args.output.join("1")), ["0.png", "1.png"]);
+        }
+
+        #[test]
+        fn layers_above_bing_tiles_keep_their_leading_zeros() {
+            let temp = tempfile::tempdir().unwrap();
+            let level_dir = temp.path().join("0");
+            fs::create_dir(&level_dir).unwrap();
+            let writer = TileWriter::new(TileFormat::Png, false)
+                .with_name_template("{quadkey}.{ext}".parse().unwrap())
+                .with_zoom(4);
+            for x in 2..=4 {
+                for y in 6..=7 {
+                    RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]))
+                        .save(level_dir.join(writer.tile_file_name(x, y)))
+                        .unwrap();
+                }
+            }
+            assert!(level_dir.join("0231.png").is_file());
+
+            let context = JobContext {
+                writer: &writer.clone().with_zoom(0),
+                journal: None,
+                progress: &Progress::none(),
+                cancel: &CancelToken::new(),
+            };
+            generate_lods(temp.path(), context).unwrap();
+            assert_eq!(tile_names(&temp.path().join("1")), ["023.png", "032.png"]);
+        }
+
+        #[test]
+        fn tiles_and_lods_keep_the_pixel_format_of_their_source() {
+            let sources = [
+                (
+                    DynamicImage::ImageLuma8(ImageBuffer::from_fn(48, 40, |x, y| {
+                        Luma([(x * 5 + y) as u8])
+                    })),
+                    TileFormat::Png,
+                ),
+                (
+                    DynamicImage::ImageLuma16(ImageBuffer::from_fn(48, 40, |x, y| {
+                        Luma([(x * 1000 + y * 7) as u16])
+                    })),
+                    TileFormat::Png,
+                ),
+                (
+                    DynamicImage::ImageLumaA8(ImageBuffer::from_fn(48, 40, |x, y| {
+                        LumaA([(x * 5) as u8, (200 + y) as u8])
+                    })),
+                    TileFormat::Png,
+                ),
+                (
+                    DynamicImage::ImageRgb16(ImageBuffer::from_fn(48, 40, |x, y| {
+                        Rgb([(x * 1000) as u16, (y * 1000) as u16, 65535])
+                    })),
+                    TileFormat::Png,
+                ),
+                (
+                    DynamicImage::ImageRgba32F(ImageBuffer::from_fn(48, 40, |x, y| {
+                        Rgba([x as f32 / 48.0, y as f32 / 40.0, 0.5, 1.0])
+                    })),
+                    TileFormat::Exr,
+                ),
+            ];
+
+            for (source, format) in sources {
+                let temp = tempfile::tempdir().unwrap();
+                let source_path = temp.path().join(format!("in.{}", format.extension()));
+                source.save(&source_path).unwrap();
+                let output = temp.path().join("out");
+                let writer = TileWriter::new(format, false);
+                let context = JobContext {
+                    writer: &writer,
+                    journal: None,
+                    progress: &Progress::none(),
+                    cancel: &CancelToken::new(),
+                };
+                image_to_tiles(&source_path, 0, 0, &output.join("0"), (16, 16), context).unwrap();
+                generate_lods(&output, context).unwrap();
+
+                let tile = |level: &str, x: i32, y: i32| {
+                    open_tile(&output.join(level).join(writer.tile_file_name(x, y))).unwrap()
+                };
+                let color = source.color();
+                for (level, tiles) in [("0", 9), ("1", 4)] {
+                    let paths = dir_paths(&output.join(level)).unwrap();
+                    assert_eq!(paths.len(), tiles, "{:?} level {}", color, level);
+                    for path in paths {
+                        assert_eq!(open_tile(&path).unwrap().color(), color, "{:?}", path);
+                    }
+                }
+
+                assert!(
+                    tile("0", 0, 0) == source.crop_imm(0, 0, 16, 16),
+                    "{:?}",
+                    color
+                );
+                // the bottom edge tile is filled with zeros below the image, which is
+                // transparent black, or opaque black without alpha
+                let is_filled =
+                    |image: DynamicImage| image.as_bytes().iter().all(|byte| *byte == 0);
+                let edge_tile = tile("0", 2, 2);
+                assert!(
+                    edge_tile.crop_imm(0, 0, 16, 8) == source.crop_imm(32, 32, 16, 8),
+                    "{:?}",
+                    color
+                );
+                assert!(is_filled(edge_tile.crop_imm(0, 8, 16, 8)), "{:?}", color);
+                // and so is the part of a LOD tile without tiles below it
+                assert!(
+                    is_filled(tile("1", 1, 1).crop_imm(15, 15, 1, 1)),
+                    "{:?}",
+                    color
+                );
+            }
+        }
+
+        #[test]
+        fn auto_cropped_tiles_keep_their_coordinates() {
+            let temp = tempfile::tempdir().unwrap();
+            RgbaImage::from_fn(64, 64, |x, y| {
+                if (20..50).contains(&x) && (36..60).contains(&y) {
+                    Rgba([x as u8, y as u8, 0, 255])
+                } else {
+                    Rgba([255; 4])
+                }
+            })
+            .save(temp.path().join("in.png"))
+            .unwrap();
+
+            let generate = |extra: &[&str], name: &str| {
+                let mut args = gen_tiles_args(temp.path(), extra);
+                args.output = temp.path().join(name);
+                run_gen_tiles(&args).unwrap();
+                args.output
+            };
+            let uncropped = generate(&["--nodata", "white"], "uncropped");
+            let cropped = generate(&["--nodata", "white", "--auto-crop"], "cropped");
+
+            let names = tile_names(&cropped);
+            assert_eq!(names, ["0,1.png", "1,1.png"]);
+            assert_eq!(names, tile_names(&uncropped));
+            for name in names {
+                assert!(
+                    open_tile(&cropped.join(&name)).unwrap()
+                        == open_tile(&uncropped.join(&name)).unwrap(),
+                    "{}",
+                    name
+                );
+            }
+        }
+
+        #[test]
+        fn progress_counts_the_tiles_of_every_level() {
+            let temp = tempfile::tempdir().unwrap();
+            let input = temp.path().join("in.png");
+            write_source_image(&input);
+            let output = temp.path().join("out");
+
+            let writer = TileWriter::new(TileFormat::Png, false);
+            let (progress, events) = Progress::channel();
+            let context = JobContext {
+                writer: &writer,
+                journal: None,
+                progress: &progress,
+                cancel: &CancelToken::new(),
+            };
+            image_to_tiles(&input, 0, 0, &output.join("0"), (16, 16), context).unwrap();
+            generate_lods(&output, context).unwrap();
+
+            // the level, total tiles and tiles done of every level started
+            let mut levels: Vec<(u32, u64, u64)> = Vec::new();
+            for event in events.try_iter() {
+                match event {
+                    ProgressEvent::Message { .. } => {}
+                    ProgressEvent::LevelStarted { level, total_tiles } => {
+                        levels.push((level, total_tiles, 0))
+                    }
+                    ProgressEvent::TileDone {
+                        level,
+                        tiles_done,
+                        total_tiles,
+                        ..
+                    } => {
+                        let (current_level, total, done) = levels.last_mut().unwrap();
+                        assert_eq!((level, total_tiles), (*current_level, *total));
+                        *done += 1;
+                        assert_eq!(tiles_done, *done);
+                    }
+                    ProgressEvent::LevelFinished {
+                        level,
+                        tiles_done,
+                        bytes_written,
+                    } => {
+                        let (current_level, total, done) = *levels.last().unwrap();
+                        assert_eq!((level, tiles_done, done), (current_level, total, total));
+
+                        let tile_bytes: u64 = dir_paths(&output.join(level.to_string()))
+                            .unwrap()
+                            .iter()
+                            .filter(|path| writer.tile_coords(path).is_some())
+                            .map(|path| fs::metadata(path).unwrap().len())
+                            .sum();
+                        assert_eq!(bytes_written, tile_bytes);
+                    }
+                }
+            }
+            // the edge pixels reach a 5th row and column of tiles, which are empty
+            assert_eq!(levels, [(0, 25, 25), (1, 4, 4)]);
+        }
+
+        #[test]
+        fn cancelling_stops_slicing_with_the_cancellation_error() {
+            let temp = tempfile::tempdir().unwrap();
+            let input = temp.path().join("in.png");
+            write_source_image(&input);
+            let output = temp.path().join("out");
+
+            // cancel as soon as the first tile is done
+            let cancel = CancelToken::new();
+            let progress = Progress::new({
+                let cancel = cancel.clone();
+                move |event| {
+                    if let ProgressEvent::TileDone { .. } = event {
+                        cancel.cancel();
+                    }
+                }
+            });
+            let context = JobContext {
+                writer: &TileWriter::new(TileFormat::Png, false),
+                journal: None,
+                progress: &progress,
+                cancel: &cancel,
+            };
+            match image_to_tiles(&input, 0, 0, &output.join("0"), (16, 16), context) {
+                Err(TileError::Cancelled) => {}
+                result => panic!("expected the job to be cancelled, got {:?}", result),
+            }
+
+            // and the LOD layers are not started
+            match generate_lods(&output, context) {
+                Err(TileError::Cancelled) => {}
+                result => panic!("expected the job to be cancelled, got {:?}", result),
+            }
+            assert!(!output.join("1").exists());
+        }
+
+        #[test]
+        fn library_entry_points_validate_their_args() {
+            let temp = tempfile::tempdir().unwrap();
+            let args = gen_tiles_args(temp.path(), &["--gutter", "33"]);
+            let context = JobContext {
+                writer: &TileWriter::new(args.format, false),
+                journal: None,
+                progress: &Progress::none(),
+                cancel: &CancelToken::new(),
+            };
+
+            assert!(matches!(
+                gen_tiles_to_dir(&args, context),
+                Err(TileError::InvalidArgs(_))
+            ));
+            assert!(matches!(
+                gen_tile_layers(&args, context),
+                Err(TileError::InvalidArgs(_))
+            ));
+            assert!(!args.output.exists());
+        }
+
+        #[test]
+        fn clean_dir_creates_and_marks_a_new_dir() {
+            let temp = tempfile::tempdir().unwrap();
+            let dir = temp.path().join("tiles");
+
+            clean_dir(&dir, false, &NameTemplate::default()).unwrap();
+            assert!(dir.join(OUTPUT_MARKER_FILE_NAME).is_file());
+
+            // and clears it again on the next run
+            touch(&dir.join("0,0.png"));
+            clean_dir(&dir, false, &NameTemplate::default()).unwrap();
+            assert!(!dir.join("0,0.png").exists());
+        }
+
+        #[test]
+        fn clean_dir_refuses_unmarked_dirs() {
+            let temp = tempfile::tempdir().unwrap();
+            let dir = temp.path();
+            // a third party tile set, and files that only look like tiles
+            for name in ["0,0.png", "1,2.txt", "3,4.csv"] {
+                touch(&dir.join(name));
+            }
+
+            let err = clean_dir(dir, false, &NameTemplate::default()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            for name in ["0,0.png", "1,2.txt", "3,4.csv"] {
+                assert!(dir.join(name).is_file(), "{}", name);
+            }
+            assert!(!dir.join(OUTPUT_MARKER_FILE_NAME).exists());
+        }
+
+        #[test]
+        fn clean_dir_refuses_unmarked_dirs_with_only_foreign_files() {
+            let temp = tempfile::tempdir().unwrap();
+            touch(&temp.path().join("notes.md"));
+
+            assert!(clean_dir(temp.path(), false, &NameTemplate::default()).is_err());
+            assert!(temp.path().join("notes.md").is_file());
+        }
+
+        #[test]
+        fn clean_dir_only_deletes_tiles_of_marked_dirs() {
+            let temp = tempfile::tempdir().unwrap();
+            let dir = temp.path().join("tiles");
+            let template: NameTemplate = "{quadkey}.{ext}".parse().unwrap();
+            clean_dir(&dir, false, &template).unwrap();
+
+            fs::create_dir(dir.join("0")).unwrap();
+            fs::create_dir(dir.join("docs")).unwrap();
+            for name in [
+                "0/0.png",
+                "0/12.ktx2",
+                "2023.csv",
+                "1,2.txt",
+                "docs/3.png",
+                "13.jpg",
+            ] {
+                touch(&dir.join(name));
+            }
+
+            clean_dir(&dir, false, &template).unwrap();
+            for name in ["0", "0/0.png", "0/12.ktx2", "13.jpg"] {
+                assert!(!dir.join(name).exists(), "{}", name);
+            }
+            for name in ["2023.csv", "1,2.txt", "docs/3.png"] {
+                assert!(dir.join(name).is_file(), "{}", name);
+            }
+        }
+
+        #[test]
+        fn clean_dir_with_force_only_deletes_tiles() {
+            let temp = tempfile::tempdir().unwrap();
+            let dir = temp.path();
+            for name in ["0,0.png", "-1,5.jpg", "1,2.txt", "notes.md"] {
+                touch(&dir.join(name));
+            }
+
+            clean_dir(dir, true, &NameTemplate::default()).unwrap();
+            assert!(!dir.join("0,0.png").exists());
+            assert!(!dir.join("-1,5.jpg").exists());
+            assert!(dir.join("1,2.txt").is_file());
+            assert!(dir.join("notes.md").is_file());
+            assert!(dir.join(OUTPUT_MARKER_FILE_NAME).is_file());
+        }
+
+        #[test]
+        fn clean_dir_clears_dirs_with_a_journal() {
+            let temp = tempfile::tempdir().unwrap();
+            let dir = temp.path();
+            Journal::create(dir, "gen-tiles").unwrap();
+            touch(&dir.join("0,0.png"));
+
+            clean_dir(dir, false, &NameTemplate::default()).unwrap();
+            assert!(!dir.join("0,0.png").exists());
+            assert!(!dir.join(JOURNAL_FILE_NAME).exists());
+        }
+
+        #[test]
+        fn clean_dir_rejects_files() {
+            let temp = tempfile::tempdir().unwrap();
+            let path = temp.path().join("0,0.png");
+            touch(&path);
+
+            assert!(clean_dir(&path, true, &NameTemplate::default()).is_err());
+            assert!(path.is_file());
+        }
     }
 }
@@ -1,22 +1,35 @@
-use std::{fs, path::Path};
+use std::{fs, path::Path, sync::Mutex, time::Duration};
 
 use colored::Colorize;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
 
 use tileproc::args::*;
+use tileproc::atlas::pack_atlas;
+use tileproc::batch::{run_jobs, BatchEvent, BatchReport, JobFile, JobStatus};
+use tileproc::cancel::CancelToken;
+use tileproc::coords::{PixelInfo, TileInfo};
+use tileproc::journal::Journal;
+use tileproc::mercator::gen_web_mercator_tiles;
+use tileproc::merge::{merge_pyramids, merge_tiles};
+use tileproc::naming::NameTemplate;
+use tileproc::progress::{Progress, ProgressEvent};
+use tileproc::terrain::gen_terrain_tiles;
 use tileproc::tiler::*;
+use tileproc::validate::validate_pyramid;
+use tileproc::writer::TileWriter;
 
 fn print_err(err: &str) -> ! {
     println!("{}: {}", "error".red().bold(), err);
     std::process::exit(1);
 }
 
-/// Moves all files (not directories) inside one directory, into another directory. Does not delete the directory that files were moved from.
+/// Moves all tiles named by `name_template` (not other files or directories) inside one directory, into another directory. Does not delete the directory that files were moved from.
 /// Does not move files between drives.
-fn move_files_in_directory(from: &Path, to: &Path) {
+fn move_files_in_directory(from: &Path, to: &Path, name_template: &NameTemplate) {
     for entry in fs::read_dir(from).unwrap() {
         let path = entry.unwrap().path();
 
-        if path.is_file() {
+        if path.is_file() && name_template.parse_path(&path).is_some() {
             let filename = path.file_name().unwrap();
 
             let mut new_to = to.to_path_buf();
@@ -27,21 +40,225 @@ fn move_files_in_directory(from: &Path, to: &Path) {
     }
 }
 
+/// Creates the progress reporter chosen by the command line arguments.
+fn progress_reporter(args: &Args) -> Progress {
+    if args.quiet {
+        return Progress::none();
+    }
+
+    match args.progress {
+        ProgressFormat::Json => {
+            Progress::new(|event| println!("{}", serde_json::to_string(event).unwrap()))
+        }
+        ProgressFormat::Bar => {
+            let bar: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+            Progress::new(move |event| {
+                let mut bar = bar.lock().unwrap();
+                match event {
+                    ProgressEvent::Message { message } => match bar.as_ref() {
+                        Some(bar) => bar.suspend(|| println!("{}", message)),
+                        None => println!("{}", message),
+                    },
+                    ProgressEvent::LevelStarted { level, total_tiles } => {
+                        let new_bar = ProgressBar::new(*total_tiles).with_style(
+                            ProgressStyle::with_template(
+                                "{prefix} [{wide_bar}] {pos}/{len} tiles, {msg}",
+                            )
+                            .unwrap(),
+                        );
+                        new_bar.set_prefix(format!("layer {}", level));
+                        *bar = Some(new_bar);
+                    }
+                    ProgressEvent::TileDone {
+                        tiles_done,
+                        bytes_written,
+                        eta_secs,
+                        ..
+                    } => {
+                        if let Some(bar) = bar.as_ref() {
+                            bar.set_position(*tiles_done);
+                            bar.set_message(format!(
+                                "{} written, {} left",
+                                HumanBytes(*bytes_written),
+                                HumanDuration(Duration::from_secs_f64(eta_secs.unwrap_or(0.0)))
+                            ));
+                        }
+                    }
+                    ProgressEvent::LevelFinished { .. } => {
+                        if let Some(bar) = bar.take() {
+                            bar.finish();
+                        }
+                    }
+                }
+            })
+        }
+    }
+}
+
+fn job_status_label(status: JobStatus) -> colored::ColoredString {
+    match status {
+        JobStatus::Succeeded => "ok".green(),
+        JobStatus::Failed => "failed".red(),
+        JobStatus::Cancelled => "cancelled".yellow(),
+    }
+}
+
+/// Runs a job file, printing a line for every finished job in place of per layer progress bars.
+fn run_job_file(
+    quiet: bool,
+    progress: ProgressFormat,
+    job_file: &JobFile,
+    cancel: &CancelToken,
+) -> BatchReport {
+    let job_count = job_file.jobs.len();
+
+    if quiet {
+        return run_jobs(job_file, |_| {}, cancel);
+    }
+
+    match progress {
+        ProgressFormat::Json => run_jobs(
+            job_file,
+            |event| println!("{}", serde_json::to_string(event).unwrap()),
+            cancel,
+        ),
+        ProgressFormat::Bar => {
+            let bar = ProgressBar::new(job_count as u64).with_style(
+                ProgressStyle::with_template("jobs [{wide_bar}] {pos}/{len}, {msg}").unwrap(),
+            );
+            let report = run_jobs(
+                job_file,
+                {
+                    let bar = bar.clone();
+                    move |event| match event {
+                        BatchEvent::JobStarted { input, .. } => {
+                            bar.set_message(format!("{}", input.display()))
+                        }
+                        BatchEvent::JobFinished { job, report } => {
+                            bar.suspend(|| {
+                                println!(
+                                    "job {}/{} {}: {} -> {}",
+                                    job + 1,
+                                    job_count,
+                                    job_status_label(report.status),
+                                    report.input.display(),
+                                    report.output.display()
+                                )
+                            });
+                            bar.inc(1);
+                        }
+                        BatchEvent::JobProgress { .. } => {}
+                    }
+                },
+                cancel,
+            );
+            bar.finish_and_clear();
+            report
+        }
+    }
+}
+
+/// Checks the arguments of a `gen-tiles` or `gen-tile-layers` job, and starts its journal.
+fn start_journal(command: &str, gen_tiles_args: &GenTilesArgs) -> Journal {
+    gen_tiles_args
+        .validate()
+        .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+    tileproc::tiler::start_journal(command, gen_tiles_args)
+        .unwrap_or_else(|err| print_err(&format!("{}.", err)))
+}
+
 fn main() {
     let args: Args = clap::Parser::parse();
+    let progress = progress_reporter(&args);
+
+    // stop between tiles on ctrl-c, so that no tile is left half written
+    let cancel = CancelToken::new();
+    {
+        let cancel = cancel.clone();
+        ctrlc::set_handler(move || cancel.cancel()).expect("failed to set ctrl-c handler");
+    }
 
     match args.top_commands {
         TopSubcommands::GenTiles(gen_tiles_args) => {
-            gen_tiles_to_dir(&gen_tiles_args);
+            let journal = start_journal("gen-tiles", &gen_tiles_args);
+            let context = JobContext {
+                writer: &TileWriter::new(gen_tiles_args.format, gen_tiles_args.fsync)
+                    .with_texture(gen_tiles_args.block_compression, gen_tiles_args.mipmaps)
+                    .with_name_template(gen_tiles_args.name_template.clone()),
+                journal: Some(&journal),
+                progress: &progress,
+                cancel: &cancel,
+            };
+            gen_tiles_to_dir(&gen_tiles_args, context)
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
         }
         TopSubcommands::GenTileLayers(gen_tiles_args) => {
-            clean_dir(&gen_tiles_args.output);
+            let journal = start_journal("gen-tile-layers", &gen_tiles_args);
+            let context = JobContext {
+                writer: &TileWriter::new(gen_tiles_args.format, gen_tiles_args.fsync)
+                    .with_texture(gen_tiles_args.block_compression, gen_tiles_args.mipmaps)
+                    .with_name_template(gen_tiles_args.name_template.clone()),
+                journal: Some(&journal),
+                progress: &progress,
+                cancel: &cancel,
+            };
 
-            let mut new_gen_tiles_args = gen_tiles_args.clone();
-            new_gen_tiles_args.output.push("0/");
-            gen_tiles_to_dir(&new_gen_tiles_args);
+            gen_tile_layers(&gen_tiles_args, context)
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+        }
+        TopSubcommands::GenWebTiles(gen_web_tiles_args) => {
+            gen_web_tiles_args
+                .validate()
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            clean_dir(
+                &gen_web_tiles_args.output,
+                gen_web_tiles_args.force,
+                &gen_web_tiles_args.name_template,
+            )
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            let context = JobContext {
+                writer: &TileWriter::new(gen_web_tiles_args.format, gen_web_tiles_args.fsync)
+                    .with_name_template(gen_web_tiles_args.name_template.clone()),
+                journal: None,
+                progress: &progress,
+                cancel: &cancel,
+            };
+            gen_web_mercator_tiles(&gen_web_tiles_args, context)
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+        }
+        TopSubcommands::GenTerrain(gen_terrain_args) => {
+            if !gen_terrain_args.input.is_file() {
+                print_err("input is not a file.");
+            }
+            gen_terrain_args
+                .validate()
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            clean_dir(
+                &gen_terrain_args.output,
+                gen_terrain_args.force,
+                &gen_terrain_args.name_template,
+            )
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            if let Some(hillshade_output) = &gen_terrain_args.hillshade_output {
+                clean_dir(
+                    hillshade_output,
+                    gen_terrain_args.force,
+                    &gen_terrain_args.name_template,
+                )
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            }
 
-            generate_lods(&gen_tiles_args.output);
+            // terrain-RGB needs a lossless format
+            let context = JobContext {
+                writer: &TileWriter::new(TileFormat::Png, gen_terrain_args.fsync)
+                    .with_name_template(gen_terrain_args.name_template.clone()),
+                journal: None,
+                progress: &progress,
+                cancel: &cancel,
+            };
+            gen_terrain_tiles(&gen_terrain_args, context)
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
         }
         TopSubcommands::StitchImage(stitch_image_args) => {
             // Assertions
@@ -52,7 +269,7 @@ fn main() {
                 print_err("output has no file extension.");
             });
 
-            let files = get_files_in_dir(
+            let mut files = get_files_in_dir(
                 &stitch_image_args
                     .input
                     .into_os_string()
@@ -61,16 +278,21 @@ fn main() {
                 "",
             )
             .unwrap();
+            files.retain(|file| stitch_image_args.name_template.parse_path(file).is_some());
 
             if files.is_empty() {
                 print_err("no files found in input directory.");
             }
 
-            let output_imgbuf = consolidate_images(&files);
+            let output_image =
+                consolidate_images(&files, &stitch_image_args.name_template, &cancel)
+                    .unwrap_or_else(|err| print_err(&format!("{}.", err)));
 
-            // Write the contents of this image to the Writer in PNG format.
-            output_imgbuf
-                .save(stitch_image_args.output)
+            // Write the contents of this image to the Writer in the format of its extension.
+            let writer = TileWriter::new(TileFormat::default(), stitch_image_args.fsync);
+            writer.remove_temp_file(&stitch_image_args.output).unwrap();
+            writer
+                .write(&output_image, &stitch_image_args.output)
                 .expect("failed to save file");
         }
         TopSubcommands::TilesToLayers(tiles_to_layers_args) => {
@@ -78,9 +300,228 @@ fn main() {
                 print_err("input is not a directory.");
             }
             let zero_path = tiles_to_layers_args.input.join("0/");
-            clean_dir(&zero_path);
-            move_files_in_directory(&tiles_to_layers_args.input, &zero_path);
-            generate_lods(&tiles_to_layers_args.input);
+            clean_dir(
+                &zero_path,
+                tiles_to_layers_args.force,
+                &tiles_to_layers_args.name_template,
+            )
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            move_files_in_directory(
+                &tiles_to_layers_args.input,
+                &zero_path,
+                &tiles_to_layers_args.name_template,
+            );
+            let context = JobContext {
+                writer: &TileWriter::new(tiles_to_layers_args.format, tiles_to_layers_args.fsync)
+                    .with_name_template(tiles_to_layers_args.name_template.clone()),
+                journal: None,
+                progress: &progress,
+                cancel: &cancel,
+            };
+            generate_lods(&tiles_to_layers_args.input, context)
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+        }
+        TopSubcommands::Update(update_args) => {
+            if !update_args.output.join("0").is_dir() {
+                print_err("output does not contain tile layers.");
+            }
+            update_args
+                .validate()
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+
+            let context = JobContext {
+                writer: &TileWriter::new(update_args.format, update_args.fsync)
+                    .with_name_template(update_args.name_template.clone()),
+                journal: None,
+                progress: &progress,
+                cancel: &cancel,
+            };
+            let changed_tiles = patch_tiles(
+                &update_args.input,
+                (update_args.patch_x, update_args.patch_y),
+                update_args.x_offset,
+                update_args.y_offset,
+                &update_args.output.join("0"),
+                update_args.tile_size(),
+                context,
+            )
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+
+            update_lods(
+                &update_args.output,
+                &changed_tiles,
+                update_args.tile_size(),
+                context,
+            )
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+        }
+        TopSubcommands::Merge(merge_args) => {
+            if merge_args.input.len() < 2 {
+                print_err("merge needs at least two inputs.");
+            }
+            for input in &merge_args.input {
+                if !input.is_dir() {
+                    print_err(&format!("{} is not a directory.", input.display()));
+                }
+                // clearing the output would delete the input's tiles
+                if merge_args.output.canonicalize().ok() == input.canonicalize().ok() {
+                    print_err("output is one of the inputs.");
+                }
+            }
+
+            let layered = merge_args
+                .input
+                .iter()
+                .filter(|input| input.join("0").is_dir())
+                .count();
+            if layered != 0 && layered != merge_args.input.len() {
+                print_err("can not merge tile layers with flat tile directories.");
+            }
+
+            clean_dir(
+                &merge_args.output,
+                merge_args.force,
+                &merge_args.name_template,
+            )
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            let context = JobContext {
+                writer: &TileWriter::new(merge_args.format, merge_args.fsync)
+                    .with_name_template(merge_args.name_template.clone()),
+                journal: None,
+                progress: &progress,
+                cancel: &cancel,
+            };
+
+            if layered == 0 {
+                merge_tiles(
+                    &merge_args.input,
+                    &merge_args.output,
+                    merge_args.blend,
+                    0,
+                    context,
+                )
+            } else {
+                merge_pyramids(
+                    &merge_args.input,
+                    &merge_args.output,
+                    merge_args.blend,
+                    context,
+                )
+            }
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+        }
+        TopSubcommands::PackAtlas(pack_atlas_args) => {
+            if !pack_atlas_args.input.is_dir() {
+                print_err("input is not a directory.");
+            }
+            let level_dir = if pack_atlas_args.input.join("0").is_dir() {
+                pack_atlas_args
+                    .input
+                    .join(pack_atlas_args.level.to_string())
+            } else if pack_atlas_args.level == 0 {
+                pack_atlas_args.input.clone()
+            } else {
+                print_err("input does not contain tile layers.");
+            };
+            if !level_dir.is_dir() {
+                print_err(&format!(
+                    "input has no LOD layer {}.",
+                    pack_atlas_args.level
+                ));
+            }
+            if pack_atlas_args.output.canonicalize().ok() == level_dir.canonicalize().ok() {
+                print_err("output is the input.");
+            }
+
+            clean_dir(
+                &pack_atlas_args.output,
+                pack_atlas_args.force,
+                &pack_atlas_args.name_template,
+            )
+            .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+            let context = JobContext {
+                writer: &TileWriter::new(pack_atlas_args.format, pack_atlas_args.fsync)
+                    .with_texture(pack_atlas_args.block_compression, false)
+                    .with_name_template(pack_atlas_args.name_template.clone()),
+                journal: None,
+                progress: &progress,
+                cancel: &cancel,
+            };
+            pack_atlas(&level_dir, &pack_atlas_args, context)
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+        }
+        TopSubcommands::Coords(coords_args) => {
+            let tile_dimensions = coords_args.tile_size();
+            if tile_dimensions.0 == 0 || tile_dimensions.1 == 0 {
+                print_err("the tile dimensions must be positive.");
+            }
+            let offset = (coords_args.x_offset, coords_args.y_offset);
+
+            let json = match (coords_args.pixel, coords_args.tile) {
+                (Some(pixel), _) => {
+                    PixelInfo::new(pixel, coords_args.level, tile_dimensions, offset)
+                        .map(|info| serde_json::to_string_pretty(&info))
+                }
+                (None, Some(tile)) => {
+                    TileInfo::new(tile, coords_args.level, tile_dimensions, offset)
+                        .map(|info| serde_json::to_string_pretty(&info))
+                }
+                (None, None) => unreachable!("clap requires a pixel or a tile"),
+            };
+            let json = json.unwrap_or_else(|| print_err("the coordinates are out of range."));
+            println!("{}", json.unwrap());
+        }
+        TopSubcommands::Run(run_args) => {
+            let job_file = JobFile::load(&run_args.job_file)
+                .unwrap_or_else(|err| print_err(&format!("{}.", err)));
+
+            let report = run_job_file(args.quiet, args.progress, &job_file, &cancel);
+
+            match args.progress {
+                ProgressFormat::Json => println!("{}", serde_json::to_string(&report).unwrap()),
+                ProgressFormat::Bar => {
+                    for (index, job) in report.jobs.iter().enumerate() {
+                        println!(
+                            "{:>4} {:<9} {} -> {}: {} tiles, {} in {}",
+                            index + 1,
+                            job_status_label(job.status),
+                            job.input.display(),
+                            job.output.display(),
+                            job.tiles_done,
+                            HumanBytes(job.bytes_written),
+                            HumanDuration(Duration::from_secs_f64(job.seconds))
+                        );
+                        if let Some(error) = &job.error {
+                            println!("       {}", error);
+                        }
+                    }
+                    println!(
+                        "{} succeeded, {} failed, {} cancelled: {} tiles, {} in {}",
+                        report.succeeded,
+                        report.failed,
+                        report.cancelled,
+                        report.tiles_done,
+                        HumanBytes(report.bytes_written),
+                        HumanDuration(Duration::from_secs_f64(report.seconds))
+                    );
+                }
+            }
+
+            if !report.is_success() {
+                std::process::exit(1);
+            }
+        }
+        TopSubcommands::Validate(validate_args) => {
+            if !validate_args.input.is_dir() {
+                print_err("input is not a directory.");
+            }
+
+            let report = validate_pyramid(&validate_args.input, &validate_args.name_template);
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+            if !report.is_valid() {
+                std::process::exit(1);
+            }
         }
     }
 }
@@ -0,0 +1,139 @@
+use std::path::Path;
+
+use image::{DynamicImage, GenericImageView, Pixel, Primitive};
+use num_traits::{NumCast, ToPrimitive};
+use rayon::prelude::*;
+
+use crate::error::TileError;
+use crate::hdr::open_image;
+use crate::pixel::{with_alpha, with_pixel_type, TilePixel};
+
+/// Cuts an image out with a mask image of the same size, by multiplying the alpha of every pixel
+/// with the mask's alpha, or with its brightness if the mask is opaque. Pixels the mask
+/// hides completely become transparent black, so tiles outside the mask aren't written.
+///
+/// Images without an alpha channel get one, keeping their bit depth.
+pub fn apply_mask(image: DynamicImage, mask_path: &Path) -> Result<DynamicImage, TileError> {
+    let mask = open_image(mask_path).map_err(TileError::image(mask_path))?;
+    if mask.dimensions() != image.dimensions() {
+        return Err(TileError::DimensionMismatch {
+            path: mask_path.to_path_buf(),
+            expected: image.dimensions(),
+            found: mask.dimensions(),
+        });
+    }
+
+    // the coverage of every pixel, from 0 to 1
+    let mask = mask.to_luma_alpha32f();
+    let coverage_channel = if mask.pixels().any(|pixel| pixel[1] < 1.0) {
+        1
+    } else {
+        0
+    };
+    let coverage: Vec<f32> = mask
+        .pixels()
+        .map(|pixel| pixel[coverage_channel].clamp(0.0, 1.0))
+        .collect();
+
+    let image = with_alpha(image);
+    Ok(with_pixel_type!(image.color(), P => {
+        let mut buffer = P::from_dynamic(image);
+        buffer
+            .par_chunks_mut(P::CHANNEL_COUNT as usize)
+            .zip(coverage.par_iter())
+            .for_each(|(channels, &coverage)| mask_pixel::<P>(channels, coverage));
+        P::into_dynamic(buffer)
+    }))
+}
+
+/// Multiplies the alpha of a pixel, its last channel, by `coverage`.
+fn mask_pixel<P: TilePixel>(channels: &mut [P::Subpixel], coverage: f32) {
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    let alpha = channels.last_mut().unwrap();
+
+    let mut masked = alpha.to_f64().unwrap() * coverage as f64;
+    // integer channels are rounded, floating point ones have a max of 1
+    if max > 1.0 {
+        masked = masked.round();
+    }
+
+    if masked <= 0.0 {
+        channels.fill(P::Subpixel::DEFAULT_MIN_VALUE);
+    } else {
+        *alpha = NumCast::from(masked).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayAlphaImage, GrayImage, Luma, LumaA, Rgb, RgbImage, Rgba};
+
+    use super::*;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 1, Rgb([200, 100, 50])))
+    }
+
+    fn pixels(image: DynamicImage) -> Vec<Rgba<u8>> {
+        image.into_rgba8().pixels().copied().collect()
+    }
+
+    #[test]
+    fn opaque_masks_cover_with_their_brightness() {
+        let temp = tempfile::tempdir().unwrap();
+        let mask_path = temp.path().join("mask.png");
+        GrayImage::from_fn(3, 1, |x, _| Luma([[255, 128, 0][x as usize]]))
+            .save(&mask_path)
+            .unwrap();
+
+        assert_eq!(
+            pixels(apply_mask(image(), &mask_path).unwrap()),
+            [
+                Rgba([200, 100, 50, 255]),
+                Rgba([200, 100, 50, 128]),
+                Rgba([0; 4])
+            ]
+        );
+    }
+
+    #[test]
+    fn masks_with_alpha_cover_with_their_alpha() {
+        let temp = tempfile::tempdir().unwrap();
+        let mask_path = temp.path().join("mask.png");
+        // the brightness of the mask is ignored once it has transparent pixels
+        GrayAlphaImage::from_fn(3, 1, |x, _| {
+            [LumaA([0, 255]), LumaA([255, 64]), LumaA([255, 0])][x as usize]
+        })
+        .save(&mask_path)
+        .unwrap();
+
+        assert_eq!(
+            pixels(apply_mask(image(), &mask_path).unwrap()),
+            [
+                Rgba([200, 100, 50, 255]),
+                Rgba([200, 100, 50, 64]),
+                Rgba([0; 4])
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_masks_of_another_size() {
+        let temp = tempfile::tempdir().unwrap();
+        let mask_path = temp.path().join("mask.png");
+        GrayImage::new(3, 2).save(&mask_path).unwrap();
+
+        match apply_mask(image(), &mask_path) {
+            Err(TileError::DimensionMismatch {
+                path,
+                expected,
+                found,
+            }) => {
+                assert_eq!(path, mask_path);
+                assert_eq!(expected, (3, 1));
+                assert_eq!(found, (3, 2));
+            }
+            result => panic!("expected a dimension mismatch, got {:?}", result),
+        }
+    }
+}
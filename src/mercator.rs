@@ -0,0 +1,596 @@
+use std::{
+    f64::consts::PI,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, Rgba, Rgba32FImage};
+use rayon::prelude::*;
+
+use crate::args::{GenWebTilesArgs, Resampling, SourceCrs};
+use crate::error::TileError;
+use crate::georef::GeoTransform;
+use crate::pixel::{buffer, normalized_rgba, with_pixel_type, TileBuffer, TilePixel};
+use crate::tiler::{
+    compress_staged_tiles, dir_paths, prepare_output_dir, shrink_tiles, staging_writer, JobContext,
+};
+
+/// The radius of the sphere Web Mercator projects, in meters.
+const EARTH_RADIUS: f64 = 6378137.0;
+/// Half the width and height of the Web Mercator world, in meters.
+const MERCATOR_EXTENT: f64 = PI * EARTH_RADIUS;
+/// The latitude at which the Web Mercator world is cut off, so that it is square.
+const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+fn lon_lat_to_mercator(lon: f64, lat: f64) -> (f64, f64) {
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    (
+        EARTH_RADIUS * lon.to_radians(),
+        EARTH_RADIUS * (PI / 4.0 + lat / 2.0).tan().ln(),
+    )
+}
+
+fn mercator_to_lon_lat(x: f64, y: f64) -> (f64, f64) {
+    (
+        (x / EARTH_RADIUS).to_degrees(),
+        (y / EARTH_RADIUS).sinh().atan().to_degrees(),
+    )
+}
+
+/// Maps between Web Mercator meters and the pixels of a georeferenced source image.
+struct Projection {
+    transform: GeoTransform,
+    crs: SourceCrs,
+}
+
+impl Projection {
+    /// Returns the position of a Web Mercator point in source pixel space, where pixel x,y
+    /// covers x..x+1, y..y+1.
+    fn source_pixel(&self, mercator: (f64, f64)) -> (f64, f64) {
+        let world = match self.crs {
+            SourceCrs::Epsg3857 => mercator,
+            SourceCrs::Epsg4326 => mercator_to_lon_lat(mercator.0, mercator.1),
+        };
+        (
+            (world.0 - self.transform.origin.0) / self.transform.pixel_size.0,
+            (world.1 - self.transform.origin.1) / self.transform.pixel_size.1,
+        )
+    }
+
+    /// Returns the Web Mercator position of a point in source pixel space.
+    fn mercator(&self, source_pixel: (f64, f64)) -> (f64, f64) {
+        let world = (
+            self.transform.origin.0 + source_pixel.0 * self.transform.pixel_size.0,
+            self.transform.origin.1 + source_pixel.1 * self.transform.pixel_size.1,
+        );
+        match self.crs {
+            SourceCrs::Epsg3857 => world,
+            SourceCrs::Epsg4326 => lon_lat_to_mercator(world.0, world.1),
+        }
+    }
+
+    /// The width of a source pixel in Web Mercator meters.
+    fn pixel_width(&self) -> f64 {
+        match self.crs {
+            SourceCrs::Epsg3857 => self.transform.pixel_size.0,
+            // Web Mercator x is proportional to longitude
+            SourceCrs::Epsg4326 => EARTH_RADIUS * self.transform.pixel_size.0.to_radians(),
+        }
+    }
+}
+
+/// Returns the width and height on the ground, in meters, of the pixels of a georeferenced image
+/// around a point in its world coordinates.
+pub fn ground_pixel_size(
+    transform: &GeoTransform,
+    crs: SourceCrs,
+    world: (f64, f64),
+) -> (f64, f64) {
+    let (width, height) = (transform.pixel_size.0, -transform.pixel_size.1);
+    match crs {
+        // Web Mercator stretches the world by 1 / cos(latitude)
+        SourceCrs::Epsg3857 => {
+            let scale = mercator_to_lon_lat(world.0, world.1).1.to_radians().cos();
+            (width * scale, height * scale)
+        }
+        // meridians get closer with latitude, while parallels are evenly spaced
+        SourceCrs::Epsg4326 => (
+            EARTH_RADIUS * width.to_radians() * world.1.to_radians().cos(),
+            EARTH_RADIUS * height.to_radians(),
+        ),
+    }
+}
+
+/// Returns the coordinate reference system of a georeferenced image: `source_crs` if there is
+/// one, or else the one of the image's GeoTIFF keys, or `None` if it has none.
+pub fn read_source_crs(
+    input: &Path,
+    source_crs: Option<SourceCrs>,
+) -> Result<Option<SourceCrs>, TileError> {
+    if source_crs.is_some() {
+        return Ok(source_crs);
+    }
+
+    match GeoTransform::geotiff_epsg_code(input)
+        .map_err(|err| TileError::InvalidGeoreference(err.to_string()))?
+    {
+        Some(4326) => Ok(Some(SourceCrs::Epsg4326)),
+        Some(3857) => Ok(Some(SourceCrs::Epsg3857)),
+        Some(code) => Err(TileError::InvalidGeoreference(format!(
+            "{}: EPSG:{} is not supported, only EPSG:4326 and EPSG:3857 are",
+            input.display(),
+            code
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// The size of a pixel in Web Mercator meters at a zoom level.
+fn mercator_resolution(zoom: u32, tile_dimensions: u32) -> f64 {
+    2.0 * MERCATOR_EXTENT / (tile_dimensions as f64 * 2f64.powi(zoom as i32))
+}
+
+/// Returns the Web Mercator position of a point in the global pixel space of a zoom level,
+/// whose origin is the top left corner of the world.
+fn global_pixel_to_mercator(pixel: (f64, f64), resolution: f64) -> (f64, f64) {
+    (
+        pixel.0 * resolution - MERCATOR_EXTENT,
+        MERCATOR_EXTENT - pixel.1 * resolution,
+    )
+}
+
+/// Returns the first zoom level whose pixels are at least as small as the pixels of a source.
+pub fn native_zoom(transform: &GeoTransform, crs: SourceCrs, tile_dimensions: u32) -> u32 {
+    let projection = Projection {
+        transform: *transform,
+        crs,
+    };
+    let zoom = (2.0 * MERCATOR_EXTENT / (tile_dimensions as f64 * projection.pixel_width()))
+        .log2()
+        .ceil();
+    zoom.clamp(0.0, 30.0) as u32
+}
+
+/// The weight of a filter at a distance from the sample position, in filter pixels.
+fn filter_weight(resampling: Resampling, distance: f64) -> f64 {
+    let distance = distance.abs();
+    match resampling {
+        Resampling::Nearest => 1.0,
+        Resampling::Bilinear => (1.0 - distance).max(0.0),
+        Resampling::Lanczos => {
+            if distance < 1e-9 {
+                1.0
+            } else if distance < 3.0 {
+                let x = PI * distance;
+                3.0 * x.sin() * (x / 3.0).sin() / (x * x)
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+/// Samples a source image at a position in source pixel space.
+///
+/// `scale` is the number of source pixels per output pixel. When it is more than 1, the filter
+/// is widened to cover all of them, so that shrunk images don't alias. Pixels outside of the
+/// source are transparent, and colors are weighted by alpha so that transparent pixels don't
+/// darken their neighbors.
+///
+/// Returns the sampled RGBA channels, from 0 to 1.
+fn sample<P: TilePixel>(
+    source: &TileBuffer<P>,
+    position: (f64, f64),
+    scale: (f64, f64),
+    resampling: Resampling,
+) -> Rgba<f32> {
+    if resampling == Resampling::Nearest {
+        let (x, y) = (position.0.floor(), position.1.floor());
+        if x < 0.0 || y < 0.0 || x >= source.width() as f64 || y >= source.height() as f64 {
+            return Rgba([0.0; 4]);
+        }
+        return Rgba(normalized_rgba(source.get_pixel(x as u32, y as u32)).map(|c| c as f32));
+    }
+
+    let support = match resampling {
+        Resampling::Lanczos => 3.0,
+        _ => 1.0,
+    };
+    let scale = (scale.0.max(1.0), scale.1.max(1.0));
+
+    // pixel centers are at x + 0.5
+    let center = (position.0 - 0.5, position.1 - 0.5);
+    let x_taps =
+        (center.0 - support * scale.0).ceil() as i64..=(center.0 + support * scale.0) as i64;
+    let y_taps =
+        (center.1 - support * scale.1).ceil() as i64..=(center.1 + support * scale.1) as i64;
+
+    let x_weights: Vec<(i64, f64)> = x_taps
+        .map(|x| {
+            (
+                x,
+                filter_weight(resampling, (x as f64 - center.0) / scale.0),
+            )
+        })
+        .collect();
+
+    let mut total_weight = 0.0;
+    let mut alpha = 0.0;
+    let mut colors = [0.0; 3];
+
+    for y in y_taps {
+        let y_weight = filter_weight(resampling, (y as f64 - center.1) / scale.1);
+
+        for &(x, x_weight) in &x_weights {
+            let weight = x_weight * y_weight;
+            total_weight += weight;
+
+            if x < 0 || y < 0 || x >= source.width() as i64 || y >= source.height() as i64 {
+                continue;
+            }
+            let pixel = normalized_rgba(source.get_pixel(x as u32, y as u32));
+            let weighted_alpha = weight * pixel[3];
+            alpha += weighted_alpha;
+            for channel in 0..3 {
+                colors[channel] += weighted_alpha * pixel[channel];
+            }
+        }
+    }
+
+    if total_weight <= 0.0 || alpha <= 0.0 {
+        return Rgba([0.0; 4]);
+    }
+    let color = |channel: usize| (colors[channel] / alpha) as f32;
+    Rgba([
+        color(0),
+        color(1),
+        color(2),
+        (alpha / total_weight).clamp(0.0, 1.0) as f32,
+    ])
+}
+
+/// Renders the Web Mercator tile x,y of a zoom level from a source image.
+///
+/// Returns `None` if the tile is empty.
+fn render_tile<P: TilePixel>(
+    source: &TileBuffer<P>,
+    projection: &Projection,
+    tile: (i32, i32),
+    resolution: f64,
+    tile_dimensions: u32,
+    resampling: Resampling,
+) -> Option<Rgba32FImage> {
+    let tile_origin = (
+        tile.0 as f64 * tile_dimensions as f64,
+        tile.1 as f64 * tile_dimensions as f64,
+    );
+    let source_pixel = |x: f64, y: f64| {
+        projection.source_pixel(global_pixel_to_mercator(
+            (tile_origin.0 + x, tile_origin.1 + y),
+            resolution,
+        ))
+    };
+
+    // the number of source pixels per tile pixel, which barely changes within a tile
+    let top_left = source_pixel(0.0, 0.0);
+    let bottom_right = source_pixel(tile_dimensions as f64, tile_dimensions as f64);
+    let scale = (
+        (bottom_right.0 - top_left.0).abs() / tile_dimensions as f64,
+        (bottom_right.1 - top_left.1).abs() / tile_dimensions as f64,
+    );
+
+    let mut tile_image = Rgba32FImage::new(tile_dimensions, tile_dimensions);
+    let mut tile_empty = true;
+
+    for y in 0..tile_dimensions {
+        for x in 0..tile_dimensions {
+            let position = source_pixel(x as f64 + 0.5, y as f64 + 0.5);
+            let pixel = sample(source, position, scale, resampling);
+
+            if pixel[3] > 0.0 {
+                tile_empty = false;
+            }
+            tile_image.put_pixel(x, y, pixel);
+        }
+    }
+
+    (!tile_empty).then_some(tile_image)
+}
+
+/// Returns the tiles of a zoom level that a source image overlaps.
+fn covered_tiles(
+    projection: &Projection,
+    source_dimensions: (u32, u32),
+    zoom: u32,
+    tile_dimensions: u32,
+) -> Vec<(i32, i32)> {
+    let (width, height) = (source_dimensions.0 as f64, source_dimensions.1 as f64);
+    let corners = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, height)]
+        .map(|corner| projection.mercator(corner));
+
+    let resolution = mercator_resolution(zoom, tile_dimensions);
+    let tile_size = resolution * tile_dimensions as f64;
+    let last_tile = 2f64.powi(zoom as i32) - 1.0;
+    let tile_index = |meters: f64| ((meters / tile_size).floor()).clamp(0.0, last_tile) as i32;
+
+    let min_x = corners.iter().map(|c| c.0).fold(f64::INFINITY, f64::min);
+    let max_x = corners
+        .iter()
+        .map(|c| c.0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let min_y = corners.iter().map(|c| c.1).fold(f64::INFINITY, f64::min);
+    let max_y = corners
+        .iter()
+        .map(|c| c.1)
+        .fold(f64::NEG_INFINITY, f64::max);
+
+    // tile rows count down from the top of the world
+    let (first_x, last_x) = (
+        tile_index(min_x + MERCATOR_EXTENT),
+        tile_index(max_x + MERCATOR_EXTENT),
+    );
+    let (first_y, last_y) = (
+        tile_index(MERCATOR_EXTENT - max_y),
+        tile_index(MERCATOR_EXTENT - min_y),
+    );
+
+    (first_y..=last_y)
+        .flat_map(|y| (first_x..=last_x).map(move |x| (x, y)))
+        .collect()
+}
+
+/// Reads the georeference and coordinate reference system of the input of `args`.
+fn read_projection(args: &GenWebTilesArgs) -> Result<Projection, TileError> {
+    let invalid_georeference = |message: String| {
+        TileError::InvalidGeoreference(format!("{}: {}", args.input.display(), message))
+    };
+
+    let transform = GeoTransform::read(&args.input)
+        .map_err(|err| TileError::InvalidGeoreference(err.to_string()))?
+        .ok_or_else(|| invalid_georeference("no world file or GeoTIFF tags found".to_string()))?;
+
+    let crs = read_source_crs(&args.input, args.source_crs)?.ok_or_else(|| {
+        invalid_georeference(
+            "can not tell its coordinate reference system, pass --source-crs".to_string(),
+        )
+    })?;
+
+    Ok(Projection { transform, crs })
+}
+
+/// Reprojects a source image into the tiles of a zoom level, in `level_dir`. Tiles have the
+/// pixel type of the source.
+fn reproject_tiles<P: TilePixel>(
+    source: &TileBuffer<P>,
+    projection: &Projection,
+    zoom: u32,
+    level_dir: &Path,
+    args: &GenWebTilesArgs,
+    context: JobContext,
+) -> Result<(), TileError> {
+    let tile_dimensions = args.tile_dimensions;
+    let tiles = covered_tiles(projection, source.dimensions(), zoom, tile_dimensions);
+    let resolution = mercator_resolution(zoom, tile_dimensions);
+    let writer = &context.writer.clone().with_zoom(zoom);
+    let context = JobContext { writer, ..context };
+    context.progress.start_level(zoom, tiles.len() as u64);
+
+    tiles.par_iter().try_for_each(|&(x, y)| {
+        context.cancel.check()?;
+
+        let bytes_written = match render_tile(
+            source,
+            projection,
+            (x, y),
+            resolution,
+            tile_dimensions,
+            args.resampling,
+        ) {
+            Some(tile_image) => {
+                let path = level_dir.join(context.writer.tile_file_name(x, y));
+                context
+                    .writer
+                    .write(
+                        &P::into_dynamic(P::convert(&DynamicImage::ImageRgba32F(tile_image))),
+                        &path,
+                    )
+                    .map_err(TileError::image(&path))?
+            }
+            None => 0,
+        };
+
+        context.progress.tile_done(bytes_written);
+        Ok(())
+    })?;
+    context.progress.finish_level();
+    Ok(())
+}
+
+/// Reprojects the georeferenced input image of `args` into Web Mercator slippy map tiles at its
+/// max zoom level, and shrinks them into the tiles of every zoom level down to its min zoom
+/// level.
+///
+/// Every zoom level is saved in a sub directory of the output directory named after it, with
+/// tiles named after their slippy map x and y coordinates.
+pub fn gen_web_mercator_tiles(
+    args: &GenWebTilesArgs,
+    context: JobContext,
+) -> Result<(), TileError> {
+    args.validate().map_err(TileError::InvalidArgs)?;
+    let projection = read_projection(args)?;
+    let tile_dimensions = args.tile_dimensions;
+    let output_dir = &args.output;
+
+    let max_zoom = args
+        .max_zoom
+        .unwrap_or_else(|| native_zoom(&projection.transform, projection.crs, tile_dimensions));
+    let min_zoom = args.min_zoom.min(max_zoom);
+    // quadkeys have no digits at zoom level 0
+    context
+        .writer
+        .clone()
+        .with_zoom(min_zoom)
+        .check_tile_names((0, 0), (0, 0))?;
+
+    context.progress.message("decoding image...");
+    let source = image::open(&args.input).map_err(TileError::image(&args.input))?;
+
+    // block compressed tiles are staged uncompressed until every zoom level is shrunk
+    let staging_writer = staging_writer(context.writer);
+    let staged_context = staging_writer
+        .as_ref()
+        .map_or(context, |writer| JobContext { writer, ..context });
+
+    context.progress.message("reprojecting tiles...");
+    let level_dir = output_dir.join(max_zoom.to_string());
+    prepare_output_dir(&level_dir, staged_context)?;
+
+    with_pixel_type!(source.color(), P => reproject_tiles(
+        &*buffer::<P>(&source),
+        &projection,
+        max_zoom,
+        &level_dir,
+        args,
+        staged_context,
+    ))?;
+
+    // slippy map tiles halve their coordinates from one zoom level to the next, like LOD layers
+    for zoom in (min_zoom..max_zoom).rev() {
+        let files: Vec<PathBuf> = dir_paths(&output_dir.join((zoom + 1).to_string()))?
+            .into_iter()
+            .filter(|path| context.writer.tile_coords(path).is_some())
+            .collect();
+
+        shrink_tiles(
+            files,
+            &output_dir.join(zoom.to_string()),
+            zoom,
+            staged_context,
+        )?;
+    }
+
+    if staging_writer.is_some() {
+        for zoom in min_zoom..=max_zoom {
+            compress_staged_tiles(&output_dir.join(zoom.to_string()), context)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+    use image::RgbaImage;
+
+    use super::*;
+    use crate::args::TileFormat;
+    use crate::cancel::CancelToken;
+    use crate::progress::Progress;
+    use crate::writer::TileWriter;
+
+    fn assert_near(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-6 && (actual.1 - expected.1).abs() < 1e-6,
+            "{actual:?} is not {expected:?}"
+        );
+    }
+
+    #[test]
+    fn measures_degrees_on_the_ground() {
+        let transform = GeoTransform {
+            origin: (0.0, 0.0),
+            pixel_size: (1.0 / 3600.0, -1.0 / 3600.0),
+        };
+        // an arc second at the equator
+        let arc_second = EARTH_RADIUS * PI / (180.0 * 3600.0);
+        assert_near(
+            ground_pixel_size(&transform, SourceCrs::Epsg4326, (10.0, 0.0)),
+            (arc_second, arc_second),
+        );
+        assert_near(
+            ground_pixel_size(&transform, SourceCrs::Epsg4326, (10.0, -60.0)),
+            (arc_second / 2.0, arc_second),
+        );
+    }
+
+    #[test]
+    fn measures_web_mercator_meters_on_the_ground() {
+        let transform = GeoTransform {
+            origin: (0.0, 0.0),
+            pixel_size: (10.0, -10.0),
+        };
+        assert_near(
+            ground_pixel_size(&transform, SourceCrs::Epsg3857, (5.0, 0.0)),
+            (10.0, 10.0),
+        );
+        let sixty_degrees_north = lon_lat_to_mercator(0.0, 60.0);
+        assert_near(
+            ground_pixel_size(&transform, SourceCrs::Epsg3857, sixty_degrees_north),
+            (5.0, 5.0),
+        );
+    }
+
+    #[test]
+    fn reprojects_pixels_onto_their_web_mercator_tiles() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("in.png");
+        let output = temp.path().join("out");
+
+        // a 4x4 image with a red pixel at 2,1, whose pixels are the size of the pixels of zoom
+        // level 1 with 4x4 tiles, and whose top left pixel is global pixel 3,1 of that level
+        let mut image = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255]));
+        image.put_pixel(2, 1, Rgba([255, 0, 0, 255]));
+        image.save(&input).unwrap();
+        let resolution = mercator_resolution(1, 4);
+        let origin = (
+            -MERCATOR_EXTENT + 3.0 * resolution,
+            MERCATOR_EXTENT - resolution,
+        );
+        fs::write(
+            temp.path().join("in.pgw"),
+            format!(
+                "{}\n0\n0\n{}\n{}\n{}\n",
+                resolution,
+                -resolution,
+                origin.0 + resolution / 2.0,
+                origin.1 - resolution / 2.0
+            ),
+        )
+        .unwrap();
+
+        let args = GenWebTilesArgs::parse_from([
+            "gen-web-tiles",
+            "-i",
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "--tile-dimensions",
+            "4",
+            "--source-crs",
+            "epsg:3857",
+            "--min-zoom",
+            "1",
+            "--max-zoom",
+            "1",
+            "--resampling",
+            "nearest",
+        ]);
+        let context = JobContext {
+            writer: &TileWriter::new(TileFormat::Png, false),
+            journal: None,
+            progress: &Progress::none(),
+            cancel: &CancelToken::new(),
+        };
+        gen_web_mercator_tiles(&args, context).unwrap();
+
+        // global pixel 5,2 is pixel 1,2 of tile 1,0
+        let tile = |name: &str| image::open(output.join("1").join(name)).unwrap().to_rgba8();
+        assert_eq!(*tile("1,0.png").get_pixel(1, 2), Rgba([255, 0, 0, 255]));
+        assert_eq!(*tile("1,0.png").get_pixel(0, 2), Rgba([0, 0, 255, 255]));
+        assert_eq!(*tile("0,0.png").get_pixel(3, 1), Rgba([0, 0, 255, 255]));
+        assert_eq!(*tile("0,0.png").get_pixel(2, 1), Rgba([0, 0, 0, 0]));
+        assert_eq!(*tile("1,1.png").get_pixel(2, 0), Rgba([0, 0, 255, 255]));
+        assert_eq!(*tile("1,1.png").get_pixel(3, 1), Rgba([0, 0, 0, 0]));
+    }
+}
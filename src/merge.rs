@@ -0,0 +1,349 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, GenericImageView, Rgba};
+use rayon::prelude::*;
+
+use crate::args::BlendMode;
+use crate::error::TileError;
+use crate::naming::NameTemplate;
+use crate::pixel::convert_to;
+use crate::texture::open_tile;
+use crate::tiler::{generate_lods_from, prepare_output_dir, JobContext};
+
+/// Combines a pixel of an upper tile into the pixel below it, with channels from 0 to 1.
+///
+/// Colors are blended as in the W3C compositing spec: where the pixel below is transparent, the
+/// upper pixel's own color shows, and the result is alpha-composited over the pixel below.
+fn blend_pixel(mode: BlendMode, below: &mut Rgba<f32>, above: Rgba<f32>) {
+    if above[3] <= 0.0 {
+        return;
+    }
+    if mode == BlendMode::ReplaceIfOpaque {
+        if above[3] >= 1.0 {
+            *below = above;
+        }
+        return;
+    }
+
+    let alpha_above = above[3];
+    let alpha_below = below[3];
+    let alpha = alpha_above + alpha_below * (1.0 - alpha_above);
+
+    for channel in 0..3 {
+        let color_above = above[channel];
+        let color_below = below[channel];
+
+        let blended = match mode {
+            BlendMode::Multiply => color_above * color_below,
+            BlendMode::Screen => color_above + color_below - color_above * color_below,
+            BlendMode::Over | BlendMode::ReplaceIfOpaque => color_above,
+        };
+        let color_above = (1.0 - alpha_below) * color_above + alpha_below * blended;
+
+        let color = alpha_above * color_above + alpha_below * (1.0 - alpha_above) * color_below;
+        below[channel] = color / alpha;
+    }
+    below[3] = alpha;
+}
+
+/// Returns the tiles named by `name_template` of every input directory, by their coordinates, in
+/// the order of the inputs.
+fn tiles_by_coords(
+    input_dirs: &[PathBuf],
+    name_template: &NameTemplate,
+) -> BTreeMap<(i32, i32), Vec<PathBuf>> {
+    let mut tiles: BTreeMap<(i32, i32), Vec<PathBuf>> = BTreeMap::new();
+
+    for input_dir in input_dirs {
+        let mut paths: Vec<PathBuf> = fs::read_dir(input_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            if let Some(coords) = name_template.parse_path(&path) {
+                tiles.entry(coords).or_default().push(path);
+            }
+        }
+    }
+    tiles
+}
+
+/// Merges one level of tile directories into `output_dir`, stacking each input over the ones
+/// before it.
+///
+/// The output has a tile wherever any input has one. Tiles only one input has are copied without
+/// being decoded, the others are blended with `mode` and written in the writer's format, with
+/// the pixel type of the lowest of the blended tiles.
+pub fn merge_tiles(
+    input_dirs: &[PathBuf],
+    output_dir: &Path,
+    mode: BlendMode,
+    level: u32,
+    context: JobContext,
+) -> Result<(), TileError> {
+    prepare_output_dir(output_dir, context)?;
+
+    let tiles: Vec<((i32, i32), Vec<PathBuf>)> =
+        tiles_by_coords(input_dirs, &context.writer.name_template)
+            .into_iter()
+            .collect();
+    context.progress.start_level(level, tiles.len() as u64);
+
+    tiles.par_iter().try_for_each(|((x, y), paths)| {
+        context.cancel.check()?;
+
+        let bytes_written = if let [path] = paths.as_slice() {
+            context
+                .writer
+                .copy(path, &output_dir.join(path.file_name().unwrap()))
+                .expect("failed to copy tile")
+        } else {
+            // blend in floating point, and write the pixel type of the lowest tile
+            let lowest = open_tile(&paths[0]).unwrap();
+            let color_type = lowest.color();
+            let mut merged = lowest.into_rgba32f();
+
+            for path in &paths[1..] {
+                let tile = open_tile(path).unwrap();
+                if tile.dimensions() != merged.dimensions() {
+                    return Err(TileError::DimensionMismatch {
+                        path: path.clone(),
+                        expected: merged.dimensions(),
+                        found: tile.dimensions(),
+                    });
+                }
+
+                for (below, above) in merged.pixels_mut().zip(tile.to_rgba32f().pixels()) {
+                    blend_pixel(mode, below, *above);
+                }
+            }
+
+            let writer = context.writer.at_zoom_of(paths);
+            writer
+                .write(
+                    &convert_to(&DynamicImage::ImageRgba32F(merged), color_type),
+                    &output_dir.join(writer.tile_file_name(*x, *y)),
+                )
+                .expect("failed to save file")
+        };
+
+        context.progress.tile_done(bytes_written);
+        Ok(())
+    })?;
+
+    context.progress.finish_level();
+    Ok(())
+}
+
+/// Returns the number of LOD layer directories in a directory of tile layers.
+fn level_count(dir: &Path) -> u32 {
+    let mut count = 0;
+    while dir.join(count.to_string()).is_dir() {
+        count += 1;
+    }
+    count
+}
+
+/// Merges directories of tile layers level by level into `output_dir`, like `merge_tiles`.
+///
+/// Only the levels every input has are merged. The levels above them are generated from the
+/// merged tiles, since an input's tiles there don't include the other inputs.
+pub fn merge_pyramids(
+    input_dirs: &[PathBuf],
+    output_dir: &Path,
+    mode: BlendMode,
+    context: JobContext,
+) -> Result<(), TileError> {
+    let shared_levels = input_dirs
+        .iter()
+        .map(|dir| level_count(dir))
+        .min()
+        .unwrap_or(0);
+
+    for level in 0..shared_levels {
+        let level_dirs: Vec<PathBuf> = input_dirs
+            .iter()
+            .map(|dir| dir.join(level.to_string()))
+            .collect();
+        merge_tiles(
+            &level_dirs,
+            &output_dir.join(level.to_string()),
+            mode,
+            level,
+            context,
+        )?;
+    }
+
+    if shared_levels > 0 {
+        generate_lods_from(output_dir, shared_levels - 1, context)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+    use crate::args::TileFormat;
+    use crate::cancel::CancelToken;
+    use crate::naming::DEFAULT_NAME_TEMPLATE;
+    use crate::progress::Progress;
+    use crate::validate::validate_pyramid;
+    use crate::writer::TileWriter;
+
+    fn blend(mode: BlendMode, below: [f32; 4], above: [f32; 4]) -> [f32; 4] {
+        let mut below = Rgba(below);
+        blend_pixel(mode, &mut below, Rgba(above));
+        below.0
+    }
+
+    fn write_tile(dir: &Path, name: &str, color: [u8; 4], size: u32) {
+        fs::create_dir_all(dir).unwrap();
+        RgbaImage::from_pixel(size, size, Rgba(color))
+            .save(dir.join(name))
+            .unwrap();
+    }
+
+    fn pixel(path: &Path) -> [u8; 4] {
+        image::open(path).unwrap().to_rgba8().get_pixel(0, 0).0
+    }
+
+    fn run(merge: impl FnOnce(JobContext) -> Result<(), TileError>) -> Result<(), TileError> {
+        merge(JobContext {
+            writer: &TileWriter::new(TileFormat::Png, false),
+            journal: None,
+            progress: &Progress::none(),
+            cancel: &CancelToken::new(),
+        })
+    }
+
+    #[test]
+    fn blends_pixels() {
+        let (below, above) = ([1.0, 0.5, 0.0, 1.0], [0.5, 0.5, 1.0, 1.0]);
+        assert_eq!(blend(BlendMode::Over, below, above), above);
+        assert_eq!(
+            blend(BlendMode::Multiply, below, above),
+            [0.5, 0.25, 0.0, 1.0]
+        );
+        assert_eq!(
+            blend(BlendMode::Screen, below, above),
+            [1.0, 0.75, 1.0, 1.0]
+        );
+
+        // half transparent pixels are composited over the pixels below them
+        let half_blue = [0.0, 0.0, 1.0, 0.5];
+        assert_eq!(
+            blend(BlendMode::Over, [1.0, 0.0, 0.0, 1.0], half_blue),
+            [0.5, 0.0, 0.5, 1.0]
+        );
+        // where the pixel below is transparent, the pixel above shows as it is
+        assert_eq!(blend(BlendMode::Multiply, [0.0; 4], above), above);
+        assert_eq!(blend(BlendMode::Multiply, below, [0.0; 4]), below);
+    }
+
+    #[test]
+    fn replaces_only_with_opaque_pixels() {
+        let below = [1.0, 0.0, 0.0, 1.0];
+        assert_eq!(
+            blend(BlendMode::ReplaceIfOpaque, below, [0.0, 1.0, 0.0, 0.99]),
+            below
+        );
+        assert_eq!(
+            blend(BlendMode::ReplaceIfOpaque, below, [0.0, 1.0, 0.0, 1.0]),
+            [0.0, 1.0, 0.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn merges_tiles_over_the_tiles_of_earlier_inputs() {
+        let temp = tempfile::tempdir().unwrap();
+        let (below, above, output) = (
+            temp.path().join("below"),
+            temp.path().join("above"),
+            temp.path().join("out"),
+        );
+        write_tile(&below, "0,0.png", [255, 0, 0, 255], 4);
+        write_tile(&below, "1,0.png", [255, 0, 0, 255], 4);
+        write_tile(&above, "0,0.png", [0, 0, 255, 128], 4);
+        write_tile(&above, "0,1.png", [0, 0, 255, 128], 4);
+
+        run(|context| {
+            merge_tiles(
+                &[below.clone(), above.clone()],
+                &output,
+                BlendMode::Over,
+                0,
+                context,
+            )
+        })
+        .unwrap();
+
+        let [r, g, b, a] = pixel(&output.join("0,0.png"));
+        assert!(r.abs_diff(127) <= 1 && g == 0 && b.abs_diff(128) <= 1 && a == 255);
+        // tiles only one input has are copied
+        assert_eq!(
+            fs::read(output.join("1,0.png")).unwrap(),
+            fs::read(below.join("1,0.png")).unwrap()
+        );
+        assert_eq!(pixel(&output.join("0,1.png")), [0, 0, 255, 128]);
+    }
+
+    #[test]
+    fn rejects_tiles_of_other_dimensions() {
+        let temp = tempfile::tempdir().unwrap();
+        let (below, above) = (temp.path().join("below"), temp.path().join("above"));
+        write_tile(&below, "0,0.png", [255, 0, 0, 255], 4);
+        write_tile(&above, "0,0.png", [0, 0, 255, 255], 8);
+
+        let result = run(|context| {
+            merge_tiles(
+                &[below, above.clone()],
+                &temp.path().join("out"),
+                BlendMode::Over,
+                0,
+                context,
+            )
+        });
+        assert!(matches!(
+            result,
+            Err(TileError::DimensionMismatch { path, expected: (4, 4), found: (8, 8) })
+                if path == above.join("0,0.png")
+        ));
+    }
+
+    #[test]
+    fn regenerates_the_levels_above_the_shared_ones() {
+        let temp = tempfile::tempdir().unwrap();
+        let (below, above, output) = (
+            temp.path().join("below"),
+            temp.path().join("above"),
+            temp.path().join("out"),
+        );
+        for name in ["0,0.png", "2,0.png", "0,2.png", "2,2.png", "4,4.png"] {
+            write_tile(&below.join("0"), name, [255, 0, 0, 255], 4);
+        }
+        // a level only one input has, which doesn't include the other input
+        write_tile(&below.join("1"), "9,9.png", [255, 0, 0, 255], 4);
+        write_tile(&above.join("0"), "0,0.png", [0, 0, 255, 255], 4);
+
+        run(|context| merge_pyramids(&[below, above], &output, BlendMode::Over, context)).unwrap();
+
+        assert_eq!(pixel(&output.join("0").join("0,0.png")), [0, 0, 255, 255]);
+        assert!(!output.join("1").join("9,9.png").exists());
+        // the merged tile is the top left quarter of its parent
+        let [r, _, b, _] = pixel(&output.join("1").join("0,0.png"));
+        assert!(r == 0 && b == 255);
+
+        let report = validate_pyramid(&output, &DEFAULT_NAME_TEMPLATE.parse().unwrap());
+        assert!(report.is_valid(), "{:?}", report.problems);
+        assert_eq!(report.levels, 3);
+    }
+}
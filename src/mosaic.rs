@@ -0,0 +1,313 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use image::io::Reader;
+use serde::Deserialize;
+
+use crate::error::TileError;
+use crate::pixel::{buffer, is_opaque, is_transparent, with_pixel_type, TilePixel};
+use crate::tiler::{draw_onto_tiles, overlapped_tiles, remove_tiles, JobContext};
+
+/// One image of a mosaic.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MosaicSource {
+    pub image: PathBuf,
+    /// The x pixel of this image to make tile pixel 0,0, like the x offset of `gen-tiles`.
+    pub x_offset: i32,
+    /// The y pixel of this image to make tile pixel 0,0, like the y offset of `gen-tiles`.
+    pub y_offset: i32,
+    /// Sources are drawn over sources with a lower z-order. Sources with the same z-order are
+    /// drawn in the order they are listed.
+    #[serde(default)]
+    pub z_order: i32,
+}
+
+/// Several source images placed into one tile grid, read from a JSON file.
+/// ```json
+/// {
+///     "sources": [
+///         { "image": "west.png", "x_offset": 0, "y_offset": 0 },
+///         { "image": "east.png", "x_offset": -4000, "y_offset": 0 },
+///         { "image": "harbor.png", "x_offset": -3500, "y_offset": -1200, "z_order": 1 }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MosaicManifest {
+    pub sources: Vec<MosaicSource>,
+}
+
+/// Returns true if `path` names a mosaic manifest rather than an image.
+pub fn is_manifest_path(path: &Path) -> bool {
+    path.extension() == Some("json".as_ref())
+}
+
+impl MosaicManifest {
+    /// Reads a manifest. Relative image paths are resolved against the directory of the
+    /// manifest.
+    pub fn load(path: &Path) -> Result<MosaicManifest, TileError> {
+        let invalid_manifest = |message: String| TileError::InvalidManifest {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        let contents = fs::read_to_string(path).map_err(|err| invalid_manifest(err.to_string()))?;
+        let mut manifest: MosaicManifest =
+            serde_json::from_str(&contents).map_err(|err| invalid_manifest(err.to_string()))?;
+
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        for source in &mut manifest.sources {
+            source.image = base_dir.join(&source.image);
+            if !source.image.is_file() {
+                return Err(invalid_manifest(format!(
+                    "{} is not a file",
+                    source.image.display()
+                )));
+            }
+        }
+
+        Ok(manifest)
+    }
+}
+
+/// Draws a pixel of a source over a tile pixel.
+fn composite_pixel<P: TilePixel>(tile_pixel: &mut P, source_pixel: P) {
+    if is_opaque(&source_pixel) || is_transparent(tile_pixel) {
+        *tile_pixel = source_pixel;
+    } else {
+        tile_pixel.blend(&source_pixel);
+    }
+}
+
+/// Composites the sources of a mosaic into layer 0 tiles, without stitching them into one image.
+///
+/// Sources are decoded one at a time in z-order, and alpha-composited over the tiles drawn
+/// before them. `x_offset` and `y_offset` shift every source. The tiles have the pixel type of
+/// the source with the lowest z-order, which the other sources are converted to.
+///
+/// A half composited layer can't be resumed tile by tile, so any tiles already in `output_dir`
+/// are removed first.
+pub fn mosaic_to_tiles(
+    manifest: &MosaicManifest,
+    x_offset: i32,
+    y_offset: i32,
+    output_dir: &Path,
+    tile_dimensions: (u32, u32),
+    context: JobContext,
+) -> Result<(), TileError> {
+    fs::create_dir_all(output_dir).map_err(TileError::io(output_dir))?;
+    remove_tiles(output_dir, &context.writer.name_template).map_err(TileError::io(output_dir))?;
+
+    let mut sources: Vec<&MosaicSource> = manifest.sources.iter().collect();
+    sources.sort_by_key(|source| source.z_order);
+
+    // position of every source's top left pixel in tile pixel space
+    let positions: Vec<(i32, i32)> = sources
+        .iter()
+        .map(|source| (-source.x_offset - x_offset, -source.y_offset - y_offset))
+        .collect();
+
+    // every tile has the zoom level that all of the sources reach
+    let mut writer = context.writer.clone();
+    let mut total_tiles = 0;
+    for (source, &position) in sources.iter().zip(&positions) {
+        let dimensions = Reader::open(&source.image)
+            .map_err(TileError::io(&source.image))?
+            .into_dimensions()
+            .map_err(TileError::image(&source.image))?;
+        let overlapped = overlapped_tiles(position, dimensions, tile_dimensions)?;
+        if let Some(&bottom_right) = overlapped.last() {
+            writer = writer.reaching(bottom_right);
+        }
+        total_tiles += overlapped.len() as u64;
+    }
+    let context = JobContext {
+        writer: &writer,
+        ..context
+    };
+    context.progress.start_level(0, total_tiles);
+
+    // the tiles have the pixel type of the lowest source
+    let mut color_type = None;
+
+    for (index, (source, &position)) in sources.iter().zip(&positions).enumerate() {
+        context.cancel.check()?;
+        context.progress.message(&format!(
+            "compositing {} ({} of {})...",
+            source.image.display(),
+            index + 1,
+            sources.len()
+        ));
+
+        let image = image::open(&source.image).map_err(TileError::image(&source.image))?;
+        let color_type = *color_type.get_or_insert(image.color());
+        with_pixel_type!(color_type, P => draw_onto_tiles(
+            &*buffer::<P>(&image),
+            position,
+            output_dir,
+            tile_dimensions,
+            composite_pixel::<P>,
+            context,
+        ))?;
+    }
+
+    context.progress.finish_level();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::args::TileFormat;
+    use crate::cancel::CancelToken;
+    use crate::progress::Progress;
+    use crate::writer::TileWriter;
+
+    fn write_image(path: &Path, size: u32, color: [u8; 4]) {
+        RgbaImage::from_pixel(size, size, Rgba(color))
+            .save(path)
+            .unwrap();
+    }
+
+    fn write_manifest(dir: &Path, manifest: &str) -> PathBuf {
+        let path = dir.join("mosaic.json");
+        fs::write(&path, manifest).unwrap();
+        path
+    }
+
+    /// Returns true for the color of half transparent blue over opaque red, give or take the
+    /// rounding of 8-bit blending.
+    fn is_blue_over_red([r, g, b, a]: [u8; 4]) -> bool {
+        r.abs_diff(127) <= 1 && g == 0 && b.abs_diff(128) <= 1 && a >= 254
+    }
+
+    #[test]
+    fn loads_manifests_next_to_their_images() {
+        let temp = tempfile::tempdir().unwrap();
+        write_image(&temp.path().join("a.png"), 4, [0; 4]);
+        let path = write_manifest(
+            temp.path(),
+            r#"{ "sources": [{ "image": "a.png", "x_offset": 1, "y_offset": -2 }] }"#,
+        );
+
+        let manifest = MosaicManifest::load(&path).unwrap();
+        let source = &manifest.sources[0];
+        assert_eq!(source.image, temp.path().join("a.png"));
+        assert_eq!(
+            (source.x_offset, source.y_offset, source.z_order),
+            (1, -2, 0)
+        );
+        assert!(is_manifest_path(&path));
+        assert!(!is_manifest_path(&source.image));
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        let temp = tempfile::tempdir().unwrap();
+        write_image(&temp.path().join("a.png"), 4, [0; 4]);
+        for manifest in [
+            r#"{ "sources": [{ "image": "missing.png", "x_offset": 0, "y_offset": 0 }] }"#,
+            r#"{ "sources": [{ "image": "a.png", "x_offset": 0, "y_offset": 0, "z": 1 }] }"#,
+            r#"{ "sources": [{ "image": "a.png" }] }"#,
+            "not json",
+        ] {
+            let path = write_manifest(temp.path(), manifest);
+            assert!(
+                matches!(
+                    MosaicManifest::load(&path),
+                    Err(TileError::InvalidManifest { .. })
+                ),
+                "{manifest}"
+            );
+        }
+    }
+
+    #[test]
+    fn composites_sources_in_z_order() {
+        let temp = tempfile::tempdir().unwrap();
+        // an opaque green image on top, listed first, a half transparent blue image over the
+        // middle of a red one
+        write_image(&temp.path().join("green.png"), 2, [0, 255, 0, 255]);
+        write_image(&temp.path().join("red.png"), 8, [255, 0, 0, 255]);
+        write_image(&temp.path().join("blue.png"), 4, [0, 0, 255, 128]);
+        let manifest = MosaicManifest::load(&write_manifest(
+            temp.path(),
+            r#"{ "sources": [
+                { "image": "green.png", "x_offset": -3, "y_offset": -3, "z_order": 2 },
+                { "image": "red.png", "x_offset": 0, "y_offset": 0 },
+                { "image": "blue.png", "x_offset": -2, "y_offset": -2, "z_order": 1 }
+            ] }"#,
+        ))
+        .unwrap();
+
+        // a tile left over from another mosaic
+        let output = temp.path().join("out");
+        fs::create_dir(&output).unwrap();
+        write_image(&output.join("5,5.png"), 4, [0; 4]);
+
+        // shifted by 4 pixels, so the red image covers the tiles from -1,-1 to 0,0
+        let writer = TileWriter::new(TileFormat::Png, false);
+        let context = JobContext {
+            writer: &writer,
+            journal: None,
+            progress: &Progress::none(),
+            cancel: &CancelToken::new(),
+        };
+        mosaic_to_tiles(&manifest, 4, 4, &output, (4, 4), context).unwrap();
+
+        let mut tiles: Vec<_> = fs::read_dir(&output)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        tiles.sort();
+        assert_eq!(tiles, ["-1,-1.png", "-1,0.png", "0,-1.png", "0,0.png"]);
+
+        let pixel = |name: &str, x: u32, y: u32| {
+            image::open(output.join(name))
+                .unwrap()
+                .to_rgba8()
+                .get_pixel(x, y)
+                .0
+        };
+        assert_eq!(pixel("-1,-1.png", 0, 0), [255, 0, 0, 255]);
+        assert!(is_blue_over_red(pixel("-1,-1.png", 2, 2)));
+        assert_eq!(pixel("-1,-1.png", 3, 3), [0, 255, 0, 255]);
+        assert_eq!(pixel("0,0.png", 0, 0), [0, 255, 0, 255]);
+        assert!(is_blue_over_red(pixel("0,0.png", 1, 1)));
+        assert_eq!(pixel("0,0.png", 2, 2), [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn reports_undecodable_sources() {
+        let temp = tempfile::tempdir().unwrap();
+        write_image(&temp.path().join("a.png"), 4, [255, 0, 0, 255]);
+        fs::write(temp.path().join("b.png"), b"not a png").unwrap();
+        let manifest = MosaicManifest::load(&write_manifest(
+            temp.path(),
+            r#"{ "sources": [
+                { "image": "a.png", "x_offset": 0, "y_offset": 0 },
+                { "image": "b.png", "x_offset": 0, "y_offset": 0 }
+            ] }"#,
+        ))
+        .unwrap();
+
+        let writer = TileWriter::new(TileFormat::Png, false);
+        let context = JobContext {
+            writer: &writer,
+            journal: None,
+            progress: &Progress::none(),
+            cancel: &CancelToken::new(),
+        };
+        let output = temp.path().join("out");
+        match mosaic_to_tiles(&manifest, 0, 0, &output, (4, 4), context) {
+            Err(TileError::Image { path, .. }) => assert_eq!(path, temp.path().join("b.png")),
+            result => panic!("expected an image error, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::args::TileFormat;
+
+/// The template of the tile names tileproc writes by default, such as `"-3,12.png"`.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{x},{y}.{ext}";
+
+/// The highest zoom level whose quadkeys have coordinates that fit in an `i32`.
+const MAX_QUADKEY_ZOOM: u32 = 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    X,
+    Y,
+    Quadkey,
+    Extension,
+}
+
+/// The values of the coordinate fields of a tile name. Quadkeys set all of them.
+#[derive(Debug, Clone, Copy, Default)]
+struct Fields {
+    x: Option<i32>,
+    y: Option<i32>,
+    zoom: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// How tile files are named, both when tiles are written and when they are read back.
+///
+/// A template holds fields in braces: `{x}` and `{y}` are the tile coordinates, `{col}` and
+/// `{row}` are the same as `{x}` and `{y}`, and `{quadkey}` is a Bing Maps quadkey of both. It
+/// ends with `.{ext}`, the extension of a tile format, so that names with other extensions are
+/// never taken for tiles. Fields need some other text between them, so that names can be split
+/// back into them.
+///
+/// Quadkeys have one digit per zoom level, as in Bing Maps, so the name of a quadkey tile depends
+/// on its zoom level as well as its coordinates, and the quadkeys of a LOD layer have one digit
+/// less than those of the layer below it. They can only name tiles with coordinates of 0 and up,
+/// within the tiles of zoom levels 1 to 31. Other templates ignore zoom levels.
+///
+/// ```
+/// use tileproc::naming::NameTemplate;
+///
+/// let template: NameTemplate = "r{row}_c{col}.{ext}".parse().unwrap();
+/// assert_eq!(template.file_name(-3, 12, 0, "png"), "r12_c-3.png");
+/// assert_eq!(template.parse("r12_c-3.png"), Some((-3, 12)));
+/// assert_eq!(template.parse("-3,12.png"), None);
+/// assert_eq!(template.parse("r12_c-3.txt"), None);
+///
+/// let template: NameTemplate = "{quadkey}.{ext}".parse().unwrap();
+/// assert_eq!(template.file_name(3, 5, 4, "jpg"), "0213.jpg");
+/// assert_eq!(template.parse("0213.jpg"), Some((3, 5)));
+/// assert_eq!(template.zoom("0213.jpg"), Some(4));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct NameTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl Default for NameTemplate {
+    fn default() -> NameTemplate {
+        DEFAULT_NAME_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<NameTemplate, String> {
+        let invalid = |reason: &str| format!("`{}` is not a name template, {}", value, reason);
+
+        let mut parts = Vec::new();
+        let mut rest = value;
+        while !rest.is_empty() {
+            let Some(start) = rest.find('{') else {
+                parts.push(Part::Literal(rest.to_string()));
+                break;
+            };
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("a `{` is never closed"))?;
+            let field = match &rest[start + 1..start + end] {
+                "x" | "col" => Field::X,
+                "y" | "row" => Field::Y,
+                "quadkey" => Field::Quadkey,
+                "ext" => Field::Extension,
+                name => return Err(invalid(&format!("`{{{}}}` is not a field", name))),
+            };
+            if matches!(parts.last(), Some(Part::Field(_))) {
+                return Err(invalid("fields need text between them"));
+            }
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+
+        let count = |field: Field| {
+            parts
+                .iter()
+                .filter(|part| **part == Part::Field(field))
+                .count()
+        };
+        let coordinates = (count(Field::X), count(Field::Y), count(Field::Quadkey));
+        if coordinates != (1, 1, 0) && coordinates != (0, 0, 1) {
+            return Err(invalid(
+                "it needs one `{x}` and one `{y}`, or one `{quadkey}`",
+            ));
+        }
+        if count(Field::Extension) != 1 || !value.ends_with(".{ext}") {
+            return Err(invalid("it needs to end with `.{ext}`"));
+        }
+        if value.contains(['/', '\\']) {
+            return Err(invalid("tile names can't hold directories"));
+        }
+
+        Ok(NameTemplate {
+            template: value.to_string(),
+            parts,
+        })
+    }
+}
+
+impl TryFrom<String> for NameTemplate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<NameTemplate, String> {
+        value.parse()
+    }
+}
+
+impl NameTemplate {
+    /// Returns false if the template can't name the tile at the given coordinates and zoom level,
+    /// which is the case for quadkeys of tiles outside their zoom level, or of zoom level 0.
+    pub fn can_name(&self, x: i32, y: i32, zoom: u32) -> bool {
+        !self.parts.contains(&Part::Field(Field::Quadkey))
+            || ((1..=MAX_QUADKEY_ZOOM).contains(&zoom) && x >= 0 && y >= 0 && (x | y) >> zoom == 0)
+    }
+
+    /// Returns the file name of the tile at the given coordinates and zoom level, with the file
+    /// `extension`.
+    pub fn file_name(&self, x: i32, y: i32, zoom: u32, extension: &str) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Field(Field::X) => x.to_string(),
+                Part::Field(Field::Y) => y.to_string(),
+                Part::Field(Field::Quadkey) => quadkey(x, y, zoom),
+                Part::Field(Field::Extension) => extension.to_string(),
+            })
+            .collect()
+    }
+
+    /// Parses the tile coordinates out of a tile's file name.
+    ///
+    /// Returns `None` if the file name is not a tile name of this template.
+    pub fn parse(&self, file_name: &str) -> Option<(i32, i32)> {
+        let fields = self.fields(file_name)?;
+        Some((fields.x?, fields.y?))
+    }
+
+    /// Parses the tile coordinates out of the file name of a tile's path.
+    pub fn parse_path(&self, path: &Path) -> Option<(i32, i32)> {
+        self.parse(path.file_name()?.to_str()?)
+    }
+
+    /// Returns the zoom level of a tile's file name, which is the number of digits of its
+    /// quadkey.
+    ///
+    /// Returns `None` if the file name is not a tile name of this template, or the template has
+    /// no quadkey.
+    pub fn zoom(&self, file_name: &str) -> Option<u32> {
+        self.fields(file_name)?.zoom
+    }
+
+    /// Returns the zoom level of the file name of a tile's path.
+    pub fn zoom_of_path(&self, path: &Path) -> Option<u32> {
+        self.zoom(path.file_name()?.to_str()?)
+    }
+
+    fn fields(&self, file_name: &str) -> Option<Fields> {
+        let mut fields = Fields::default();
+        match_parts(&self.parts, file_name, &mut fields).then_some(fields)
+    }
+}
+
+/// Returns the quadkey of a tile that `NameTemplate::can_name` at a zoom level, one digit per
+/// zoom level.
+fn quadkey(x: i32, y: i32, zoom: u32) -> String {
+    (0..zoom)
+        .rev()
+        .map(|bit| {
+            let digit = ((x >> bit) & 1) | (((y >> bit) & 1) << 1);
+            char::from(b'0' + digit as u8)
+        })
+        .collect()
+}
+
+/// Returns the coordinates and zoom level of a quadkey, or `None` if the coordinates don't fit in
+/// an `i32`.
+fn parse_quadkey(digits: &str) -> Option<(i32, i32, u32)> {
+    let zoom = u32::try_from(digits.len()).ok()?;
+    if zoom > MAX_QUADKEY_ZOOM {
+        return None;
+    }
+    let (x, y) = digits.bytes().fold((0, 0), |(x, y), digit| {
+        let digit = (digit - b'0') as i32;
+        (x << 1 | (digit & 1), y << 1 | (digit >> 1))
+    });
+    Some((x, y, zoom))
+}
+
+/// Matches a file name against the parts of a template, trying the longest value for every field
+/// first. The values of the coordinate fields are stored in `fields`.
+fn match_parts(parts: &[Part], name: &str, fields: &mut Fields) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return name.is_empty();
+    };
+
+    let field = match part {
+        Part::Literal(literal) => {
+            return name
+                .strip_prefix(literal.as_str())
+                .is_some_and(|name| match_parts(rest, name, fields))
+        }
+        Part::Field(field) => *field,
+    };
+
+    let is_field_char = |(index, char): (usize, char)| match field {
+        Field::X | Field::Y => char.is_ascii_digit() || (index == 0 && char == '-'),
+        Field::Quadkey => ('0'..='3').contains(&char),
+        Field::Extension => char.is_ascii_alphanumeric(),
+    };
+    let longest = name
+        .char_indices()
+        .take_while(|&indexed| is_field_char(indexed))
+        .count();
+
+    (1..=longest).rev().any(|length| {
+        let (value, name) = name.split_at(length);
+        let parsed = match field {
+            Field::X => value.parse().ok().map(|x| Fields {
+                x: Some(x),
+                ..*fields
+            }),
+            Field::Y => value.parse().ok().map(|y| Fields {
+                y: Some(y),
+                ..*fields
+            }),
+            Field::Quadkey => parse_quadkey(value).map(|(x, y, zoom)| Fields {
+                x: Some(x),
+                y: Some(y),
+                zoom: Some(zoom),
+            }),
+            Field::Extension => TileFormat::from_extension(value).map(|_| *fields),
+        };
+        let Some(mut parsed) = parsed else {
+            return false;
+        };
+        if match_parts(rest, name, &mut parsed) {
+            *fields = parsed;
+            true
+        } else {
+            false
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(template: &str) -> NameTemplate {
+        template.parse().unwrap()
+    }
+
+    #[test]
+    fn default_template_names_tiles_like_before() {
+        let template = NameTemplate::default();
+        assert_eq!(template.file_name(-3, 12, 0, "png"), "-3,12.png");
+        assert_eq!(template.parse("-3,12.png"), Some((-3, 12)));
+        assert_eq!(template.parse("0,0.ktx2"), Some((0, 0)));
+    }
+
+    #[test]
+    fn names_roundtrip_for_every_template() {
+        let templates = [
+            "{x},{y}.{ext}",
+            "{x}_{y}.{ext}",
+            "r{row}_c{col}.{ext}",
+            "tile-{x}-{y}.{ext}",
+            "{y}-{x}.{ext}",
+            "{quadkey}.{ext}",
+        ]
+        .map(template);
+        let extensions = ["png", "jpg", "bmp", "tiff", "exr", "ktx2", "dds"];
+
+        for template in &templates {
+            for (x, y) in [(0, 0), (1, 0), (5, 3), (255, 1024), (i32::MAX, i32::MAX)] {
+                for extension in extensions {
+                    let name = template.file_name(x, y, MAX_QUADKEY_ZOOM, extension);
+                    assert_eq!(template.parse(&name), Some((x, y)), "{}", name);
+                }
+            }
+            if !template.template.contains("quadkey") {
+                for (x, y) in [(-1, -1), (-3, 12), (7, -9), (i32::MIN, i32::MIN)] {
+                    let name = template.file_name(x, y, 0, "png");
+                    assert_eq!(template.parse(&name), Some((x, y)), "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quadkeys_have_one_digit_per_zoom_level() {
+        let template = template("{quadkey}.{ext}");
+        assert_eq!(template.file_name(3, 6, 4, "png"), "0231.png");
+        assert_eq!(template.parse("0231.png"), Some((3, 6)));
+        assert_eq!(template.zoom("0231.png"), Some(4));
+        assert_eq!(template.file_name(0, 0, 3, "png"), "000.png");
+        assert_eq!(template.zoom("000.png"), Some(3));
+
+        // the quadkey of a tile's parent is its quadkey without the last digit
+        assert_eq!(template.file_name(1, 3, 3, "png"), "023.png");
+        assert_eq!(NameTemplate::default().zoom("3,6.png"), None);
+    }
+
+    #[test]
+    fn negative_coordinates_split_on_dashes() {
+        let template = template("tile-{x}-{y}.{ext}");
+        assert_eq!(template.file_name(-3, -4, 0, "png"), "tile--3--4.png");
+        assert_eq!(template.parse("tile--3--4.png"), Some((-3, -4)));
+        assert_eq!(template.parse("tile-3--4.png"), Some((3, -4)));
+        assert_eq!(template.parse("tile--3-4.png"), Some((-3, 4)));
+    }
+
+    #[test]
+    fn rejects_names_that_are_not_tiles() {
+        let template = NameTemplate::default();
+        for name in [
+            "1,2.txt",
+            "3,4.csv",
+            "1,2.png.bak",
+            "1,2.json",
+            "1,2",
+            "a,2.png",
+            "1;2.png",
+            "1,2,3.png",
+            ",2.png",
+            "1,.png",
+            "99999999999,0.png",
+            "tileproc-journal.txt",
+        ] {
+            assert_eq!(template.parse(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn quadkeys_only_take_tile_extensions() {
+        let template = template("{quadkey}.{ext}");
+        assert_eq!(template.parse("2023.csv"), None);
+        assert_eq!(template.parse("2024.png"), None);
+        assert_eq!(template.parse(".png"), None);
+        assert_eq!(template.parse("2023.png"), Some((1, 11)));
+        assert_eq!(template.parse("0.png"), Some((0, 0)));
+        // zoom levels need coordinates that fit in an i32
+        assert!(template.parse(&format!("{}.png", "1".repeat(31))).is_some());
+        assert_eq!(template.parse(&format!("{}.png", "1".repeat(32))), None);
+        assert_eq!(template.parse(&format!("{}1.png", "0".repeat(40))), None);
+    }
+
+    #[test]
+    fn extensions_ignore_case() {
+        let template = NameTemplate::default();
+        assert_eq!(template.parse("1,2.PNG"), Some((1, 2)));
+        assert_eq!(template.parse("1,2.Ktx2"), Some((1, 2)));
+    }
+
+    #[test]
+    fn quadkeys_only_name_tiles_of_their_zoom_level() {
+        let template = template("{quadkey}.{ext}");
+        assert!(template.can_name(0, 0, 1));
+        assert!(template.can_name(1, 1, 1));
+        assert!(!template.can_name(2, 0, 1));
+        assert!(!template.can_name(-1, 0, 1));
+        assert!(!template.can_name(0, -1, 1));
+        assert!(!template.can_name(0, 0, 0));
+        assert!(template.can_name(i32::MAX, 0, MAX_QUADKEY_ZOOM));
+        assert!(NameTemplate::default().can_name(-1, -1, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for invalid in [
+            "",
+            "{x}{y}.{ext}",
+            "{x},{y}.png",
+            "{x}.{ext}",
+            "{x},{x}.{ext}",
+            "{x},{y},{quadkey}.{ext}",
+            "{ext}-{x},{y}.{ext}",
+            "a/{x},{y}.{ext}",
+            "{z},{y}.{ext}",
+            "{x,{y}.{ext}",
+        ] {
+            assert!(invalid.parse::<NameTemplate>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use image::{DynamicImage, GenericImageView, Pixel, Primitive};
+use rayon::prelude::*;
+
+use crate::args::Color;
+use crate::pixel::{buffer, is_blank, normalized_rgba, with_alpha, with_pixel_type, TilePixel};
+
+/// Makes every pixel of an image within `tolerance` of `color` in each channel transparent black,
+/// the pixel tiles are filled with where there is no image. Channels are compared at 8 bits, so
+/// 16-bit and floating point images match the same colors as 8-bit ones.
+///
+/// Images without an alpha channel get one, keeping their bit depth.
+pub fn nodata_to_alpha(image: DynamicImage, color: Color, tolerance: u8) -> DynamicImage {
+    let image = with_alpha(image);
+
+    let nodata = color.0.map(|channel| channel as f64 / 255.0);
+    let tolerance = tolerance as f64 / 255.0;
+    // half of an 8-bit step, so that rounding doesn't decide whether a pixel matches
+    let epsilon = 0.5 / 255.0;
+
+    with_pixel_type!(image.color(), P => {
+        let mut buffer = P::from_dynamic(image);
+        buffer.par_chunks_mut(P::CHANNEL_COUNT as usize).for_each(|channels| {
+            let pixel = P::from_slice_mut(channels);
+            let rgba = normalized_rgba(pixel);
+            let matches = (0..3).all(|channel| {
+                (rgba[channel] - nodata[channel]).abs() <= tolerance + epsilon
+            });
+            if matches {
+                pixel
+                    .channels_mut()
+                    .iter_mut()
+                    .for_each(|channel| *channel = <P as Pixel>::Subpixel::DEFAULT_MIN_VALUE);
+            }
+        });
+        P::into_dynamic(buffer)
+    })
+}
+
+/// Returns the position and size of the smallest rectangle holding every pixel of an image that
+/// isn't transparent black, or `None` if there is no such pixel.
+pub fn content_bounds(image: &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+    with_pixel_type!(image.color(), P => content_bounds_of_type::<P>(image))
+}
+
+fn content_bounds_of_type<P: TilePixel>(image: &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+    let buffer = buffer::<P>(image);
+    let (width, height) = image.dimensions();
+
+    let row_is_blank = |y: u32| (0..width).all(|x| is_blank(buffer.get_pixel(x, y)));
+    let column_is_blank =
+        |x: u32, top: u32, bottom: u32| (top..=bottom).all(|y| is_blank(buffer.get_pixel(x, y)));
+
+    let top = (0..height).find(|&y| !row_is_blank(y))?;
+    let bottom = (top..height).rev().find(|&y| !row_is_blank(y))?;
+    let left = (0..width).find(|&x| !column_is_blank(x, top, bottom))?;
+    let right = (left..width)
+        .rev()
+        .find(|&x| !column_is_blank(x, top, bottom))?;
+
+    Some((left, top, right - left + 1, bottom - top + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb, RgbImage, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn tolerance_includes_its_boundary_in_every_channel() {
+        let pixels = [
+            [10, 20, 30],
+            [15, 25, 35],
+            [5, 15, 25],
+            [16, 20, 30],
+            [10, 14, 30],
+            [10, 20, 36],
+        ];
+        let image = RgbImage::from_fn(pixels.len() as u32, 1, |x, _| Rgb(pixels[x as usize]));
+
+        let image = nodata_to_alpha(DynamicImage::ImageRgb8(image), Color([10, 20, 30]), 5);
+        assert_eq!(image.color(), ColorType::Rgba8);
+        let image = image.into_rgba8();
+        for (x, pixel) in pixels.iter().enumerate() {
+            let expected = if x < 3 {
+                Rgba([0; 4])
+            } else {
+                Rgba([pixel[0], pixel[1], pixel[2], 255])
+            };
+            assert_eq!(*image.get_pixel(x as u32, 0), expected, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn deeper_images_match_at_8_bits() {
+        // 15 and 16 at 8 bits, the first within a tolerance of 5 of 10 and the second not
+        let image = ImageBuffer::from_fn(2, 1, |x, _| Rgb([[3855u16, 4112][x as usize]; 3]));
+
+        let image = nodata_to_alpha(DynamicImage::ImageRgb16(image), Color([10; 3]), 5);
+        assert_eq!(image.color(), ColorType::Rgba16);
+        let image = image.into_rgba16();
+        assert_eq!(*image.get_pixel(0, 0), Rgba([0; 4]));
+        assert_eq!(*image.get_pixel(1, 0), Rgba([4112, 4112, 4112, 65535]));
+    }
+
+    #[test]
+    fn content_bounds_hold_every_pixel_that_is_not_blank() {
+        let mut image = ImageBuffer::new(10, 8);
+        image.put_pixel(2, 5, Rgba([0u8, 0, 0, 255]));
+        image.put_pixel(7, 3, Rgba([255u8, 0, 0, 1]));
+        let image = DynamicImage::ImageRgba8(image);
+        assert_eq!(content_bounds(&image), Some((2, 3, 6, 3)));
+
+        assert_eq!(content_bounds(&DynamicImage::new_rgba8(4, 4)), None);
+    }
+}
@@ -0,0 +1,277 @@
+use std::borrow::Cow;
+
+use image::{
+    imageops::{self, FilterType},
+    ColorType, DynamicImage, ImageBuffer, ImageFormat, Luma, LumaA, Pixel, Primitive, Rgb, Rgba,
+};
+use num_traits::{NumCast, ToPrimitive};
+
+/// An image buffer of tile pixels.
+pub type TileBuffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// A pixel type tiles can be made of, one for every kind of image a `DynamicImage` holds, so
+/// that tiles keep the bit depth and channels of their source.
+pub trait TilePixel: Pixel<Subpixel: Send + Sync> + Send + Sync + 'static {
+    /// Whether the pixel has an alpha channel. Without one, no pixel is transparent.
+    const HAS_ALPHA: bool;
+
+    /// Returns the image buffer inside `image`, if it holds pixels of this type.
+    fn as_buffer(image: &DynamicImage) -> Option<&TileBuffer<Self>>;
+
+    /// Converts any image to pixels of this type.
+    fn convert(image: &DynamicImage) -> TileBuffer<Self>;
+
+    fn into_dynamic(buffer: TileBuffer<Self>) -> DynamicImage;
+
+    /// Takes the image buffer out of `image`, converting it only if it holds other pixels.
+    fn from_dynamic(image: DynamicImage) -> TileBuffer<Self>;
+}
+
+macro_rules! impl_tile_pixel {
+    ($pixel:ty, $variant:ident, $has_alpha:expr, $as_buffer:ident, $convert:ident) => {
+        impl TilePixel for $pixel {
+            const HAS_ALPHA: bool = $has_alpha;
+
+            fn as_buffer(image: &DynamicImage) -> Option<&TileBuffer<Self>> {
+                image.$as_buffer()
+            }
+
+            fn convert(image: &DynamicImage) -> TileBuffer<Self> {
+                image.$convert()
+            }
+
+            fn into_dynamic(buffer: TileBuffer<Self>) -> DynamicImage {
+                DynamicImage::$variant(buffer)
+            }
+
+            fn from_dynamic(image: DynamicImage) -> TileBuffer<Self> {
+                match image {
+                    DynamicImage::$variant(buffer) => buffer,
+                    image => image.$convert(),
+                }
+            }
+        }
+    };
+}
+
+impl_tile_pixel!(Luma<u8>, ImageLuma8, false, as_luma8, to_luma8);
+impl_tile_pixel!(LumaA<u8>, ImageLumaA8, true, as_luma_alpha8, to_luma_alpha8);
+impl_tile_pixel!(Rgb<u8>, ImageRgb8, false, as_rgb8, to_rgb8);
+impl_tile_pixel!(Rgba<u8>, ImageRgba8, true, as_rgba8, to_rgba8);
+impl_tile_pixel!(Luma<u16>, ImageLuma16, false, as_luma16, to_luma16);
+impl_tile_pixel!(
+    LumaA<u16>,
+    ImageLumaA16,
+    true,
+    as_luma_alpha16,
+    to_luma_alpha16
+);
+impl_tile_pixel!(Rgb<u16>, ImageRgb16, false, as_rgb16, to_rgb16);
+impl_tile_pixel!(Rgba<u16>, ImageRgba16, true, as_rgba16, to_rgba16);
+impl_tile_pixel!(Rgb<f32>, ImageRgb32F, false, as_rgb32f, to_rgb32f);
+impl_tile_pixel!(Rgba<f32>, ImageRgba32F, true, as_rgba32f, to_rgba32f);
+
+/// Evaluates `$body` with `$pixel` as the `TilePixel` type of a `ColorType`. Color types without
+/// a pixel type of their own are treated as RGBA8.
+macro_rules! with_pixel_type {
+    ($color_type:expr, $pixel:ident => $body:expr) => {
+        match $color_type {
+            ::image::ColorType::L8 => {
+                type $pixel = ::image::Luma<u8>;
+                $body
+            }
+            ::image::ColorType::La8 => {
+                type $pixel = ::image::LumaA<u8>;
+                $body
+            }
+            ::image::ColorType::Rgb8 => {
+                type $pixel = ::image::Rgb<u8>;
+                $body
+            }
+            ::image::ColorType::L16 => {
+                type $pixel = ::image::Luma<u16>;
+                $body
+            }
+            ::image::ColorType::La16 => {
+                type $pixel = ::image::LumaA<u16>;
+                $body
+            }
+            ::image::ColorType::Rgb16 => {
+                type $pixel = ::image::Rgb<u16>;
+                $body
+            }
+            ::image::ColorType::Rgba16 => {
+                type $pixel = ::image::Rgba<u16>;
+                $body
+            }
+            ::image::ColorType::Rgb32F => {
+                type $pixel = ::image::Rgb<f32>;
+                $body
+            }
+            ::image::ColorType::Rgba32F => {
+                type $pixel = ::image::Rgba<f32>;
+                $body
+            }
+            _ => {
+                type $pixel = ::image::Rgba<u8>;
+                $body
+            }
+        }
+    };
+}
+pub(crate) use with_pixel_type;
+
+/// Returns the image buffer inside `image` as pixels of type `P`, converting it only if it
+/// holds other pixels.
+pub fn buffer<P: TilePixel>(image: &DynamicImage) -> Cow<'_, TileBuffer<P>> {
+    match P::as_buffer(image) {
+        Some(buffer) => Cow::Borrowed(buffer),
+        None => Cow::Owned(P::convert(image)),
+    }
+}
+
+/// Converts an image to a color type.
+pub fn convert_to(image: &DynamicImage, color_type: ColorType) -> DynamicImage {
+    with_pixel_type!(color_type, P => P::into_dynamic(P::convert(image)))
+}
+
+/// Adds an alpha channel to an image that has none, keeping its bit depth.
+pub fn with_alpha(image: DynamicImage) -> DynamicImage {
+    let color_type = match image.color() {
+        ColorType::L8 => ColorType::La8,
+        ColorType::L16 => ColorType::La16,
+        ColorType::Rgb8 => ColorType::Rgba8,
+        ColorType::Rgb16 => ColorType::Rgba16,
+        ColorType::Rgb32F => ColorType::Rgba32F,
+        _ => return image,
+    };
+    convert_to(&image, color_type)
+}
+
+/// Resizes an image like `imageops::resize`, without clipping floating point channels brighter
+/// than 1.
+pub fn resize<P: TilePixel>(
+    image: &TileBuffer<P>,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> TileBuffer<P> {
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    let brightest = image
+        .iter()
+        .map(|channel| channel.to_f64().unwrap())
+        .fold(max, f64::max);
+    if brightest <= max || !brightest.is_finite() {
+        return imageops::resize(image, width, height, filter);
+    }
+
+    // only floating point channels can be brighter than their max, so scale them down around
+    // the filter, which clips them
+    let scale = |mut image: TileBuffer<P>, factor: f64| {
+        for channel in image.iter_mut() {
+            *channel = NumCast::from(channel.to_f64().unwrap() * factor).unwrap();
+        }
+        image
+    };
+    scale(
+        imageops::resize(
+            &scale(image.clone(), 1.0 / brightest),
+            width,
+            height,
+            filter,
+        ),
+        brightest,
+    )
+}
+
+/// Resizes an image to exactly `width` by `height`, keeping its pixel type, like `resize`.
+pub fn resize_image(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> DynamicImage {
+    with_pixel_type!(image.color(), P => {
+        P::into_dynamic(resize(&*buffer::<P>(image), width, height, filter))
+    })
+}
+
+/// Returns a tile of blank pixels, which is what tiles are filled with where there is no image:
+/// transparent black, or opaque black for pixels without an alpha channel.
+pub fn blank_tile<P: TilePixel>(width: u32, height: u32) -> TileBuffer<P> {
+    // every channel of a new buffer is 0
+    TileBuffer::new(width, height)
+}
+
+/// Returns true for a pixel that is transparent black, the pixel tiles are filled with where
+/// there is no image. Tiles whose pixels are all blank are empty, and are not written.
+///
+/// Pixels without an alpha channel are never blank, since their black fill can't be told apart
+/// from black in the image, so tiles without alpha are only empty where no image covers them.
+/// The parts of their edge tiles and LOD layer tiles outside the image are opaque black.
+pub fn is_blank<P: TilePixel>(pixel: &P) -> bool {
+    P::HAS_ALPHA
+        && pixel
+            .channels()
+            .iter()
+            .all(|channel| *channel == P::Subpixel::DEFAULT_MIN_VALUE)
+}
+
+/// Returns the alpha of a pixel, or `None` if it has no alpha channel.
+fn alpha<P: TilePixel>(pixel: &P) -> Option<P::Subpixel> {
+    P::HAS_ALPHA.then(|| *pixel.channels().last().unwrap())
+}
+
+/// Returns true for a pixel that completely covers what is below it.
+pub fn is_opaque<P: TilePixel>(pixel: &P) -> bool {
+    alpha(pixel).is_none_or(|alpha| alpha == P::Subpixel::DEFAULT_MAX_VALUE)
+}
+
+/// Returns true for a pixel with an alpha of 0.
+pub fn is_transparent<P: TilePixel>(pixel: &P) -> bool {
+    alpha(pixel) == Some(P::Subpixel::DEFAULT_MIN_VALUE)
+}
+
+/// Returns the RGBA channels of a pixel, scaled so that the max value of its subpixel type is 1.
+pub fn normalized_rgba<P: TilePixel>(pixel: &P) -> [f64; 4] {
+    let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+    pixel
+        .to_rgba()
+        .0
+        .map(|channel| channel.to_f64().unwrap() / max)
+}
+
+/// Returns the color type closest to `color_type` that images can be encoded in with `format`.
+///
+/// PNG and TIFF store 8 and 16 bits per channel, so floating point images are written with 16.
+/// JPEG and BMP only store 8 bits, JPEG without alpha, and OpenEXR only stores floats.
+pub fn encodable_color_type(color_type: ColorType, format: ImageFormat) -> ColorType {
+    let has_color = color_type.has_color();
+    let has_alpha = color_type.has_alpha();
+    let eight_bit = match (has_color, has_alpha) {
+        (false, false) => ColorType::L8,
+        (false, true) => ColorType::La8,
+        (true, false) => ColorType::Rgb8,
+        (true, true) => ColorType::Rgba8,
+    };
+
+    match format {
+        ImageFormat::Png => match color_type {
+            ColorType::Rgb32F => ColorType::Rgb16,
+            ColorType::Rgba32F => ColorType::Rgba16,
+            _ => color_type,
+        },
+        // TIFF has no gray with alpha
+        ImageFormat::Tiff => match color_type {
+            ColorType::La8 => ColorType::Rgba8,
+            ColorType::La16 | ColorType::Rgba32F => ColorType::Rgba16,
+            ColorType::Rgb32F => ColorType::Rgb16,
+            _ => color_type,
+        },
+        ImageFormat::Jpeg if has_color => ColorType::Rgb8,
+        ImageFormat::Jpeg => ColorType::L8,
+        ImageFormat::OpenExr if has_alpha => ColorType::Rgba32F,
+        ImageFormat::OpenExr => ColorType::Rgb32F,
+        _ => eight_bit,
+    }
+}
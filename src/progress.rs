@@ -0,0 +1,134 @@
+use std::{
+    sync::{mpsc, Mutex},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+/// Something that happened while generating tiles.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A status message, such as "decoding image...".
+    Message { message: String },
+    /// Work on a LOD layer started. Layer 0 holds the tiles sliced from the source image.
+    LevelStarted { level: u32, total_tiles: u64 },
+    /// A tile was written, found to be empty, or skipped because a previous run finished it.
+    TileDone {
+        level: u32,
+        tiles_done: u64,
+        total_tiles: u64,
+        /// The bytes written to tiles of this layer so far.
+        bytes_written: u64,
+        /// Estimated seconds until the layer is finished.
+        eta_secs: Option<f64>,
+    },
+    /// Every tile of a LOD layer is done.
+    LevelFinished {
+        level: u32,
+        tiles_done: u64,
+        bytes_written: u64,
+    },
+}
+
+struct LevelState {
+    level: u32,
+    started: Instant,
+    tiles_done: u64,
+    total_tiles: u64,
+    bytes_written: u64,
+}
+
+type Callback = Box<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+/// Reports the progress of tiling functions to a callback.
+///
+/// Tiles are processed on many threads at once, so the callback is called from all of them.
+#[derive(Default)]
+pub struct Progress {
+    callback: Option<Callback>,
+    level: Mutex<Option<LevelState>>,
+}
+
+impl Progress {
+    /// Reports progress by calling `callback` with every event.
+    pub fn new(callback: impl Fn(&ProgressEvent) + Send + Sync + 'static) -> Progress {
+        Progress {
+            callback: Some(Box::new(callback)),
+            level: Mutex::new(None),
+        }
+    }
+
+    /// Doesn't report progress anywhere.
+    pub fn none() -> Progress {
+        Progress::default()
+    }
+
+    /// Reports progress by sending every event to the returned receiver.
+    pub fn channel() -> (Progress, mpsc::Receiver<ProgressEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let progress = Progress::new(move |event| {
+            // the receiver may have stopped listening
+            let _ = sender.send(event.clone());
+        });
+        (progress, receiver)
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.callback {
+            callback(&event);
+        }
+    }
+
+    pub(crate) fn message(&self, message: &str) {
+        self.report(ProgressEvent::Message {
+            message: message.to_string(),
+        });
+    }
+
+    pub(crate) fn start_level(&self, level: u32, total_tiles: u64) {
+        *self.level.lock().unwrap() = Some(LevelState {
+            level,
+            started: Instant::now(),
+            tiles_done: 0,
+            total_tiles,
+            bytes_written: 0,
+        });
+        self.report(ProgressEvent::LevelStarted { level, total_tiles });
+    }
+
+    pub(crate) fn tile_done(&self, bytes_written: u64) {
+        // report while holding the lock, so that tiles_done only ever counts up
+        let mut state = self.level.lock().unwrap();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+
+        state.tiles_done += 1;
+        state.bytes_written += bytes_written;
+
+        let eta_secs = (state.tiles_done < state.total_tiles).then(|| {
+            let secs_per_tile = state.started.elapsed().as_secs_f64() / state.tiles_done as f64;
+            secs_per_tile * (state.total_tiles - state.tiles_done) as f64
+        });
+
+        self.report(ProgressEvent::TileDone {
+            level: state.level,
+            tiles_done: state.tiles_done,
+            total_tiles: state.total_tiles,
+            bytes_written: state.bytes_written,
+            eta_secs,
+        });
+    }
+
+    pub(crate) fn finish_level(&self) {
+        let state = self.level.lock().unwrap().take();
+        if let Some(state) = state {
+            self.report(ProgressEvent::LevelFinished {
+                level: state.level,
+                tiles_done: state.tiles_done,
+                bytes_written: state.bytes_written,
+            });
+        }
+    }
+}
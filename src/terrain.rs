@@ -0,0 +1,561 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use image::{io::Reader, DynamicImage, GrayAlphaImage, LumaA, Pixel, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+use crate::args::{GenTerrainArgs, TerrainEncoding};
+use crate::coords::{tile_origin, tile_range};
+use crate::error::TileError;
+use crate::georef::GeoTransform;
+use crate::mercator::{ground_pixel_size, read_source_crs};
+use crate::naming::NameTemplate;
+use crate::tiler::{dir_paths, generate_lods, prepare_output_dir, JobContext};
+
+/// The largest value the 24 bits of a terrain-RGB pixel can encode.
+const MAX_ENCODED_VALUE: f64 = 16_777_215.0;
+
+/// The heights of an elevation raster or tile, row by row. Pixels without a height are NaN.
+struct HeightMap {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+impl HeightMap {
+    fn new(width: u32, height: u32) -> HeightMap {
+        HeightMap {
+            width,
+            height,
+            values: vec![f32::NAN; width as usize * height as usize],
+        }
+    }
+
+    fn from_pixels<P: Pixel>(
+        buffer: &image::ImageBuffer<P, Vec<P::Subpixel>>,
+        height: impl Fn(&P) -> f32,
+    ) -> HeightMap {
+        HeightMap {
+            width: buffer.width(),
+            height: buffer.height(),
+            values: buffer.pixels().map(height).collect(),
+        }
+    }
+
+    /// Reads the heights of an elevation raster from the first channel of its pixels. Pixels
+    /// with an alpha of 0 have no height.
+    fn from_image(image: &DynamicImage) -> HeightMap {
+        let with_alpha = |height: f32, alpha: bool| if alpha { height } else { f32::NAN };
+
+        match image {
+            DynamicImage::ImageLuma8(buffer) => HeightMap::from_pixels(buffer, |p| p[0] as f32),
+            DynamicImage::ImageLumaA8(buffer) => {
+                HeightMap::from_pixels(buffer, |p| with_alpha(p[0] as f32, p[1] != 0))
+            }
+            DynamicImage::ImageLuma16(buffer) => HeightMap::from_pixels(buffer, |p| p[0] as f32),
+            DynamicImage::ImageRgb32F(buffer) => HeightMap::from_pixels(buffer, |p| p[0]),
+            DynamicImage::ImageRgba32F(buffer) => {
+                HeightMap::from_pixels(buffer, |p| with_alpha(p[0], p[3] > 0.0))
+            }
+            image => HeightMap::from_pixels(&image.to_luma_alpha16(), |p| {
+                with_alpha(p[0] as f32, p[1] != 0)
+            }),
+        }
+    }
+
+    fn index(&self, x: i64, y: i64) -> Option<usize> {
+        (x >= 0 && y >= 0 && x < self.width as i64 && y < self.height as i64)
+            .then(|| y as usize * self.width as usize + x as usize)
+    }
+
+    /// Returns the height at a pixel, or NaN if it has none or is outside of the map.
+    fn get(&self, x: i64, y: i64) -> f32 {
+        self.index(x, y)
+            .map_or(f32::NAN, |index| self.values[index])
+    }
+
+    fn set(&mut self, x: u32, y: u32, height: f32) {
+        let index = y as usize * self.width as usize + x as usize;
+        self.values[index] = height;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.values.iter().all(|height| height.is_nan())
+    }
+
+    /// Halves the map, averaging the heights of every 2x2 block of pixels that have one.
+    fn shrink(&self) -> HeightMap {
+        let mut shrunk = HeightMap::new(self.width / 2, self.height / 2);
+
+        for y in 0..shrunk.height {
+            for x in 0..shrunk.width {
+                let (sum, count) = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .iter()
+                    .map(|(dx, dy)| self.get((x * 2 + dx) as i64, (y * 2 + dy) as i64))
+                    .filter(|height| !height.is_nan())
+                    .fold((0.0, 0), |(sum, count), height| {
+                        (sum + height as f64, count + 1)
+                    });
+
+                if count > 0 {
+                    shrunk.set(x, y, (sum / count as f64) as f32);
+                }
+            }
+        }
+        shrunk
+    }
+}
+
+/// Encodes heights into, and decodes them from, terrain-RGB pixels.
+#[derive(Debug, Clone, Copy)]
+struct TerrainCodec {
+    base: f64,
+    /// The height difference between consecutive 24-bit values of a pixel.
+    step: f64,
+}
+
+impl TerrainCodec {
+    fn new(encoding: TerrainEncoding, base: Option<f64>, interval: Option<f64>) -> TerrainCodec {
+        match encoding {
+            TerrainEncoding::Mapbox => TerrainCodec {
+                base: base.unwrap_or(-10000.0),
+                step: interval.unwrap_or(0.1),
+            },
+            // the blue channel of Terrarium holds fractions of an interval
+            TerrainEncoding::Terrarium => TerrainCodec {
+                base: base.unwrap_or(-32768.0),
+                step: interval.unwrap_or(1.0) / 256.0,
+            },
+        }
+    }
+
+    /// Encodes a height into an opaque pixel, or a transparent pixel if there is none. Heights
+    /// out of the range of the encoding are clamped.
+    fn encode(&self, height: f32) -> Rgba<u8> {
+        if height.is_nan() {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let value = ((height as f64 - self.base) / self.step)
+            .round()
+            .clamp(0.0, MAX_ENCODED_VALUE) as u32;
+        Rgba([(value >> 16) as u8, (value >> 8) as u8, value as u8, 255])
+    }
+
+    fn decode(&self, pixel: Rgba<u8>) -> f32 {
+        if pixel[3] == 0 {
+            return f32::NAN;
+        }
+        let value = ((pixel[0] as u32) << 16) | ((pixel[1] as u32) << 8) | pixel[2] as u32;
+        (self.base + value as f64 * self.step) as f32
+    }
+
+    fn encode_map(&self, heights: &HeightMap) -> RgbaImage {
+        RgbaImage::from_fn(heights.width, heights.height, |x, y| {
+            self.encode(heights.get(x as i64, y as i64))
+        })
+    }
+}
+
+/// Shades the slopes of a height map as if lit by the sun, with Horn's method.
+#[derive(Debug, Clone, Copy)]
+struct Hillshade {
+    /// The width and height of a pixel, in height units.
+    pixel_size: (f64, f64),
+    z_factor: f64,
+    /// The angle of the sun from straight up, in radians.
+    zenith: f64,
+    /// The direction of the sun, in radians counterclockwise from east.
+    azimuth: f64,
+}
+
+impl Hillshade {
+    fn new(args: &GenTerrainArgs, pixel_size: (f64, f64)) -> Hillshade {
+        Hillshade {
+            pixel_size,
+            z_factor: args.z_factor,
+            zenith: (90.0 - args.sun_altitude).to_radians(),
+            azimuth: (450.0 - args.sun_azimuth).rem_euclid(360.0).to_radians(),
+        }
+    }
+
+    /// Returns the brightness of a pixel of a height map from 0 to 1, or `None` if the pixel has
+    /// no height. Neighbors without a height are taken to be as high as the pixel.
+    fn shade(&self, heights: &HeightMap, x: i64, y: i64) -> Option<f64> {
+        let center = heights.get(x, y);
+        if center.is_nan() {
+            return None;
+        }
+        let z = |dx: i64, dy: i64| {
+            let height = heights.get(x + dx, y + dy);
+            if height.is_nan() {
+                center as f64
+            } else {
+                height as f64
+            }
+        };
+
+        let dz_dx = ((z(1, -1) + 2.0 * z(1, 0) + z(1, 1))
+            - (z(-1, -1) + 2.0 * z(-1, 0) + z(-1, 1)))
+            / (8.0 * self.pixel_size.0);
+        let dz_dy = ((z(-1, 1) + 2.0 * z(0, 1) + z(1, 1))
+            - (z(-1, -1) + 2.0 * z(0, -1) + z(1, -1)))
+            / (8.0 * self.pixel_size.1);
+
+        let slope = (self.z_factor * dz_dx.hypot(dz_dy)).atan();
+        let aspect = dz_dy.atan2(-dz_dx);
+
+        let shade = self.zenith.cos() * slope.cos()
+            + self.zenith.sin() * slope.sin() * (self.azimuth - aspect).cos();
+        Some(shade.clamp(0.0, 1.0))
+    }
+
+    /// Shades the pixels of a height map that a tile covers. `origin` is the pixel of the map
+    /// at the tile's top left corner.
+    fn shade_tile(
+        &self,
+        heights: &HeightMap,
+        origin: (i64, i64),
+        tile_dimensions: u32,
+    ) -> GrayAlphaImage {
+        GrayAlphaImage::from_fn(tile_dimensions, tile_dimensions, |x, y| {
+            match self.shade(heights, origin.0 + x as i64, origin.1 + y as i64) {
+                Some(shade) => LumaA([(shade * 255.0).round() as u8, 255]),
+                None => LumaA([0, 0]),
+            }
+        })
+    }
+}
+
+/// Slices a height map into layer 0 terrain tiles, and hillshade tiles if `hillshade` is set.
+/// Tiles without any height are not written.
+fn slice_heights(
+    heights: &HeightMap,
+    offset: (i32, i32),
+    codec: TerrainCodec,
+    hillshade: Option<(&Hillshade, &Path)>,
+    output_dir: &Path,
+    tile_dimensions: u32,
+    context: JobContext,
+) -> Result<(), TileError> {
+    let (top_left_sector, bottom_right_sector) = tile_range(
+        (-offset.0 as i64, -offset.1 as i64),
+        (
+            heights.width as i64 - 1 - offset.0 as i64,
+            heights.height as i64 - 1 - offset.1 as i64,
+        ),
+        (tile_dimensions, tile_dimensions),
+        0,
+    )
+    .ok_or(TileError::TileOutOfRange)?;
+    let writer = &context.writer.reaching(bottom_right_sector);
+    let context = JobContext { writer, ..context };
+    context
+        .writer
+        .check_tile_names(top_left_sector, bottom_right_sector)?;
+    let sectors: Vec<(i32, i32)> = (top_left_sector.1..=bottom_right_sector.1)
+        .flat_map(|y| (top_left_sector.0..=bottom_right_sector.0).map(move |x| (x, y)))
+        .collect();
+    context.progress.start_level(0, sectors.len() as u64);
+
+    sectors.par_iter().try_for_each(|&(sector_x, sector_y)| {
+        context.cancel.check()?;
+
+        // the pixel of the height map at the top left corner of the tile
+        let (tile_x, tile_y) =
+            tile_origin((sector_x, sector_y), (tile_dimensions, tile_dimensions), 0).unwrap();
+        let origin = (tile_x + offset.0 as i64, tile_y + offset.1 as i64);
+
+        let mut tile_heights = HeightMap::new(tile_dimensions, tile_dimensions);
+        for y in 0..tile_dimensions {
+            for x in 0..tile_dimensions {
+                tile_heights.set(x, y, heights.get(origin.0 + x as i64, origin.1 + y as i64));
+            }
+        }
+
+        let mut bytes_written = 0;
+        if !tile_heights.is_empty() {
+            let file_name = context.writer.tile_file_name(sector_x, sector_y);
+            let path = output_dir.join(&file_name);
+            bytes_written += context
+                .writer
+                .write(
+                    &DynamicImage::ImageRgba8(codec.encode_map(&tile_heights)),
+                    &path,
+                )
+                .map_err(TileError::image(&path))?;
+
+            if let Some((hillshade, hillshade_dir)) = hillshade {
+                let path = hillshade_dir.join(&file_name);
+                bytes_written += context
+                    .writer
+                    .write(
+                        &DynamicImage::ImageLumaA8(hillshade.shade_tile(
+                            heights,
+                            origin,
+                            tile_dimensions,
+                        )),
+                        &path,
+                    )
+                    .map_err(TileError::image(&path))?;
+            }
+        }
+
+        context.progress.tile_done(bytes_written);
+        Ok(())
+    })?;
+
+    context.progress.finish_level();
+    Ok(())
+}
+
+/// Generates the terrain tiles of a LOD layer from the decoded heights of the layer below it,
+/// since averaging encoded pixels would mix up the bytes of their heights.
+fn shrink_terrain_tiles(
+    input_files: &[TileFile],
+    output_dir: &Path,
+    level: u32,
+    codec: TerrainCodec,
+    tile_dimensions: u32,
+    context: JobContext,
+) -> Result<(), TileError> {
+    // the quadkeys of the layer are one digit shorter than those of the tiles below it
+    let writer = &context
+        .writer
+        .at_zoom_of(input_files.iter().map(|file| &file.path))
+        .above();
+    let context = JobContext { writer, ..context };
+    prepare_output_dir(output_dir, context)?;
+
+    let mut children: HashMap<(i32, i32), Vec<&TileFile>> = HashMap::new();
+    for file in input_files {
+        let (x, y) = file.coords;
+        children
+            .entry((x.div_euclid(2), y.div_euclid(2)))
+            .or_default()
+            .push(file);
+    }
+    let children: Vec<_> = children.into_iter().collect();
+    context.progress.start_level(level, children.len() as u64);
+
+    children
+        .par_iter()
+        .try_for_each(|((parent_x, parent_y), tiles)| {
+            context.cancel.check()?;
+
+            // the heights of the (up to) 4 tiles below, side by side
+            let mut heights = HeightMap::new(tile_dimensions * 2, tile_dimensions * 2);
+            for file in tiles {
+                let (x, y) = file.coords;
+                let tile = image::open(&file.path)
+                    .map_err(TileError::image(&file.path))?
+                    .into_rgba8();
+                let corner = (
+                    (x - parent_x * 2) as u32 * tile_dimensions,
+                    (y - parent_y * 2) as u32 * tile_dimensions,
+                );
+                for (tile_x, tile_y, pixel) in tile.enumerate_pixels() {
+                    if tile_x < tile_dimensions && tile_y < tile_dimensions {
+                        heights.set(corner.0 + tile_x, corner.1 + tile_y, codec.decode(*pixel));
+                    }
+                }
+            }
+
+            let path = output_dir.join(context.writer.tile_file_name(*parent_x, *parent_y));
+            let bytes_written = context
+                .writer
+                .write(
+                    &DynamicImage::ImageRgba8(codec.encode_map(&heights.shrink())),
+                    &path,
+                )
+                .map_err(TileError::image(&path))?;
+
+            context.progress.tile_done(bytes_written);
+            Ok(())
+        })?;
+
+    context.progress.finish_level();
+    Ok(())
+}
+
+/// A tile file and the coordinates of its tile.
+struct TileFile {
+    coords: (i32, i32),
+    path: PathBuf,
+}
+
+/// Returns the tiles named by `name_template` directly inside a directory.
+fn tiles_in_dir(dir: &Path, name_template: &NameTemplate) -> Result<Vec<TileFile>, TileError> {
+    Ok(dir_paths(dir)?
+        .into_iter()
+        .filter_map(|path| {
+            Some(TileFile {
+                coords: name_template.parse_path(&path)?,
+                path,
+            })
+        })
+        .collect())
+}
+
+/// Returns the width and height of the pixels of the elevation raster of `args` from its
+/// georeference. Pixels in degrees or Web Mercator meters are measured on the ground in the
+/// middle of the raster, since heights are in meters.
+fn hillshade_pixel_size(
+    args: &GenTerrainArgs,
+    dimensions: (u32, u32),
+) -> Result<(f64, f64), TileError> {
+    let Some(transform) = GeoTransform::read(&args.input)
+        .map_err(|err| TileError::InvalidGeoreference(err.to_string()))?
+    else {
+        return Ok((1.0, 1.0));
+    };
+
+    Ok(match read_source_crs(&args.input, args.source_crs)? {
+        Some(crs) => {
+            let center = (
+                transform.origin.0 + dimensions.0 as f64 / 2.0 * transform.pixel_size.0,
+                transform.origin.1 + dimensions.1 as f64 / 2.0 * transform.pixel_size.1,
+            );
+            ground_pixel_size(&transform, crs, center)
+        }
+        None => (transform.pixel_size.0, -transform.pixel_size.1),
+    })
+}
+
+/// Slices the elevation raster of `args` into terrain-RGB tiles in the "0" directory of its
+/// output directory, and generates LOD layers above it until the top one has 4 tiles or less,
+/// like `gen-tile-layers`.
+///
+/// LOD tiles are encoded from the averaged heights of the tiles below them. With a hillshade
+/// output directory, hillshade tiles are shaded from the full resolution raster, so that they
+/// line up seamlessly, and shrunk into LOD layers like image tiles.
+pub fn gen_terrain_tiles(args: &GenTerrainArgs, context: JobContext) -> Result<(), TileError> {
+    args.validate().map_err(TileError::InvalidArgs)?;
+    let codec = TerrainCodec::new(args.encoding, args.base, args.interval);
+    let tile_dimensions = args.tile_dimensions;
+
+    // get input image dimensions
+    let dimensions = Reader::open(&args.input)
+        .map_err(TileError::io(&args.input))?
+        .into_dimensions()
+        .map_err(TileError::image(&args.input))?;
+
+    let hillshade = match &args.hillshade_output {
+        Some(hillshade_dir) => {
+            let pixel_size = match args.pixel_size {
+                Some(pixel_size) => (pixel_size, pixel_size),
+                None => hillshade_pixel_size(args, dimensions)?,
+            };
+            Some((Hillshade::new(args, pixel_size), hillshade_dir.join("0")))
+        }
+        None => None,
+    };
+    let offset = (
+        args.x_offset
+            .unwrap_or_else(|| (dimensions.0 / 2).try_into().unwrap()),
+        args.y_offset
+            .unwrap_or_else(|| (dimensions.1 / 2).try_into().unwrap()),
+    );
+
+    context.progress.message("decoding image...");
+    let heights =
+        HeightMap::from_image(&image::open(&args.input).map_err(TileError::image(&args.input))?);
+
+    context.progress.message("slicing tiles...");
+    let output_dir = args.output.join("0");
+    prepare_output_dir(&output_dir, context)?;
+    if let Some((_, hillshade_dir)) = &hillshade {
+        prepare_output_dir(hillshade_dir, context)?;
+    }
+
+    slice_heights(
+        &heights,
+        offset,
+        codec,
+        hillshade
+            .as_ref()
+            .map(|(hillshade, dir)| (hillshade, dir.as_path())),
+        &output_dir,
+        tile_dimensions,
+        context,
+    )?;
+    drop(heights);
+
+    let mut level = 0;
+    loop {
+        let files = tiles_in_dir(
+            &args.output.join(level.to_string()),
+            &context.writer.name_template,
+        )?;
+        if files.len() <= 4 {
+            break;
+        }
+        level += 1;
+        shrink_terrain_tiles(
+            &files,
+            &args.output.join(level.to_string()),
+            level,
+            codec,
+            tile_dimensions,
+            context,
+        )?;
+    }
+
+    if let Some(hillshade_dir) = &args.hillshade_output {
+        generate_lods(hillshade_dir, context)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that heights survive being encoded and decoded, give or take half a step.
+    fn assert_round_trips(codec: TerrainCodec, heights: &[f32]) {
+        for &height in heights {
+            let decoded = codec.decode(codec.encode(height));
+            assert!(
+                (decoded as f64 - height as f64).abs() <= codec.step / 2.0 + 1e-3,
+                "{height} decoded as {decoded}"
+            );
+        }
+    }
+
+    #[test]
+    fn mapbox_heights_round_trip() {
+        let codec = TerrainCodec::new(TerrainEncoding::Mapbox, None, None);
+        // height = -10000 + (r * 256 * 256 + g * 256 + b) * 0.1
+        assert_eq!(codec.encode(0.0), Rgba([1, 134, 160, 255]));
+        assert_eq!(codec.decode(Rgba([1, 134, 160, 255])), 0.0);
+        assert_round_trips(codec, &[-10000.0, -432.1, 0.0, 0.05, 1234.5, 8848.9]);
+
+        // heights below the base are clamped, and pixels without a height are transparent
+        assert_eq!(codec.encode(-20000.0), Rgba([0, 0, 0, 255]));
+        assert_eq!(codec.encode(f32::NAN), Rgba([0, 0, 0, 0]));
+        assert!(codec.decode(Rgba([0, 0, 0, 0])).is_nan());
+    }
+
+    #[test]
+    fn terrarium_heights_round_trip() {
+        let codec = TerrainCodec::new(TerrainEncoding::Terrarium, None, None);
+        // height = r * 256 + g + b / 256 - 32768
+        assert_eq!(codec.encode(0.0), Rgba([128, 0, 0, 255]));
+        assert_eq!(codec.encode(1.5), Rgba([128, 1, 128, 255]));
+        assert_eq!(codec.decode(Rgba([127, 255, 0, 255])), -1.0);
+        assert_round_trips(codec, &[-32768.0, -10994.0, -0.25, 0.0, 1234.5, 8848.9]);
+
+        assert_eq!(codec.encode(40000.0), Rgba([255, 255, 255, 255]));
+        assert!(codec.decode(codec.encode(f32::NAN)).is_nan());
+    }
+
+    #[test]
+    fn custom_bases_and_intervals_round_trip() {
+        for encoding in [TerrainEncoding::Mapbox, TerrainEncoding::Terrarium] {
+            let codec = TerrainCodec::new(encoding, Some(-500.0), Some(0.01));
+            assert_eq!(codec.decode(codec.encode(-500.0)), -500.0);
+            assert_round_trips(codec, &[-500.0, -123.45, 0.0, 42.42, 150.0]);
+        }
+    }
+}
@@ -0,0 +1,1111 @@
+use std::{
+    array, fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    imageops::{self, FilterType},
+    DynamicImage, ImageError, ImageResult, RgbaImage,
+};
+
+use crate::args::BlockCompression;
+
+/// The file formats block compressed tiles are stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureContainer {
+    Ktx2,
+    Dds,
+}
+
+impl TextureContainer {
+    /// Returns the container a path's extension names, or `None` for other images.
+    pub fn from_path(path: &Path) -> Option<TextureContainer> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ktx2" => Some(TextureContainer::Ktx2),
+            "dds" => Some(TextureContainer::Dds),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TextureContainer::Ktx2 => "KTX2",
+            TextureContainer::Dds => "DDS",
+        }
+    }
+}
+
+impl BlockCompression {
+    /// The size of one compressed 4x4 block.
+    fn block_bytes(self) -> usize {
+        match self {
+            BlockCompression::Bc1 => 8,
+            BlockCompression::Bc3 | BlockCompression::Bc7 => 16,
+        }
+    }
+
+    /// The sRGB `VkFormat` of KTX2 textures.
+    fn vk_format(self) -> u32 {
+        match self {
+            BlockCompression::Bc1 => 134,
+            BlockCompression::Bc3 => 138,
+            BlockCompression::Bc7 => 146,
+        }
+    }
+
+    fn from_vk_format(vk_format: u32) -> Option<BlockCompression> {
+        match vk_format {
+            133 | 134 => Some(BlockCompression::Bc1),
+            137 | 138 => Some(BlockCompression::Bc3),
+            145 | 146 => Some(BlockCompression::Bc7),
+            _ => None,
+        }
+    }
+
+    /// The sRGB `DXGI_FORMAT` of DDS textures.
+    fn dxgi_format(self) -> u32 {
+        match self {
+            BlockCompression::Bc1 => 72,
+            BlockCompression::Bc3 => 78,
+            BlockCompression::Bc7 => 99,
+        }
+    }
+
+    fn from_dxgi_format(dxgi_format: u32) -> Option<BlockCompression> {
+        match dxgi_format {
+            71 | 72 => Some(BlockCompression::Bc1),
+            77 | 78 => Some(BlockCompression::Bc3),
+            98 | 99 => Some(BlockCompression::Bc7),
+            _ => None,
+        }
+    }
+}
+
+/// The RGBA pixels of a 4x4 block, row by row.
+type Block = [[u8; 4]; 16];
+
+/// Returns the 4x4 block of pixels at block `block_x`, `block_y` of an image, repeating the
+/// image's edge pixels where the block reaches past them.
+fn read_block(image: &RgbaImage, block_x: u32, block_y: u32) -> Block {
+    array::from_fn(|i| {
+        let x = (block_x * 4 + i as u32 % 4).min(image.width() - 1);
+        let y = (block_y * 4 + i as u32 / 4).min(image.height() - 1);
+        image.get_pixel(x, y).0
+    })
+}
+
+/// Returns the mean of `points`, and the unit direction they vary along the most, which is zero
+/// if all points are the same.
+fn principal_axis<const N: usize>(points: &[[f32; N]]) -> ([f32; N], [f32; N]) {
+    let count = points.len() as f32;
+    let mean: [f32; N] =
+        array::from_fn(|c| points.iter().map(|point| point[c]).sum::<f32>() / count);
+
+    let covariance: [[f32; N]; N] = array::from_fn(|i| {
+        array::from_fn(|j| {
+            points
+                .iter()
+                .map(|point| (point[i] - mean[i]) * (point[j] - mean[j]))
+                .sum()
+        })
+    });
+
+    // power iteration, starting from the channel that varies the most
+    let start = (0..N)
+        .max_by(|&a, &b| covariance[a][a].total_cmp(&covariance[b][b]))
+        .unwrap();
+    if covariance[start][start] <= 0.0 {
+        return (mean, [0.0; N]);
+    }
+
+    let mut axis: [f32; N] = array::from_fn(|c| if c == start { 1.0 } else { 0.0 });
+    for _ in 0..8 {
+        let next: [f32; N] = array::from_fn(|i| {
+            covariance[i]
+                .iter()
+                .zip(&axis)
+                .map(|(covariance, axis)| covariance * axis)
+                .sum()
+        });
+        let length = next.iter().map(|c| c * c).sum::<f32>().sqrt();
+        if length == 0.0 {
+            break;
+        }
+        axis = next.map(|c| c / length);
+    }
+    (mean, axis)
+}
+
+/// Returns the two ends of the part of the line along `axis` through `mean` that `points` are
+/// projected onto, clamped to 8-bit colors.
+fn line_endpoints<const N: usize>(
+    points: &[[f32; N]],
+    mean: [f32; N],
+    axis: [f32; N],
+) -> ([f32; N], [f32; N]) {
+    let project = |point: &[f32; N]| -> f32 {
+        point
+            .iter()
+            .zip(&mean)
+            .zip(&axis)
+            .map(|((point, mean), axis)| (point - mean) * axis)
+            .sum()
+    };
+    let (min, max) = points
+        .iter()
+        .map(project)
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), t| {
+            (min.min(t), max.max(t))
+        });
+    let at = |t: f32| array::from_fn(|c| (mean[c] + axis[c] * t).clamp(0.0, 255.0));
+    (at(min), at(max))
+}
+
+/// Returns the index of the palette color closest to `color`, and its squared distance.
+fn nearest<const N: usize>(palette: &[[i32; N]], color: [i32; N]) -> (usize, u32) {
+    palette
+        .iter()
+        .map(|entry| {
+            entry
+                .iter()
+                .zip(&color)
+                .map(|(a, b)| (a - b).pow(2) as u32)
+                .sum::<u32>()
+        })
+        .enumerate()
+        .min_by_key(|&(_, distance)| distance)
+        .unwrap()
+}
+
+fn to_565(color: [f32; 3]) -> u16 {
+    let r = (color[0] * 31.0 / 255.0).round() as u16;
+    let g = (color[1] * 63.0 / 255.0).round() as u16;
+    let b = (color[2] * 31.0 / 255.0).round() as u16;
+    (r << 11) | (g << 5) | b
+}
+
+fn from_565(color: u16) -> [i32; 3] {
+    let r = ((color >> 11) & 31) as i32;
+    let g = ((color >> 5) & 63) as i32;
+    let b = (color & 31) as i32;
+    [
+        (r << 3) | (r >> 2),
+        (g << 2) | (g >> 4),
+        (b << 3) | (b >> 2),
+    ]
+}
+
+/// The RGBA colors of a BC1 block. Four color blocks interpolate two colors between their
+/// endpoints, three color blocks one, and have transparent black as their fourth color.
+fn bc1_palette(color0: u16, color1: u16, four_colors: bool) -> [[i32; 4]; 4] {
+    let (a, b) = (from_565(color0), from_565(color1));
+    let mix = |weight_a: i32, weight_b: i32| -> [i32; 4] {
+        let [r, g, b] =
+            array::from_fn(|c| (weight_a * a[c] + weight_b * b[c]) / (weight_a + weight_b));
+        [r, g, b, 255]
+    };
+
+    if four_colors {
+        [mix(1, 0), mix(0, 1), mix(2, 1), mix(1, 2)]
+    } else {
+        [mix(1, 0), mix(0, 1), mix(1, 1), [0; 4]]
+    }
+}
+
+/// Compresses the colors of a block into BC1. With `alpha`, pixels with an alpha below 128 are
+/// made transparent, otherwise alpha is ignored, as in the color half of a BC3 block.
+fn encode_bc1(block: &Block, alpha: bool) -> [u8; 8] {
+    let is_transparent = |pixel: &[u8; 4]| alpha && pixel[3] < 128;
+    let colors: Vec<[f32; 3]> = block
+        .iter()
+        .filter(|pixel| !is_transparent(pixel))
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]].map(f32::from))
+        .collect();
+
+    let (mut color0, mut color1) = if colors.is_empty() {
+        (0, 0)
+    } else {
+        let (mean, axis) = principal_axis(&colors);
+        let (low, high) = line_endpoints(&colors, mean, axis);
+        (to_565(high), to_565(low))
+    };
+
+    // the order of the endpoints selects four colors, or three and transparent
+    let four_colors = colors.len() == block.len();
+    if four_colors == (color0 < color1) {
+        (color0, color1) = (color1, color0);
+    }
+
+    let palette = bc1_palette(color0, color1, four_colors);
+    let opaque_colors = if four_colors { 4 } else { 3 };
+    let rgb_palette: Vec<[i32; 3]> = palette[..opaque_colors]
+        .iter()
+        .map(|color| [color[0], color[1], color[2]])
+        .collect();
+    let mut indices = 0u32;
+    for (i, pixel) in block.iter().enumerate() {
+        let index = if is_transparent(pixel) {
+            3
+        } else {
+            nearest(&rgb_palette, [pixel[0], pixel[1], pixel[2]].map(i32::from)).0
+        };
+        indices |= (index as u32) << (2 * i);
+    }
+
+    let mut bytes = [0; 8];
+    bytes[0..2].copy_from_slice(&color0.to_le_bytes());
+    bytes[2..4].copy_from_slice(&color1.to_le_bytes());
+    bytes[4..8].copy_from_slice(&indices.to_le_bytes());
+    bytes
+}
+
+/// Decodes a BC1 block. Without `three_colors`, blocks always have four colors, as in the color
+/// half of a BC3 block.
+fn decode_bc1(bytes: &[u8], three_colors: bool) -> Block {
+    let color0 = u16::from_le_bytes([bytes[0], bytes[1]]);
+    let color1 = u16::from_le_bytes([bytes[2], bytes[3]]);
+    let indices = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+
+    let palette = bc1_palette(color0, color1, !three_colors || color0 > color1);
+    array::from_fn(|i| palette[((indices >> (2 * i)) & 3) as usize].map(|c| c as u8))
+}
+
+/// The alphas of a BC3 block. Blocks whose first endpoint is larger interpolate six alphas
+/// between their endpoints, the others four, and have 0 and 255 as their last alphas.
+fn bc3_alpha_palette(alpha0: u8, alpha1: u8) -> [i32; 8] {
+    let (a, b) = (i32::from(alpha0), i32::from(alpha1));
+    if alpha0 > alpha1 {
+        array::from_fn(|i| match i {
+            0 => a,
+            1 => b,
+            _ => ((8 - i as i32) * a + (i as i32 - 1) * b) / 7,
+        })
+    } else {
+        array::from_fn(|i| match i {
+            0 => a,
+            1 => b,
+            6 => 0,
+            7 => 255,
+            _ => ((6 - i as i32) * a + (i as i32 - 1) * b) / 5,
+        })
+    }
+}
+
+/// Compresses the alphas of a block into the alpha half of a BC3 block.
+fn encode_bc3_alpha(block: &Block) -> [u8; 8] {
+    let alphas = block.map(|pixel| pixel[3]);
+    let alpha0 = *alphas.iter().max().unwrap();
+    let alpha1 = *alphas.iter().min().unwrap();
+
+    let palette = bc3_alpha_palette(alpha0, alpha1).map(|alpha| [alpha]);
+    let mut indices = 0u64;
+    for (i, alpha) in alphas.iter().enumerate() {
+        let index = nearest(&palette, [i32::from(*alpha)]).0;
+        indices |= (index as u64) << (3 * i);
+    }
+
+    let mut bytes = [0; 8];
+    bytes[0] = alpha0;
+    bytes[1] = alpha1;
+    bytes[2..8].copy_from_slice(&indices.to_le_bytes()[..6]);
+    bytes
+}
+
+fn decode_bc3_alpha(bytes: &[u8]) -> [u8; 16] {
+    let palette = bc3_alpha_palette(bytes[0], bytes[1]);
+    let mut index_bytes = [0; 8];
+    index_bytes[..6].copy_from_slice(&bytes[2..8]);
+    let indices = u64::from_le_bytes(index_bytes);
+
+    array::from_fn(|i| palette[((indices >> (3 * i)) & 7) as usize] as u8)
+}
+
+/// The interpolation weights, out of 64, of BC7 blocks with 4-bit indices.
+const BC7_WEIGHTS: [i32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+fn bc7_palette(endpoint0: [i32; 4], endpoint1: [i32; 4]) -> [[i32; 4]; 16] {
+    BC7_WEIGHTS.map(|weight| {
+        array::from_fn(|c| ((64 - weight) * endpoint0[c] + weight * endpoint1[c] + 32) >> 6)
+    })
+}
+
+/// A BC7 mode 6 endpoint: 7 bits per channel, and a p-bit shared by all channels that is
+/// appended to each of them.
+#[derive(Clone, Copy)]
+struct Bc7Endpoint {
+    channels: [u8; 4],
+    p_bit: u8,
+}
+
+impl Bc7Endpoint {
+    /// Returns the endpoint closest to an 8-bit color.
+    fn quantize(color: [f32; 4]) -> Bc7Endpoint {
+        (0..=1)
+            .map(|p_bit| {
+                let channels =
+                    color.map(|c| ((c - p_bit as f32) / 2.0).round().clamp(0.0, 127.0) as u8);
+                Bc7Endpoint { channels, p_bit }
+            })
+            .min_by_key(|endpoint| {
+                let expanded = endpoint.expand();
+                nearest(&[expanded], color.map(|c| c.round() as i32)).1
+            })
+            .unwrap()
+    }
+
+    fn expand(self) -> [i32; 4] {
+        self.channels
+            .map(|c| (i32::from(c) << 1) | i32::from(self.p_bit))
+    }
+}
+
+/// Returns the endpoints that reproduce `points` best with the BC7 weights of `indices`, or
+/// `None` if they can't be solved for, such as when all indices are the same.
+fn fit_bc7_endpoints(points: &[[f32; 4]], indices: &[usize; 16]) -> Option<([f32; 4], [f32; 4])> {
+    let (mut aa, mut ab, mut bb) = (0.0, 0.0, 0.0);
+    let (mut ax, mut bx) = ([0.0f32; 4], [0.0f32; 4]);
+    for (point, &index) in points.iter().zip(indices) {
+        let weight = BC7_WEIGHTS[index] as f32 / 64.0;
+        let inverse = 1.0 - weight;
+        aa += inverse * inverse;
+        ab += inverse * weight;
+        bb += weight * weight;
+        for c in 0..4 {
+            ax[c] += inverse * point[c];
+            bx[c] += weight * point[c];
+        }
+    }
+
+    let determinant = aa * bb - ab * ab;
+    if determinant.abs() < 1e-6 {
+        return None;
+    }
+    Some((
+        array::from_fn(|c| ((bb * ax[c] - ab * bx[c]) / determinant).clamp(0.0, 255.0)),
+        array::from_fn(|c| ((aa * bx[c] - ab * ax[c]) / determinant).clamp(0.0, 255.0)),
+    ))
+}
+
+/// Writes values into a 128-bit BC7 block, from the lowest bit up.
+struct BitWriter {
+    bits: u128,
+    position: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= u128::from(value) << self.position;
+        self.position += count;
+    }
+}
+
+/// Reads values from a 128-bit BC7 block, from the lowest bit up.
+struct BitReader {
+    bits: u128,
+}
+
+impl BitReader {
+    fn read(&mut self, count: u32) -> u32 {
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        value
+    }
+}
+
+/// Compresses a block into BC7 mode 6, which has one pair of RGBA endpoints and 4-bit indices.
+/// The endpoints start on the principal axis of the block's colors, and are refined by least
+/// squares.
+fn encode_bc7(block: &Block) -> [u8; 16] {
+    let points: Vec<[f32; 4]> = block.iter().map(|pixel| pixel.map(f32::from)).collect();
+    let (mean, axis) = principal_axis(&points);
+    let (mut low, mut high) = line_endpoints(&points, mean, axis);
+
+    let mut best: Option<(u32, [Bc7Endpoint; 2], [usize; 16])> = None;
+    for _ in 0..3 {
+        let endpoints = [Bc7Endpoint::quantize(low), Bc7Endpoint::quantize(high)];
+        let palette = bc7_palette(endpoints[0].expand(), endpoints[1].expand());
+
+        let mut error = 0;
+        let indices: [usize; 16] = array::from_fn(|i| {
+            let (index, distance) = nearest(&palette, block[i].map(i32::from));
+            error += distance;
+            index
+        });
+
+        if best
+            .as_ref()
+            .is_none_or(|(best_error, ..)| error < *best_error)
+        {
+            best = Some((error, endpoints, indices));
+        }
+        match fit_bc7_endpoints(&points, &indices) {
+            Some((fitted_low, fitted_high)) => (low, high) = (fitted_low, fitted_high),
+            None => break,
+        }
+    }
+    let (_, mut endpoints, mut indices) = best.unwrap();
+
+    // the top bit of the first index is implied to be 0
+    if indices[0] >= 8 {
+        endpoints.swap(0, 1);
+        indices = indices.map(|index| 15 - index);
+    }
+
+    let mut writer = BitWriter {
+        bits: 0,
+        position: 0,
+    };
+    writer.write(1 << 6, 7);
+    for c in 0..4 {
+        for endpoint in &endpoints {
+            writer.write(u32::from(endpoint.channels[c]), 7);
+        }
+    }
+    for endpoint in &endpoints {
+        writer.write(u32::from(endpoint.p_bit), 1);
+    }
+    for (i, &index) in indices.iter().enumerate() {
+        writer.write(index as u32, if i == 0 { 3 } else { 4 });
+    }
+    writer.bits.to_le_bytes()
+}
+
+/// Decodes a BC7 block, or returns `None` if it isn't in mode 6, the only mode tileproc writes.
+fn decode_bc7(bytes: &[u8]) -> Option<Block> {
+    let mut reader = BitReader {
+        bits: u128::from_le_bytes(bytes.try_into().unwrap()),
+    };
+    if reader.read(7) != 1 << 6 {
+        return None;
+    }
+
+    let mut endpoints = [Bc7Endpoint {
+        channels: [0; 4],
+        p_bit: 0,
+    }; 2];
+    for c in 0..4 {
+        for endpoint in &mut endpoints {
+            endpoint.channels[c] = reader.read(7) as u8;
+        }
+    }
+    for endpoint in &mut endpoints {
+        endpoint.p_bit = reader.read(1) as u8;
+    }
+
+    let palette = bc7_palette(endpoints[0].expand(), endpoints[1].expand());
+    Some(array::from_fn(|i| {
+        let index = reader.read(if i == 0 { 3 } else { 4 });
+        palette[index as usize].map(|c| c as u8)
+    }))
+}
+
+/// Compresses an image into blocks, row by row.
+fn compress(image: &RgbaImage, compression: BlockCompression) -> Vec<u8> {
+    let blocks_x = image.width().div_ceil(4);
+    let blocks_y = image.height().div_ceil(4);
+
+    let mut data = Vec::with_capacity((blocks_x * blocks_y) as usize * compression.block_bytes());
+    for block_y in 0..blocks_y {
+        for block_x in 0..blocks_x {
+            let block = read_block(image, block_x, block_y);
+            match compression {
+                BlockCompression::Bc1 => data.extend(encode_bc1(&block, true)),
+                BlockCompression::Bc3 => {
+                    data.extend(encode_bc3_alpha(&block));
+                    data.extend(encode_bc1(&block, false));
+                }
+                BlockCompression::Bc7 => data.extend(encode_bc7(&block)),
+            }
+        }
+    }
+    data
+}
+
+/// Decompresses the blocks of an image, or returns `None` if there are too few of them or they
+/// can't be decoded.
+fn decompress(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    compression: BlockCompression,
+) -> Option<RgbaImage> {
+    let blocks_x = width.div_ceil(4);
+    let blocks_y = height.div_ceil(4);
+    let block_bytes = compression.block_bytes();
+    if data.len() < (blocks_x * blocks_y) as usize * block_bytes {
+        return None;
+    }
+
+    let mut image = RgbaImage::new(width, height);
+    for (index, bytes) in data
+        .chunks_exact(block_bytes)
+        .take((blocks_x * blocks_y) as usize)
+        .enumerate()
+    {
+        let block = match compression {
+            BlockCompression::Bc1 => decode_bc1(bytes, true),
+            BlockCompression::Bc3 => {
+                let alphas = decode_bc3_alpha(&bytes[..8]);
+                let mut block = decode_bc1(&bytes[8..], false);
+                for (pixel, alpha) in block.iter_mut().zip(alphas) {
+                    pixel[3] = alpha;
+                }
+                block
+            }
+            BlockCompression::Bc7 => decode_bc7(bytes)?,
+        };
+
+        let block_x = index as u32 % blocks_x;
+        let block_y = index as u32 / blocks_x;
+        for (i, pixel) in block.iter().enumerate() {
+            let x = block_x * 4 + i as u32 % 4;
+            let y = block_y * 4 + i as u32 / 4;
+            if x < width && y < height {
+                image.put_pixel(x, y, image::Rgba(*pixel));
+            }
+        }
+    }
+    Some(image)
+}
+
+/// One compressed mip level of a texture.
+struct MipLevel {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+    0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n',
+];
+
+/// Returns the data format descriptor of a KTX2 texture: a basic descriptor block of sRGB
+/// colors with straight alpha in 4x4 blocks.
+fn data_format_descriptor(compression: BlockCompression) -> Vec<u8> {
+    // the color model, and the bit offset, bit length - 1 and channel of every sample. The
+    // channel ids depend on the color model: BC1 with punch-through alpha is its channel 1
+    const COLOR: u8 = 0;
+    const BC1_ALPHA: u8 = 1;
+    const BC3_ALPHA: u8 = 15;
+    let (color_model, samples): (u8, &[(u16, u8, u8)]) = match compression {
+        BlockCompression::Bc1 => (128, &[(0, 63, BC1_ALPHA)]),
+        BlockCompression::Bc3 => (130, &[(0, 63, BC3_ALPHA), (64, 63, COLOR)]),
+        BlockCompression::Bc7 => (134, &[(0, 127, COLOR)]),
+    };
+    let block_size = 24 + 16 * samples.len();
+
+    let mut descriptor = Vec::with_capacity(4 + block_size);
+    descriptor.extend((4 + block_size as u32).to_le_bytes());
+    // Khronos' basic descriptor block, version 2
+    descriptor.extend(0u32.to_le_bytes());
+    descriptor.extend(2u16.to_le_bytes());
+    descriptor.extend((block_size as u16).to_le_bytes());
+    // BT.709 primaries, sRGB transfer function, straight alpha
+    descriptor.extend([color_model, 1, 2, 0]);
+    descriptor.extend([3, 3, 0, 0]);
+    descriptor.extend([compression.block_bytes() as u8, 0, 0, 0, 0, 0, 0, 0]);
+    for &(bit_offset, bit_length, channel) in samples {
+        descriptor.extend(bit_offset.to_le_bytes());
+        descriptor.extend([bit_length, channel]);
+        descriptor.extend([0; 4]);
+        descriptor.extend(0u32.to_le_bytes());
+        descriptor.extend(u32::MAX.to_le_bytes());
+    }
+    descriptor
+}
+
+fn write_ktx2(
+    levels: &[MipLevel],
+    compression: BlockCompression,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let descriptor = data_format_descriptor(compression);
+    let descriptor_offset = 80 + 24 * levels.len();
+
+    // mip levels are stored from the smallest to the largest, each aligned to a block
+    let mut offsets = vec![0; levels.len()];
+    let mut end = descriptor_offset + descriptor.len();
+    for (offset, level) in offsets.iter_mut().zip(levels).rev() {
+        end = end.next_multiple_of(compression.block_bytes());
+        *offset = end;
+        end += level.data.len();
+    }
+
+    let mut header = Vec::with_capacity(descriptor_offset + descriptor.len());
+    header.extend(KTX2_IDENTIFIER);
+    for value in [
+        compression.vk_format(),
+        1,
+        levels[0].width,
+        levels[0].height,
+        0,
+        0,
+        1,
+        levels.len() as u32,
+        0,
+        descriptor_offset as u32,
+        descriptor.len() as u32,
+        0,
+        0,
+    ] {
+        header.extend(value.to_le_bytes());
+    }
+    // no supercompression global data
+    header.extend([0; 16]);
+    for (offset, level) in offsets.iter().zip(levels) {
+        header.extend((*offset as u64).to_le_bytes());
+        header.extend((level.data.len() as u64).to_le_bytes());
+        header.extend((level.data.len() as u64).to_le_bytes());
+    }
+    header.extend(descriptor);
+    writer.write_all(&header)?;
+
+    let mut written = header.len();
+    for (offset, level) in offsets.iter().zip(levels).rev() {
+        writer.write_all(&vec![0; offset - written])?;
+        writer.write_all(&level.data)?;
+        written = offset + level.data.len();
+    }
+    Ok(())
+}
+
+fn write_dds(
+    levels: &[MipLevel],
+    compression: BlockCompression,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    // caps, height, width, pixel format and linear size
+    let mut flags = 0x1 | 0x2 | 0x4 | 0x1000 | 0x80000;
+    let mut caps = 0x1000;
+    if levels.len() > 1 {
+        flags |= 0x20000;
+        caps |= 0x8 | 0x400000;
+    }
+
+    let mut header = Vec::with_capacity(148);
+    header.extend(b"DDS ");
+    for value in [
+        124,
+        flags,
+        levels[0].height,
+        levels[0].width,
+        levels[0].data.len() as u32,
+        0,
+        levels.len() as u32,
+    ] {
+        header.extend(value.to_le_bytes());
+    }
+    header.extend([0; 44]);
+    // a pixel format that defers to the DX10 header
+    for value in [32, 0x4, u32::from_le_bytes(*b"DX10"), 0, 0, 0, 0, 0] {
+        header.extend(value.to_le_bytes());
+    }
+    for value in [caps, 0u32, 0, 0, 0] {
+        header.extend(value.to_le_bytes());
+    }
+    // a 2D texture with straight alpha
+    for value in [compression.dxgi_format(), 3, 0, 1, 1] {
+        header.extend(value.to_le_bytes());
+    }
+    writer.write_all(&header)?;
+
+    for level in levels {
+        writer.write_all(&level.data)?;
+    }
+    Ok(())
+}
+
+/// Block compresses an image, and writes it into a texture container. With `mipmaps`, the
+/// texture holds every mip level of the image down to 1x1 pixels.
+pub fn write_texture(
+    image: &DynamicImage,
+    container: TextureContainer,
+    compression: BlockCompression,
+    mipmaps: bool,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    let mut levels = Vec::new();
+    let mut level = image.to_rgba8();
+    loop {
+        let (width, height) = level.dimensions();
+        levels.push(MipLevel {
+            width,
+            height,
+            data: compress(&level, compression),
+        });
+        if !mipmaps || (width == 1 && height == 1) {
+            break;
+        }
+        level = imageops::resize(
+            &level,
+            (width / 2).max(1),
+            (height / 2).max(1),
+            FilterType::Triangle,
+        );
+    }
+
+    match container {
+        TextureContainer::Ktx2 => write_ktx2(&levels, compression, writer),
+        TextureContainer::Dds => write_dds(&levels, compression, writer),
+    }
+}
+
+/// Decodes the largest mip level of a texture, or returns `None` if it isn't a texture
+/// tileproc can read.
+fn read_texture(bytes: &[u8], container: TextureContainer) -> Option<RgbaImage> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            bytes.get(offset..offset + 4)?.try_into().unwrap(),
+        ))
+    };
+    let u64_at = |offset: usize| -> Option<usize> {
+        Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().unwrap()) as usize)
+    };
+
+    let (compression, width, height, data) = match container {
+        TextureContainer::Ktx2 => {
+            // supercompressed textures are not supported
+            if !bytes.starts_with(&KTX2_IDENTIFIER) || u32_at(44)? != 0 {
+                return None;
+            }
+            let (offset, length) = (u64_at(80)?, u64_at(88)?);
+            (
+                BlockCompression::from_vk_format(u32_at(12)?)?,
+                u32_at(20)?,
+                u32_at(24)?,
+                bytes.get(offset..offset.checked_add(length)?)?,
+            )
+        }
+        TextureContainer::Dds => {
+            if !bytes.starts_with(b"DDS ") {
+                return None;
+            }
+            let (compression, data_offset) = match bytes.get(84..88)? {
+                b"DX10" => (BlockCompression::from_dxgi_format(u32_at(128)?)?, 148),
+                b"DXT1" => (BlockCompression::Bc1, 128),
+                b"DXT5" => (BlockCompression::Bc3, 128),
+                _ => return None,
+            };
+            (
+                compression,
+                u32_at(16)?,
+                u32_at(12)?,
+                bytes.get(data_offset..)?,
+            )
+        }
+    };
+    decompress(data, width, height, compression)
+}
+
+/// Decodes a tile, like `image::open`, or a KTX2 or DDS texture tile written by tileproc into
+/// RGBA8.
+pub fn open_tile(path: &Path) -> ImageResult<DynamicImage> {
+    let Some(container) = TextureContainer::from_path(path) else {
+        return image::open(path);
+    };
+
+    let bytes = fs::read(path)?;
+    read_texture(&bytes, container)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| {
+            ImageError::Decoding(DecodingError::new(
+                ImageFormatHint::Name(container.name().to_string()),
+                "unsupported or damaged texture",
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The largest difference between the channels of two blocks.
+    fn block_error(a: &Block, b: &Block) -> u8 {
+        a.iter()
+            .flatten()
+            .zip(b.iter().flatten())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap()
+    }
+
+    /// A block whose pixels are evenly spread along a line from one color to another, which
+    /// single subset blocks can fit.
+    fn gradient_block() -> Block {
+        array::from_fn(|i| {
+            let i = i as u8;
+            [10 + 14 * i, 200 - 10 * i, 50 + 3 * i, 255 - 12 * i]
+        })
+    }
+
+    /// An image of gradients with a slope small enough for every block to fit them.
+    fn gradient_image(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([
+                (20 + 6 * x) as u8,
+                (30 + 5 * y) as u8,
+                (100 + 2 * x + 2 * y) as u8,
+                (250 - 8 * x) as u8,
+            ])
+        })
+    }
+
+    #[test]
+    fn bc1_blocks_roundtrip() {
+        // 5:6:5 colors are at most half a step of 5 bits away
+        let solid = [[200, 100, 50, 255]; 16];
+        assert!(block_error(&solid, &decode_bc1(&encode_bc1(&solid, true), true)) <= 4);
+
+        let mut gradient = gradient_block();
+        for pixel in &mut gradient {
+            pixel[3] = 255;
+        }
+        let decoded = decode_bc1(&encode_bc1(&gradient, true), true);
+        // red spans 210 over four colors, so pixels are at most 35 from one, plus the rounding
+        assert!(block_error(&gradient, &decoded) <= 35 + 4);
+
+        // pixels with an alpha below 128 become transparent black, the others opaque
+        let mut punch_through = solid;
+        punch_through[5][3] = 127;
+        punch_through[6][3] = 128;
+        let decoded = decode_bc1(&encode_bc1(&punch_through, true), true);
+        assert_eq!(decoded[5], [0; 4]);
+        assert_eq!(decoded[6][3], 255);
+        assert!(block_error(&[decoded[0]; 16], &solid) <= 4);
+
+        // without alpha, as in BC3 blocks, alpha is ignored
+        let decoded = decode_bc1(&encode_bc1(&punch_through, false), false);
+        assert!(decoded.iter().all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn bc3_alphas_roundtrip() {
+        // the alphas span 180, and are at most half of a seventh of it from one of eight alphas
+        let block = gradient_block();
+        let decoded = decode_bc3_alpha(&encode_bc3_alpha(&block));
+        for (pixel, alpha) in block.iter().zip(decoded) {
+            assert!(pixel[3].abs_diff(alpha) <= 13);
+        }
+
+        let solid = [[0, 0, 0, 77]; 16];
+        assert_eq!(decode_bc3_alpha(&encode_bc3_alpha(&solid)), [77; 16]);
+    }
+
+    #[test]
+    fn bc7_blocks_roundtrip() {
+        let solid = [[200, 100, 50, 180]; 16];
+        let decoded = decode_bc7(&encode_bc7(&solid)).unwrap();
+        assert!(block_error(&solid, &decoded) <= 2);
+
+        let gradient = gradient_block();
+        let decoded = decode_bc7(&encode_bc7(&gradient)).unwrap();
+        // the largest gap between the 16 weights is 5 / 64 of a span of 210
+        assert!(block_error(&gradient, &decoded) <= 9);
+    }
+
+    #[test]
+    fn bc7_blocks_of_other_modes_are_not_decoded() {
+        assert_eq!(decode_bc7(&[0; 16]), None);
+        // mode 5
+        let mut bytes = [0; 16];
+        bytes[0] = 1 << 5;
+        assert_eq!(decode_bc7(&bytes), None);
+    }
+
+    #[test]
+    fn images_roundtrip_through_every_compression() {
+        // 6x5 pixels, so the last blocks are partly outside of the image
+        let image = gradient_image(6, 5);
+        // BC1 makes the pixels opaque, while their alphas go down to 210
+        for (compression, color_error, alpha_error) in [
+            (BlockCompression::Bc1, 8, 45),
+            (BlockCompression::Bc3, 8, 2),
+            (BlockCompression::Bc7, 8, 8),
+        ] {
+            let data = compress(&image, compression);
+            assert_eq!(data.len(), 2 * 2 * compression.block_bytes());
+
+            let decoded = decompress(&data, 6, 5, compression).unwrap();
+            assert_eq!(decoded.dimensions(), (6, 5));
+            for (pixel, decoded) in image.pixels().zip(decoded.pixels()) {
+                for c in 0..3 {
+                    assert!(
+                        pixel[c].abs_diff(decoded[c]) <= color_error,
+                        "{compression:?}"
+                    );
+                }
+                assert!(
+                    pixel[3].abs_diff(decoded[3]) <= alpha_error,
+                    "{compression:?}"
+                );
+            }
+
+            assert_eq!(decompress(&data[..data.len() - 1], 6, 5, compression), None);
+        }
+    }
+
+    #[test]
+    fn ktx2_headers_match_the_specification() {
+        let image = DynamicImage::ImageRgba8(gradient_image(8, 4));
+        let mut bytes = Vec::new();
+        write_texture(
+            &image,
+            TextureContainer::Ktx2,
+            BlockCompression::Bc1,
+            false,
+            &mut bytes,
+        )
+        .unwrap();
+
+        #[rustfmt::skip]
+        let header: [u8; 148] = [
+            // identifier
+            0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n',
+            // VK_FORMAT_BC1_RGBA_SRGB_BLOCK, type size 1, 8x4 pixels, no depth, layers or faces
+            134, 0, 0, 0, 1, 0, 0, 0, 8, 0, 0, 0, 4, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0,
+            // one level, no supercompression
+            1, 0, 0, 0, 0, 0, 0, 0,
+            // a 44 byte descriptor at 104, no key/value data
+            104, 0, 0, 0, 44, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            // no supercompression global data
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            // the level: 16 bytes at 152
+            152, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0,
+            16, 0, 0, 0, 0, 0, 0, 0,
+            // the descriptor's size, and a basic descriptor block of 40 bytes, version 2
+            44, 0, 0, 0, 0, 0, 0, 0, 2, 0, 40, 0,
+            // KHR_DF_MODEL_BC1A, BT.709 primaries, sRGB transfer, straight alpha
+            128, 1, 2, 0,
+            // 4x4 blocks of 8 bytes
+            3, 3, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0,
+            // one 64 bit sample of KHR_DF_CHANNEL_BC1A_ALPHA, from 0 to u32::MAX
+            0, 0, 63, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        assert_eq!(bytes[..148], header);
+        // the level is aligned to its blocks
+        assert_eq!(bytes[148..152], [0; 4]);
+        assert_eq!(bytes.len(), 152 + 16);
+    }
+
+    #[test]
+    fn ktx2_descriptors_of_bc3_and_bc7() {
+        let descriptor = data_format_descriptor(BlockCompression::Bc3);
+        assert_eq!(descriptor.len(), 4 + 24 + 2 * 16);
+        // KHR_DF_MODEL_BC3, blocks of 16 bytes
+        assert_eq!(descriptor[12], 130);
+        assert_eq!(descriptor[20], 16);
+        // the alpha half, KHR_DF_CHANNEL_BC3_ALPHA, then the color half
+        assert_eq!(descriptor[28..32], [0, 0, 63, 15]);
+        assert_eq!(descriptor[44..48], [64, 0, 63, 0]);
+
+        let descriptor = data_format_descriptor(BlockCompression::Bc7);
+        assert_eq!(descriptor.len(), 4 + 24 + 16);
+        assert_eq!(descriptor[12], 134);
+        assert_eq!(descriptor[28..32], [0, 0, 127, 0]);
+    }
+
+    #[test]
+    fn dds_headers_match_the_specification() {
+        let image = DynamicImage::ImageRgba8(gradient_image(8, 4));
+        let mut bytes = Vec::new();
+        write_texture(
+            &image,
+            TextureContainer::Dds,
+            BlockCompression::Bc7,
+            true,
+            &mut bytes,
+        )
+        .unwrap();
+
+        let mut header = Vec::new();
+        header.extend(b"DDS ");
+        // caps, height, width, pixel format, linear size and mipmap count, 4x8 pixels, 32 bytes
+        // in the first level, 4 levels down to 1x1
+        for value in [124u32, 0xA1007, 4, 8, 32, 0, 4] {
+            header.extend(value.to_le_bytes());
+        }
+        header.extend([0; 44]);
+        // a pixel format of 32 bytes with a FourCC of DX10
+        for value in [32, 0x4, u32::from_le_bytes(*b"DX10"), 0, 0, 0, 0, 0] {
+            header.extend(value.to_le_bytes());
+        }
+        // a complex texture with mipmaps
+        for value in [0x401008u32, 0, 0, 0, 0] {
+            header.extend(value.to_le_bytes());
+        }
+        // DXGI_FORMAT_BC7_UNORM_SRGB, a 2D texture, one array element, straight alpha
+        for value in [99u32, 3, 0, 1, 1] {
+            header.extend(value.to_le_bytes());
+        }
+        assert_eq!(header.len(), 148);
+        assert_eq!(bytes[..148], header);
+
+        // levels of 8x4, 4x2, 2x1 and 1x1 pixels, one block per level but the first
+        assert_eq!(bytes.len(), 148 + 16 * (2 + 1 + 1 + 1));
+    }
+
+    #[test]
+    fn textures_roundtrip() {
+        let image = gradient_image(12, 8);
+        // the largest mip level, compressed like the textures
+        let expected = decompress(
+            &compress(&image, BlockCompression::Bc7),
+            12,
+            8,
+            BlockCompression::Bc7,
+        )
+        .unwrap();
+        for container in [TextureContainer::Ktx2, TextureContainer::Dds] {
+            for mipmaps in [false, true] {
+                let mut bytes = Vec::new();
+                write_texture(
+                    &DynamicImage::ImageRgba8(image.clone()),
+                    container,
+                    BlockCompression::Bc7,
+                    mipmaps,
+                    &mut bytes,
+                )
+                .unwrap();
+
+                assert_eq!(read_texture(&bytes, container).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_textures_are_not_read() {
+        for container in [TextureContainer::Ktx2, TextureContainer::Dds] {
+            let mut bytes = Vec::new();
+            write_texture(
+                &DynamicImage::ImageRgba8(gradient_image(8, 8)),
+                container,
+                BlockCompression::Bc3,
+                false,
+                &mut bytes,
+            )
+            .unwrap();
+
+            assert!(read_texture(&bytes, container).is_some());
+            assert_eq!(read_texture(&bytes[..bytes.len() - 1], container), None);
+            assert_eq!(read_texture(&bytes[..100], container), None);
+            assert_eq!(read_texture(b"not a texture", container), None);
+        }
+
+        let mut bytes = Vec::new();
+        write_texture(
+            &DynamicImage::ImageRgba8(gradient_image(4, 4)),
+            TextureContainer::Ktx2,
+            BlockCompression::Bc1,
+            false,
+            &mut bytes,
+        )
+        .unwrap();
+        // an unknown vkFormat
+        bytes[12] = 37;
+        assert_eq!(read_texture(&bytes, TextureContainer::Ktx2), None);
+    }
+}
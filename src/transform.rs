@@ -0,0 +1,493 @@
+use std::{
+    fs::File,
+    io::{BufReader, Seek, SeekFrom},
+    path::Path,
+};
+
+use exif::{In, Reader, Tag};
+use image::Primitive;
+use num_traits::{NumCast, ToPrimitive};
+use tiff::decoder::Decoder;
+
+use crate::args::{Flip, GenTilesArgs};
+use crate::pixel::{TileBuffer, TilePixel};
+
+/// The most samples taken along each axis for one pixel of a scaled down image.
+const MAX_SAMPLES: u32 = 8;
+
+/// A 2D affine map, `x' = a * x + b * y + c` and `y' = d * x + e * y + f`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Affine([f64; 6]);
+
+impl Affine {
+    const IDENTITY: Affine = Affine([1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+
+    fn linear(a: f64, b: f64, d: f64, e: f64) -> Affine {
+        Affine([a, b, 0.0, d, e, 0.0])
+    }
+
+    fn translation(x: f64, y: f64) -> Affine {
+        Affine([1.0, 0.0, x, 0.0, 1.0, y])
+    }
+
+    fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let [a, b, c, d, e, f] = self.0;
+        (a * x + b * y + c, d * x + e * y + f)
+    }
+
+    /// Returns the map that applies this map, then `next`.
+    fn then(&self, next: Affine) -> Affine {
+        let [a, b, c, d, e, f] = self.0;
+        let [na, nb, nc, nd, ne, nf] = next.0;
+        Affine([
+            na * a + nb * d,
+            na * b + nb * e,
+            na * c + nb * f + nc,
+            nd * a + ne * d,
+            nd * b + ne * e,
+            nd * c + ne * f + nf,
+        ])
+    }
+
+    fn invert(&self) -> Affine {
+        let [a, b, c, d, e, f] = self.0;
+        let determinant = a * e - b * d;
+        let (ia, ib, id, ie) = (
+            e / determinant,
+            -b / determinant,
+            -d / determinant,
+            a / determinant,
+        );
+        Affine([ia, ib, -(ia * c + ib * f), id, ie, -(id * c + ie * f)])
+    }
+}
+
+/// Returns the map that rotates points clockwise by `degrees`, as seen with y going down. Multiples
+/// of 90 degrees are exact.
+fn rotation(degrees: f64) -> Affine {
+    let degrees = degrees.rem_euclid(360.0);
+    let (sin, cos) = match degrees {
+        0.0 => (0.0, 1.0),
+        90.0 => (1.0, 0.0),
+        180.0 => (0.0, -1.0),
+        270.0 => (-1.0, 0.0),
+        _ => degrees.to_radians().sin_cos(),
+    };
+    Affine::linear(cos, -sin, sin, cos)
+}
+
+fn flip(flip: Flip) -> Affine {
+    match flip {
+        Flip::Horizontal => Affine::linear(-1.0, 0.0, 0.0, 1.0),
+        Flip::Vertical => Affine::linear(1.0, 0.0, 0.0, -1.0),
+    }
+}
+
+/// Returns the map that turns stored pixels into displayed ones, for an EXIF orientation from 1
+/// to 8.
+fn orientation(orientation: u32) -> Affine {
+    match orientation {
+        2 => flip(Flip::Horizontal),
+        3 => rotation(180.0),
+        4 => flip(Flip::Vertical),
+        5 => flip(Flip::Horizontal).then(rotation(270.0)),
+        6 => rotation(90.0),
+        7 => flip(Flip::Horizontal).then(rotation(90.0)),
+        8 => rotation(270.0),
+        _ => Affine::IDENTITY,
+    }
+}
+
+/// Reads the EXIF orientation of an image, from 1 to 8, or 1 for images without one.
+///
+/// TIFF files are read with their own decoder, since the EXIF reader reads all of a TIFF file
+/// into memory.
+pub fn read_orientation(path: &Path) -> u32 {
+    let Ok(file) = File::open(path) else {
+        return 1;
+    };
+    let mut reader = BufReader::new(file);
+
+    if let Ok(mut decoder) = Decoder::new(&mut reader) {
+        return decoder
+            .find_tag_unsigned(tiff::tags::Tag::Orientation)
+            .ok()
+            .flatten()
+            .unwrap_or(1);
+    }
+
+    if reader.seek(SeekFrom::Start(0)).is_err() {
+        return 1;
+    }
+    Reader::new()
+        .read_from_container(&mut reader)
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)?
+                .value
+                .get_uint(0)
+        })
+        .unwrap_or(1)
+}
+
+/// Maps the pixels of a source image to the pixels of the image that is sliced into tiles, which
+/// is flipped, rotated and scaled from it.
+///
+/// Tiles are sliced from the transformed image without building it. Every tile pixel is sampled
+/// from the source image through the inverse map instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    /// Maps points of the source image to points of the transformed image.
+    forward: Affine,
+    inverse: Affine,
+    /// The dimensions of the source image.
+    source_dimensions: (u32, u32),
+}
+
+impl Transform {
+    /// The transform of an image that is sliced as it is.
+    pub fn identity(source_dimensions: (u32, u32)) -> Transform {
+        Transform::from_affine(Affine::IDENTITY, source_dimensions)
+    }
+
+    fn from_affine(forward: Affine, source_dimensions: (u32, u32)) -> Transform {
+        Transform {
+            forward,
+            inverse: forward.invert(),
+            source_dimensions,
+        }
+    }
+
+    /// Builds the transform that orients an image as its EXIF `orientation` says, then flips,
+    /// rotates it clockwise by `degrees` and scales it. The transformed image's bounding box
+    /// starts at 0,0.
+    pub fn new(
+        orientation: u32,
+        flip_axis: Option<Flip>,
+        degrees: f64,
+        scale: f64,
+        source_dimensions: (u32, u32),
+    ) -> Transform {
+        let mut forward = self::orientation(orientation);
+        if let Some(axis) = flip_axis {
+            forward = forward.then(flip(axis));
+        }
+        forward = forward
+            .then(rotation(degrees))
+            .then(Affine::linear(scale, 0.0, 0.0, scale));
+
+        let transform = Transform::from_affine(forward, source_dimensions);
+        let (min_x, min_y, _, _) = transform.bounds();
+        Transform::from_affine(
+            forward.then(Affine::translation(-min_x, -min_y)),
+            source_dimensions,
+        )
+    }
+
+    /// Builds the transform `gen_tiles_args` asks for, for its input image.
+    pub fn from_args(gen_tiles_args: &GenTilesArgs, source_dimensions: (u32, u32)) -> Transform {
+        let orientation = if gen_tiles_args.ignore_orientation {
+            1
+        } else {
+            read_orientation(&gen_tiles_args.input)
+        };
+        Transform::new(
+            orientation,
+            gen_tiles_args.flip,
+            gen_tiles_args.rotate,
+            gen_tiles_args.scale,
+            source_dimensions,
+        )
+    }
+
+    /// Returns the transform of the part of the source image at `x`, `y` with `dimensions`, which
+    /// puts its pixels where this transform puts them.
+    pub fn cropped(&self, x: u32, y: u32, dimensions: (u32, u32)) -> Transform {
+        Transform::from_affine(
+            Affine::translation(x as f64, y as f64).then(self.forward),
+            dimensions,
+        )
+    }
+
+    /// Returns the bounding box of the transformed image as its left, top, right and bottom
+    /// edges.
+    pub fn bounds(&self) -> (f64, f64, f64, f64) {
+        let (width, height) = self.source_dimensions;
+        let corners = [
+            (0.0, 0.0),
+            (width as f64, 0.0),
+            (0.0, height as f64),
+            (width as f64, height as f64),
+        ]
+        .map(|(x, y)| self.forward.apply(x, y));
+
+        corners.iter().fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(left, top, right, bottom), &(x, y)| {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            },
+        )
+    }
+
+    /// The width and height of the transformed image, rounded to whole pixels.
+    pub fn dimensions(&self) -> (u32, u32) {
+        let (left, top, right, bottom) = self.bounds();
+        ((right - left).round() as u32, (bottom - top).round() as u32)
+    }
+
+    /// Returns how far the transform moves pixels, if it only moves them by whole pixels, so that
+    /// they can be copied without being resampled.
+    pub fn whole_pixel_offset(&self) -> Option<(i32, i32)> {
+        let [a, b, c, d, e, f] = self.forward.0;
+        (a == 1.0 && b == 0.0 && d == 0.0 && e == 1.0 && c.fract() == 0.0 && f.fract() == 0.0)
+            .then_some((c as i32, f as i32))
+    }
+
+    /// Samples the source image at the center of a pixel of the transformed image.
+    ///
+    /// Returns `None` outside of the source image. Pixels are interpolated bilinearly, and
+    /// averaged over several samples where the image is scaled down. Images with an alpha channel
+    /// fade to transparent black at their edges, so that a cropped image samples like the whole
+    /// one around its transparent margins.
+    pub fn sample<P: TilePixel>(&self, image: &TileBuffer<P>, x: i64, y: i64) -> Option<P> {
+        if !P::HAS_ALPHA {
+            let (center_x, center_y) = self.inverse.apply(x as f64 + 0.5, y as f64 + 0.5);
+            let (width, height) = (image.width() as f64, image.height() as f64);
+            if center_x < 0.0 || center_y < 0.0 || center_x >= width || center_y >= height {
+                return None;
+            }
+        }
+
+        // one sample per source pixel the transformed pixel covers, along each axis
+        let [a, b, _, d, e, _] = self.inverse.0;
+        let footprint = (a * e - b * d).abs().sqrt();
+        let samples = (footprint.ceil() as u32).clamp(1, MAX_SAMPLES);
+
+        let mut sum = [0.0; 4];
+        let mut covered = false;
+        for sample_y in 0..samples {
+            for sample_x in 0..samples {
+                let (source_x, source_y) = self.inverse.apply(
+                    x as f64 + (sample_x as f64 + 0.5) / samples as f64,
+                    y as f64 + (sample_y as f64 + 0.5) / samples as f64,
+                );
+                let Some(sampled) = bilinear(image, source_x, source_y) else {
+                    continue;
+                };
+                covered = true;
+                for (sum, channel) in sum.iter_mut().zip(sampled) {
+                    *sum += channel;
+                }
+            }
+        }
+        if !covered {
+            return None;
+        }
+
+        let count = (samples * samples) as f64;
+        let max = P::Subpixel::DEFAULT_MAX_VALUE.to_f64().unwrap();
+        let mut pixel = *image.get_pixel(0, 0);
+        for (channel, sum) in pixel.channels_mut().iter_mut().zip(sum) {
+            let value = sum / count;
+            // integer channels are rounded, floating point ones have a max of 1
+            let value = if max > 1.0 {
+                value.round().clamp(0.0, max)
+            } else {
+                value
+            };
+            *channel = NumCast::from(value).unwrap();
+        }
+        Some(pixel)
+    }
+}
+
+/// Interpolates the channels of the 4 pixels around a point of an image.
+///
+/// Pixels outside of images with an alpha channel are transparent black, and `None` is returned
+/// if all of them are. Images without one are clamped to their edges instead.
+fn bilinear<P: TilePixel>(image: &TileBuffer<P>, x: f64, y: f64) -> Option<[f64; 4]> {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+
+    // distances from the center of the pixel up and to the left of the point
+    let x = x - 0.5;
+    let y = y - 0.5;
+    let (left, top) = (x.floor(), y.floor());
+    let (weight_x, weight_y) = (x - left, y - top);
+
+    let mut channels = [0.0; 4];
+    let mut covered = false;
+    for (offset_x, offset_y, weight) in [
+        (0, 0, (1.0 - weight_x) * (1.0 - weight_y)),
+        (1, 0, weight_x * (1.0 - weight_y)),
+        (0, 1, (1.0 - weight_x) * weight_y),
+        (1, 1, weight_x * weight_y),
+    ] {
+        if weight == 0.0 {
+            continue;
+        }
+        let (pixel_x, pixel_y) = (left as i64 + offset_x, top as i64 + offset_y);
+        let inside = (0..width).contains(&pixel_x) && (0..height).contains(&pixel_y);
+        if !inside && P::HAS_ALPHA {
+            continue;
+        }
+        covered = true;
+        let pixel = image.get_pixel(
+            pixel_x.clamp(0, width - 1) as u32,
+            pixel_y.clamp(0, height - 1) as u32,
+        );
+
+        for (channel, value) in channels.iter_mut().zip(pixel.channels()) {
+            *channel += value.to_f64().unwrap() * weight;
+        }
+    }
+    covered.then_some(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{imageops, DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    /// A 3x2 image with a different color in every pixel.
+    fn source_image() -> RgbImage {
+        RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8 * 100, y as u8 * 100, 50]))
+    }
+
+    /// Builds the transformed image by sampling every pixel.
+    fn transformed(transform: &Transform, image: &RgbImage) -> RgbImage {
+        let (width, height) = transform.dimensions();
+        RgbImage::from_fn(width, height, |x, y| {
+            transform.sample(image, x as i64, y as i64).unwrap()
+        })
+    }
+
+    #[test]
+    fn orients_images_like_exif_viewers() {
+        let image = source_image();
+        let expected = [
+            image.clone(),
+            imageops::flip_horizontal(&image),
+            imageops::rotate180(&image),
+            imageops::flip_vertical(&image),
+            imageops::rotate270(&imageops::flip_horizontal(&image)),
+            imageops::rotate90(&image),
+            imageops::rotate90(&imageops::flip_horizontal(&image)),
+            imageops::rotate270(&image),
+        ];
+        for (orientation, expected) in (1..=8).zip(expected) {
+            let transform = Transform::new(orientation, None, 0.0, 1.0, (3, 2));
+            assert_eq!(transformed(&transform, &image), expected, "{orientation}");
+        }
+    }
+
+    #[test]
+    fn flips_then_rotates_clockwise() {
+        let image = source_image();
+        let transform = Transform::new(1, Some(Flip::Vertical), 90.0, 1.0, (3, 2));
+        assert_eq!(transform.dimensions(), (2, 3));
+        assert_eq!(
+            transformed(&transform, &image),
+            imageops::rotate90(&imageops::flip_vertical(&image))
+        );
+
+        let transform = Transform::new(1, Some(Flip::Horizontal), -90.0, 1.0, (3, 2));
+        assert_eq!(
+            transformed(&transform, &image),
+            imageops::rotate270(&imageops::flip_horizontal(&image))
+        );
+    }
+
+    #[test]
+    fn only_whole_pixel_moves_are_copied() {
+        assert_eq!(
+            Transform::identity((3, 2)).whole_pixel_offset(),
+            Some((0, 0))
+        );
+        assert_eq!(
+            Transform::identity((3, 2))
+                .cropped(4, 5, (1, 1))
+                .whole_pixel_offset(),
+            Some((4, 5))
+        );
+        assert_eq!(
+            Transform::new(1, None, 90.0, 1.0, (3, 2)).whole_pixel_offset(),
+            None
+        );
+        assert_eq!(
+            Transform::new(1, None, 0.0, 2.0, (3, 2)).whole_pixel_offset(),
+            None
+        );
+    }
+
+    #[test]
+    fn scaling_down_averages_pixels() {
+        let checkerboard = RgbImage::from_fn(4, 4, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        let transform = Transform::new(1, None, 0.0, 0.5, (4, 4));
+        assert_eq!(transform.dimensions(), (2, 2));
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(transform.sample(&checkerboard, x, y), Some(Rgb([128; 3])));
+        }
+    }
+
+    #[test]
+    fn scaling_up_interpolates_pixels() {
+        let image = RgbImage::from_fn(2, 1, |x, _| Rgb([x as u8 * 200; 3]));
+        let transform = Transform::new(1, None, 0.0, 2.0, (2, 1));
+        assert_eq!(transform.dimensions(), (4, 2));
+        // pixels are clamped to the edges of images without alpha
+        let row: Vec<u8> = (0..4)
+            .map(|x| transform.sample(&image, x, 0).unwrap()[0])
+            .collect();
+        assert_eq!(row, [0, 50, 150, 200]);
+    }
+
+    #[test]
+    fn pixels_outside_of_the_image_are_not_sampled() {
+        let transform = Transform::new(1, None, 30.0, 1.0, (3, 2));
+        let (width, height) = transform.dimensions();
+        let image = source_image();
+        let rgba_image = DynamicImage::ImageRgb8(image.clone()).into_rgba8();
+        for (x, y) in [
+            (-1, 0),
+            (0, -1),
+            (width as i64 + 1, 0),
+            (0, height as i64 + 1),
+        ] {
+            assert_eq!(transform.sample(&image, x, y), None);
+            assert_eq!(transform.sample(&rgba_image, x, y), None);
+        }
+    }
+
+    #[test]
+    fn images_with_alpha_fade_out_at_their_edges() {
+        let image = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]));
+        // the pixel at the right edge is interpolated from a quarter of transparent black outside
+        // of the image
+        let transform = Transform::new(1, None, 0.0, 2.0, (2, 2));
+        let [_, _, _, alpha] = transform.sample(&image, 3, 1).unwrap().0;
+        assert_eq!(alpha, 191);
+    }
+
+    #[test]
+    fn cropped_images_sample_like_the_whole_image() {
+        let image = RgbImage::from_fn(16, 16, |x, y| Rgb([x as u8 * 16, y as u8 * 16, 0]));
+        let crop = imageops::crop_imm(&image, 4, 4, 8, 8).to_image();
+        let transform = Transform::new(1, None, 30.0, 0.8, (16, 16));
+        let cropped = transform.cropped(4, 4, (8, 8));
+
+        // the pixels around the middle of the crop
+        let (center_x, center_y) = transform.forward.apply(8.0, 8.0);
+        for y in center_y as i64 - 1..=center_y as i64 + 1 {
+            for x in center_x as i64 - 1..=center_x as i64 + 1 {
+                assert_eq!(transform.sample(&image, x, y), cropped.sample(&crop, x, y));
+            }
+        }
+    }
+}
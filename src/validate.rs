@@ -0,0 +1,383 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use image::GenericImageView;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::naming::NameTemplate;
+use crate::texture::open_tile;
+use crate::tiler::is_metadata_file;
+
+/// The kinds of problems `validate_pyramid` can find.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// A file or directory that is neither a tile nor a LOD level.
+    UnrecognizedFile,
+    /// A tile that could not be decoded, e.g. a truncated PNG.
+    DecodeFailed,
+    /// A tile whose dimensions differ from the first tile of the pyramid.
+    DimensionMismatch,
+    /// A tile whose parent tile is missing from the next LOD level.
+    MissingParent,
+    /// A tile in a LOD level whose children are all missing from the level below.
+    OrphanParent,
+    /// The top LOD level still has more than 4 tiles, so `generate_lods` did not finish.
+    MissingLevel,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    /// The LOD level the problem was found in. `None` for flat tile directories.
+    pub level: Option<u32>,
+    pub path: PathBuf,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    pub directory: PathBuf,
+    pub levels: u32,
+    pub tiles_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+struct Level {
+    level: Option<u32>,
+    dir: PathBuf,
+    tiles: BTreeMap<(i32, i32), PathBuf>,
+}
+
+/// Reads the tiles of one directory, reporting anything that is not a tile named by
+/// `name_template`.
+fn read_level(
+    dir: &Path,
+    level: Option<u32>,
+    name_template: &NameTemplate,
+    problems: &mut Vec<Problem>,
+) -> Level {
+    let mut tiles = BTreeMap::new();
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        // the journal and manifest live next to the tiles of flat tile directories, and the output
+        // marker is also in the "0" directory `tiles-to-layers` moves tiles into
+        if is_metadata_file(&path) {
+            continue;
+        }
+
+        match name_template.parse_path(&path) {
+            Some(coords) if path.is_file() => {
+                tiles.insert(coords, path);
+            }
+            _ => problems.push(Problem {
+                kind: ProblemKind::UnrecognizedFile,
+                level,
+                message: "not a tile".to_string(),
+                path,
+            }),
+        }
+    }
+
+    Level {
+        level,
+        dir: dir.to_path_buf(),
+        tiles,
+    }
+}
+
+/// Reads every LOD level of a directory generated by `gen-tile-layers` or `tiles-to-layers`.
+///
+/// A directory without a "0" sub directory is treated as a single flat level of tiles.
+fn read_levels(
+    dir: &Path,
+    name_template: &NameTemplate,
+    problems: &mut Vec<Problem>,
+) -> Vec<Level> {
+    if !dir.join("0").is_dir() {
+        return vec![read_level(dir, None, name_template, problems)];
+    }
+
+    let mut levels = Vec::new();
+    while dir.join(levels.len().to_string()).is_dir() {
+        let level = levels.len() as u32;
+        levels.push(read_level(
+            &dir.join(level.to_string()),
+            Some(level),
+            name_template,
+            problems,
+        ));
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        let is_level = path.is_dir()
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<usize>().ok())
+                .is_some_and(|level| level < levels.len());
+
+        if !is_level && !is_metadata_file(&path) {
+            problems.push(Problem {
+                kind: ProblemKind::UnrecognizedFile,
+                level: None,
+                message: "not a LOD level".to_string(),
+                path,
+            });
+        }
+    }
+
+    levels
+}
+
+/// Checks a tile directory or tile pyramid for corruption.
+///
+/// Every tile must decode and have the same dimensions as the first tile. In a pyramid, every
+/// tile must have a parent in the level above it, and every tile above level 0 must have at
+/// least one child, following the floor-div-2 rule used by `shrink_tiles`. Tiles are recognized by
+/// `name_template`.
+pub fn validate_pyramid(dir: &Path, name_template: &NameTemplate) -> ValidationReport {
+    let mut problems = Vec::new();
+    let levels = read_levels(dir, name_template, &mut problems);
+
+    // decode every tile
+    let decoded: Vec<_> = levels
+        .iter()
+        .flat_map(|level| level.tiles.values().map(move |path| (level.level, path)))
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(level, path)| (level, path, open_tile(path).map(|img| img.dimensions())))
+        .collect();
+
+    let tiles_checked = decoded.len();
+
+    let mut tile_dimensions = None;
+    for (level, path, result) in decoded {
+        match result {
+            Ok(dimensions) => {
+                let expected = *tile_dimensions.get_or_insert(dimensions);
+                if dimensions != expected {
+                    problems.push(Problem {
+                        kind: ProblemKind::DimensionMismatch,
+                        level,
+                        path: path.clone(),
+                        message: format!(
+                            "tile is {}x{}, expected {}x{}",
+                            dimensions.0, dimensions.1, expected.0, expected.1
+                        ),
+                    });
+                }
+            }
+            Err(err) => problems.push(Problem {
+                kind: ProblemKind::DecodeFailed,
+                level,
+                path: path.clone(),
+                message: err.to_string(),
+            }),
+        }
+    }
+
+    // check parent and child relationships between neighboring levels
+    for pair in levels.windows(2) {
+        let (children, parents) = (&pair[0], &pair[1]);
+
+        let mut expected_parents = HashSet::new();
+        for (&(x, y), path) in &children.tiles {
+            let parent = (x.div_euclid(2), y.div_euclid(2));
+            expected_parents.insert(parent);
+
+            if !parents.tiles.contains_key(&parent) {
+                problems.push(Problem {
+                    kind: ProblemKind::MissingParent,
+                    level: children.level,
+                    path: path.clone(),
+                    message: format!(
+                        "parent tile {},{} is missing from {}",
+                        parent.0,
+                        parent.1,
+                        parents.dir.display()
+                    ),
+                });
+            }
+        }
+
+        for (coords, path) in &parents.tiles {
+            if !expected_parents.contains(coords) {
+                problems.push(Problem {
+                    kind: ProblemKind::OrphanParent,
+                    level: parents.level,
+                    path: path.clone(),
+                    message: format!("no child tiles exist in {}", children.dir.display()),
+                });
+            }
+        }
+    }
+
+    if let Some(top) = levels.last() {
+        if top.level.is_some() && top.tiles.len() > 4 {
+            problems.push(Problem {
+                kind: ProblemKind::MissingLevel,
+                level: top.level,
+                path: top.dir.clone(),
+                message: format!(
+                    "top level has {} tiles, expected 4 or less",
+                    top.tiles.len()
+                ),
+            });
+        }
+    }
+
+    ValidationReport {
+        directory: dir.to_path_buf(),
+        levels: levels.len() as u32,
+        tiles_checked,
+        problems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::naming::DEFAULT_NAME_TEMPLATE;
+    use crate::tiler::OUTPUT_MARKER_FILE_NAME;
+
+    fn write_tile(dir: &Path, name: &str, size: u32) {
+        fs::create_dir_all(dir).unwrap();
+        RgbaImage::from_pixel(size, size, Rgba([10, 20, 30, 255]))
+            .save(dir.join(name))
+            .unwrap();
+    }
+
+    /// Writes a pyramid of 4x4 tiles, with 0,0, 1,0 and -1,-1 in level 0, and their parents in
+    /// level 1.
+    fn write_pyramid(dir: &Path) {
+        for name in ["0,0.png", "1,0.png", "-1,-1.png"] {
+            write_tile(&dir.join("0"), name, 4);
+        }
+        for name in ["0,0.png", "-1,-1.png"] {
+            write_tile(&dir.join("1"), name, 4);
+        }
+    }
+
+    fn validate(dir: &Path) -> ValidationReport {
+        validate_pyramid(dir, &DEFAULT_NAME_TEMPLATE.parse().unwrap())
+    }
+
+    fn problem_kinds(report: &ValidationReport) -> Vec<(ProblemKind, Option<u32>)> {
+        report
+            .problems
+            .iter()
+            .map(|problem| (problem.kind, problem.level))
+            .collect()
+    }
+
+    #[test]
+    fn valid_pyramids_have_no_problems() {
+        let temp = tempfile::tempdir().unwrap();
+        write_pyramid(temp.path());
+        // tileproc's own files are not tiles
+        fs::write(temp.path().join(OUTPUT_MARKER_FILE_NAME), "").unwrap();
+
+        let report = validate(temp.path());
+        assert!(report.is_valid(), "{:?}", report.problems);
+        assert_eq!((report.levels, report.tiles_checked), (2, 5));
+    }
+
+    #[test]
+    fn flat_directories_are_one_level() {
+        let temp = tempfile::tempdir().unwrap();
+        for name in ["0,0.png", "5,0.png", "0,5.png", "5,5.png", "9,9.png"] {
+            write_tile(temp.path(), name, 4);
+        }
+
+        let report = validate(temp.path());
+        assert!(report.is_valid(), "{:?}", report.problems);
+        assert_eq!((report.levels, report.tiles_checked), (1, 5));
+    }
+
+    #[test]
+    fn finds_broken_tiles_and_foreign_files() {
+        let temp = tempfile::tempdir().unwrap();
+        write_pyramid(temp.path());
+        fs::write(temp.path().join("0").join("1,0.png"), b"not a png").unwrap();
+        write_tile(&temp.path().join("1"), "-1,-1.png", 8);
+        fs::write(temp.path().join("0").join("notes.txt"), "").unwrap();
+        fs::create_dir(temp.path().join("backup")).unwrap();
+
+        let report = validate(temp.path());
+        let mut kinds = problem_kinds(&report);
+        kinds.sort_by_key(|(kind, level)| (*kind as u8, *level));
+        assert_eq!(
+            kinds,
+            [
+                (ProblemKind::UnrecognizedFile, None),
+                (ProblemKind::UnrecognizedFile, Some(0)),
+                (ProblemKind::DecodeFailed, Some(0)),
+                (ProblemKind::DimensionMismatch, Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_tiles_missing_from_the_pyramid() {
+        let temp = tempfile::tempdir().unwrap();
+        write_pyramid(temp.path());
+        fs::remove_file(temp.path().join("1").join("-1,-1.png")).unwrap();
+        write_tile(&temp.path().join("1"), "3,3.png", 4);
+
+        let report = validate(temp.path());
+        assert_eq!(
+            problem_kinds(&report),
+            [
+                (ProblemKind::MissingParent, Some(0)),
+                (ProblemKind::OrphanParent, Some(1)),
+            ]
+        );
+        assert_eq!(
+            report.problems[0].path,
+            temp.path().join("0").join("-1,-1.png")
+        );
+        assert_eq!(
+            report.problems[1].path,
+            temp.path().join("1").join("3,3.png")
+        );
+    }
+
+    #[test]
+    fn finds_unfinished_pyramids() {
+        let temp = tempfile::tempdir().unwrap();
+        for name in ["0,0.png", "2,0.png", "0,2.png", "2,2.png", "4,4.png"] {
+            write_tile(&temp.path().join("0"), name, 4);
+        }
+
+        let report = validate(temp.path());
+        assert_eq!(
+            problem_kinds(&report),
+            [(ProblemKind::MissingLevel, Some(0))]
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageFormat, ImageResult};
+
+use crate::args::{BlockCompression, TileFormat};
+use crate::error::TileError;
+use crate::naming::NameTemplate;
+use crate::pixel::{convert_to, encodable_color_type};
+use crate::texture::{write_texture, TextureContainer};
+
+/// The extension added to the path of an image while it is being written.
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Writes tiles and stitched images so that they never appear half written.
+///
+/// Images are written to a temporary sibling file, which is renamed to the final path once it is
+/// complete. Readers of the directory only ever see complete images, and an interrupted write
+/// leaves a temporary file behind rather than a truncated tile.
+#[derive(Debug, Clone, Default)]
+pub struct TileWriter {
+    /// The format tiles are written in.
+    pub format: TileFormat,
+    /// Flush every image to disk before renaming it into place.
+    pub fsync: bool,
+    /// The block compression of KTX2 and DDS images.
+    pub block_compression: BlockCompression,
+    /// Store a mip chain in KTX2 and DDS images.
+    pub mipmaps: bool,
+    /// How tile files are named, and tile names are parsed.
+    pub name_template: NameTemplate,
+    /// The zoom level of the tiles, which is the number of digits of their quadkeys.
+    pub zoom: u32,
+}
+
+/// Returns the path an image is written to before being renamed to `path`.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(".");
+    file_name.push(TEMP_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+/// Returns true for the temporary file of a tile write, such as `"3,-2.png.tmp"`, for tiles
+/// named by `name_template`.
+pub fn is_temp_tile_path(path: &Path, name_template: &NameTemplate) -> bool {
+    path.extension() == Some(TEMP_EXTENSION.as_ref())
+        && path
+            .file_stem()
+            .is_some_and(|stem| name_template.parse_path(Path::new(stem)).is_some())
+}
+
+impl TileWriter {
+    pub fn new(format: TileFormat, fsync: bool) -> TileWriter {
+        TileWriter {
+            format,
+            fsync,
+            ..TileWriter::default()
+        }
+    }
+
+    /// Sets how KTX2 and DDS images are compressed.
+    pub fn with_texture(
+        mut self,
+        block_compression: BlockCompression,
+        mipmaps: bool,
+    ) -> TileWriter {
+        self.block_compression = block_compression;
+        self.mipmaps = mipmaps;
+        self
+    }
+
+    /// Sets how tile files are named.
+    pub fn with_name_template(mut self, name_template: NameTemplate) -> TileWriter {
+        self.name_template = name_template;
+        self
+    }
+
+    /// Sets the zoom level of the tiles.
+    pub fn with_zoom(mut self, zoom: u32) -> TileWriter {
+        self.zoom = zoom;
+        self
+    }
+
+    /// Returns a writer for the zoom level of the tiles at `paths`, read from their quadkeys, or a
+    /// copy of this writer if none of them has one.
+    pub fn at_zoom_of<P: AsRef<Path>>(&self, paths: impl IntoIterator<Item = P>) -> TileWriter {
+        let zoom = paths
+            .into_iter()
+            .find_map(|path| self.name_template.zoom_of_path(path.as_ref()));
+        self.clone().with_zoom(zoom.unwrap_or(self.zoom))
+    }
+
+    /// Returns a writer for the LOD layer above the tiles of this writer, whose quadkeys have one
+    /// digit less.
+    pub fn above(&self) -> TileWriter {
+        self.clone().with_zoom(self.zoom.saturating_sub(1))
+    }
+
+    /// Returns a writer whose zoom level is high enough for quadkeys to name the tiles from 0,0 to
+    /// `bottom_right`, for tiles that have no zoom level yet.
+    pub fn reaching(&self, bottom_right: (i32, i32)) -> TileWriter {
+        let bits = 32 - (bottom_right.0.max(0) | bottom_right.1.max(0)).leading_zeros();
+        self.clone().with_zoom(self.zoom.max(bits).max(1))
+    }
+
+    /// Returns the file name of the tile at the given tile coordinates, such as `"-3,12.png"`.
+    pub fn tile_file_name(&self, x: i32, y: i32) -> String {
+        self.name_template
+            .file_name(x, y, self.zoom, self.format.extension())
+    }
+
+    /// Returns an error if the name template can't name every tile from `top_left` to
+    /// `bottom_right`.
+    pub fn check_tile_names(
+        &self,
+        top_left: (i32, i32),
+        bottom_right: (i32, i32),
+    ) -> Result<(), TileError> {
+        for (x, y) in [top_left, bottom_right] {
+            if !self.name_template.can_name(x, y, self.zoom) {
+                return Err(TileError::UnnamedTile {
+                    x,
+                    y,
+                    zoom: self.zoom,
+                    name_template: self.name_template.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the tile coordinates out of a tile's file name, such as `"-3,12.png"`, in any tile
+    /// format.
+    ///
+    /// Returns `None` if the file name is not a tile name.
+    pub fn tile_coords(&self, path: &Path) -> Option<(i32, i32)> {
+        self.name_template.parse_path(path)
+    }
+
+    /// Writes an image to `path`, in the format of the path's extension. Images the format can't
+    /// store are converted to the closest color type it can, and KTX2 and DDS images are block
+    /// compressed.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write(&self, image: &DynamicImage, path: &Path) -> ImageResult<u64> {
+        let temp_path = temp_path(path);
+
+        let result = (|| {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            match TextureContainer::from_path(path) {
+                Some(container) => write_texture(
+                    image,
+                    container,
+                    self.block_compression,
+                    self.mipmaps,
+                    &mut writer,
+                )?,
+                None => {
+                    let format = ImageFormat::from_path(path)?;
+                    let color_type = encodable_color_type(image.color(), format);
+                    if color_type == image.color() {
+                        image.write_to(&mut writer, format)?;
+                    } else {
+                        convert_to(image, color_type).write_to(&mut writer, format)?;
+                    }
+                }
+            }
+            let file = writer.into_inner().map_err(|err| err.into_error())?;
+
+            Ok(self.move_into_place(file, &temp_path, path)?)
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// Copies an existing image file to `path` without decoding it.
+    ///
+    /// Returns the number of bytes written.
+    pub fn copy(&self, from: &Path, path: &Path) -> io::Result<u64> {
+        let temp_path = temp_path(path);
+
+        let result = (|| {
+            fs::copy(from, &temp_path)?;
+            self.move_into_place(File::open(&temp_path)?, &temp_path, path)
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    /// Renames a completely written temporary file to its final path.
+    fn move_into_place(&self, file: File, temp_path: &Path, path: &Path) -> io::Result<u64> {
+        if self.fsync {
+            file.sync_all()?;
+        }
+        let bytes_written = file.metadata()?.len();
+        fs::rename(temp_path, path)?;
+
+        if self.fsync {
+            if let Some(dir) = path.parent().filter(|dir| dir.is_dir()) {
+                File::open(dir)?.sync_all()?;
+            }
+        }
+        Ok(bytes_written)
+    }
+
+    /// Removes temporary tile files left in a directory by writes that were interrupted.
+    pub fn remove_temp_files(&self, dir: &Path) -> io::Result<()> {
+        if !dir.is_dir() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_temp_tile_path(&path, &self.name_template) && path.is_file() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the temporary file left by an interrupted write to `path`.
+    pub fn remove_temp_file(&self, path: &Path) -> io::Result<()> {
+        let temp_path = temp_path(path);
+        if temp_path.is_file() {
+            fs::remove_file(temp_path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 255])))
+    }
+
+    #[test]
+    fn writes_through_a_temporary_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("0,0.png");
+        // left by an interrupted write
+        fs::write(temp_path(&path), b"half a png").unwrap();
+
+        let writer = TileWriter::new(TileFormat::Png, true);
+        let bytes_written = writer.write(&image(), &path).unwrap();
+        assert_eq!(bytes_written, fs::metadata(&path).unwrap().len());
+        assert_eq!(image::open(&path).unwrap(), image());
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn failed_writes_leave_the_old_file() {
+        let temp = tempfile::tempdir().unwrap();
+        // the extension is no image format, so encoding fails after the temporary file is made
+        let path = temp.path().join("0,0.unknown");
+        fs::write(&path, b"old").unwrap();
+
+        let writer = TileWriter::new(TileFormat::Png, false);
+        assert!(writer.write(&image(), &path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn remove_temp_files_only_removes_temporary_tiles() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        for name in [
+            "0,0.png.tmp",
+            "-1,2.ktx2.tmp",
+            "0,0.png",
+            "0,0.png.bak",
+            "notes.txt.tmp",
+            "a,b.png.tmp",
+            "0231.png.tmp",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        fs::create_dir(dir.join("3,3.png.tmp")).unwrap();
+
+        TileWriter::new(TileFormat::Png, false)
+            .remove_temp_files(dir)
+            .unwrap();
+        let mut left: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "0,0.png",
+                "0,0.png.bak",
+                "0231.png.tmp",
+                "3,3.png.tmp",
+                "a,b.png.tmp",
+                "notes.txt.tmp"
+            ]
+        );
+
+        // temporary files of other name templates are not tiles
+        TileWriter::new(TileFormat::Png, false)
+            .with_name_template("{quadkey}.{ext}".parse().unwrap())
+            .remove_temp_files(dir)
+            .unwrap();
+        assert!(!dir.join("0231.png.tmp").exists());
+        assert!(dir.join("3,3.png.tmp").is_dir());
+    }
+}